@@ -0,0 +1,339 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{CONTENT_LENGTH, REFERER, USER_AGENT};
+use hyper::http::HeaderMap;
+use hyper::{Body, Request, Response};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::spawn_blocking;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Writes one line per HTTP request in Combined Log Format, followed by the
+// time taken to serve the request in microseconds (like Apache's %D).
+//
+// Lines are written on a background thread so that requests never wait for
+// the disk. The file is rotated once it grows past a size limit, keeping a
+// fixed number of old files named like access.log.1, access.log.2, etc.
+#[derive(Clone)]
+pub struct AccessLog {
+    lines: UnboundedSender<String>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl AccessLog {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        max_size: u64,
+        max_files: usize,
+        trusted_proxies: Vec<IpAddr>,
+    ) -> io::Result<AccessLog> {
+        let writer = RotatingWriter::open(path.as_ref().to_owned(), max_size, max_files)?;
+        let (tx, rx) = unbounded_channel();
+        spawn_blocking(move || writer.run(rx));
+        Ok(AccessLog {
+            lines: tx,
+            trusted_proxies,
+        })
+    }
+
+    pub fn log(
+        &self,
+        remote_ip: IpAddr,
+        req: &Request<Body>,
+        response: &Response<Body>,
+        start_time: SystemTime,
+        latency: Duration,
+    ) {
+        let entry = AccessLogEntry {
+            client_ip: client_ip(remote_ip, req.headers(), &self.trusted_proxies),
+            time: start_time,
+            request_line: format!("{} {} {:?}", req.method(), req.uri(), req.version()),
+            status: response.status().as_u16(),
+            size: response_size(response),
+            referer: header_string(req.headers(), REFERER),
+            user_agent: header_string(req.headers(), USER_AGENT),
+            latency,
+        };
+        // The writer only stops if the file can no longer be written, in
+        // which case it has already reported the error.
+        self.lines.send(entry.format()).ok();
+    }
+}
+
+// Find the address of the client which made a request. If the request came
+// from a trusted proxy, the X-Forwarded-For header is followed back to the
+// first address which is not a trusted proxy.
+pub fn client_ip(remote_ip: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&remote_ip) {
+        return remote_ip;
+    }
+    let mut result = remote_ip;
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .collect::<Vec<_>>();
+    for addr in forwarded.into_iter().rev() {
+        match addr.trim().parse::<IpAddr>() {
+            Ok(ip) => {
+                result = ip;
+                if !trusted_proxies.contains(&ip) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    result
+}
+
+fn response_size(response: &Response<Body>) -> Option<u64> {
+    response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse().ok())
+        .or_else(|| hyper::body::HttpBody::size_hint(response.body()).exact())
+}
+
+fn header_string(headers: &HeaderMap, name: hyper::header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .map(|x| String::from_utf8_lossy(x.as_bytes()).into_owned())
+}
+
+struct AccessLogEntry {
+    client_ip: IpAddr,
+    time: SystemTime,
+    request_line: String,
+    status: u16,
+    size: Option<u64>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    latency: Duration,
+}
+
+impl AccessLogEntry {
+    fn format(&self) -> String {
+        format!(
+            "{} - - [{}] {} {} {} {} {} {}\n",
+            self.client_ip,
+            clf_time(self.time),
+            quote(Some(&self.request_line)),
+            self.status,
+            self.size
+                .filter(|x| *x > 0)
+                .map(|x| x.to_string())
+                .unwrap_or_else(|| "-".to_owned()),
+            quote(self.referer.as_deref()),
+            quote(self.user_agent.as_deref()),
+            self.latency.as_micros()
+        )
+    }
+}
+
+// Quote a field, escaping characters which could break up the line.
+fn quote(value: Option<&str>) -> String {
+    match value {
+        None => "\"-\"".to_owned(),
+        Some(value) => {
+            let mut result = String::from("\"");
+            for c in value.chars() {
+                match c {
+                    '"' => result.push_str("\\\""),
+                    '\\' => result.push_str("\\\\"),
+                    c if c.is_control() => result.push_str(&format!("\\x{:02x}", c as u32)),
+                    c => result.push(c),
+                }
+            }
+            result.push('"');
+            result
+        }
+    }
+}
+
+// Format a time like 10/Oct/2000:13:55:36 +0000.
+fn clf_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let day_secs = secs.rem_euclid(86400);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        day_secs / 3600,
+        (day_secs / 60) % 60,
+        day_secs % 60
+    )
+}
+
+// Convert days since the UNIX epoch into a (year, month, day) date, using
+// Howard Hinnant's algorithm for the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+struct RotatingWriter {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl RotatingWriter {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<RotatingWriter> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingWriter {
+            path,
+            max_size,
+            max_files,
+            file: BufWriter::new(file),
+            size,
+        })
+    }
+
+    fn run(mut self, mut lines: UnboundedReceiver<String>) {
+        while let Some(line) = lines.blocking_recv() {
+            let mut result = self.write(&line);
+            // Batch up writes while requests are arriving quickly.
+            while let (Ok(_), Ok(line)) = (&result, lines.try_recv()) {
+                result = self.write(&line);
+            }
+            if let Err(e) = result.and_then(|_| self.file.flush()) {
+                eprintln!("failed to write access log {:?}: {}", self.path, e);
+                return;
+            }
+        }
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files > 0 {
+            for i in (1..self.max_files).rev() {
+                let from = self.rotated_path(i);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        name.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clf_time_format() {
+        let time = UNIX_EPOCH + Duration::from_secs(971186136);
+        assert_eq!(clf_time(time), "10/Oct/2000:13:55:36 +0000");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1709164800);
+        assert_eq!(clf_time(leap_day), "29/Feb/2024:00:00:00 +0000");
+    }
+
+    #[test]
+    fn entry_format() {
+        let entry = AccessLogEntry {
+            client_ip: "203.0.113.5".parse().unwrap(),
+            time: UNIX_EPOCH + Duration::from_secs(971186136),
+            request_line: "GET /api/rules HTTP/1.1".to_owned(),
+            status: 200,
+            size: Some(2326),
+            referer: None,
+            user_agent: Some("curl/7.88 \"test\"".to_owned()),
+            latency: Duration::from_micros(1500),
+        };
+        assert_eq!(
+            entry.format(),
+            "203.0.113.5 - - [10/Oct/2000:13:55:36 +0000] \"GET /api/rules HTTP/1.1\" 200 2326 \"-\" \"curl/7.88 \\\"test\\\"\" 1500\n"
+        );
+    }
+
+    #[test]
+    fn client_ip_from_trusted_proxy() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let inner_proxy: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "198.51.100.7, 203.0.113.5, 10.0.0.2".parse().unwrap(),
+        );
+
+        // Headers from untrusted peers are ignored.
+        assert_eq!(client_ip(proxy, &headers, &[]), proxy);
+
+        // The spoofable left-most address is never used while an untrusted
+        // address follows it.
+        assert_eq!(
+            client_ip(proxy, &headers, &[proxy, inner_proxy]),
+            "203.0.113.5".parse::<IpAddr>().unwrap()
+        );
+
+        // Without a header, the proxy itself is the client.
+        assert_eq!(client_ip(proxy, &HeaderMap::new(), &[proxy]), proxy);
+    }
+
+    #[test]
+    fn rotate_files() {
+        let dir = std::env::temp_dir().join(format!("access-log-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let mut writer = RotatingWriter::open(path.clone(), 10, 2).unwrap();
+        for line in ["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            writer.write(line).unwrap();
+        }
+        writer.file.flush().unwrap();
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("access.log"), "dddddd\n");
+        assert_eq!(read("access.log.1"), "cccccc\n");
+        assert_eq!(read("access.log.2"), "bbbbbb\n");
+        assert!(!dir.join("access.log.3").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
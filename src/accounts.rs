@@ -0,0 +1,315 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use hyper::{Body, Request, Response};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use crate::db::{Database, LevelStats};
+use crate::http_util::{api_response, read_body};
+use crate::log_async;
+use crate::sessions::Session;
+
+// The number of seconds for which a login code can be used.
+const LOGIN_CODE_LIFETIME: i64 = 60 * 15;
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_USERNAME_LENGTH: usize = 32;
+
+// Endpoints for player accounts. A session is signed in to an account by
+// signing up, or by logging in with either a password or a one-time login
+// code. Login codes are written to the server log rather than being sent
+// anywhere, so they are only enabled for local use.
+#[derive(Clone)]
+pub struct Accounts {
+    db: Database,
+    max_post_size: usize,
+    login_codes: bool,
+}
+
+impl Accounts {
+    pub fn new(db: Database, max_post_size: usize, login_codes: bool) -> Accounts {
+        Accounts {
+            db,
+            max_post_size,
+            login_codes,
+        }
+    }
+
+    // Handle a request under /api/account/, or return None if the path is
+    // unknown.
+    pub async fn handle(&self, req: &mut Request<Body>) -> Option<Response<Body>> {
+        let path = req.uri().path().to_owned();
+        Some(match path.as_str() {
+            "/api/account/stats" => api_response(&self.db, "account stats", self.stats(req).await)
+                .await
+                .unwrap(),
+            "/api/account/code" => {
+                api_response(&self.db, "send login code", self.send_code(req).await)
+                    .await
+                    .unwrap()
+            }
+            "/api/account/signup" | "/api/account/login" | "/api/account/logout" => {
+                let name = match path.as_str() {
+                    "/api/account/signup" => "sign up",
+                    "/api/account/login" => "log in",
+                    _ => "log out",
+                };
+                let mut session = match Session::find_or_create(&self.db, req).await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        return Some(
+                            api_response(&self.db, name, Err::<AccountResponse, _>(e))
+                                .await
+                                .unwrap(),
+                        )
+                    }
+                };
+                let result = match path.as_str() {
+                    "/api/account/signup" => self.signup(req, &mut session).await,
+                    "/api/account/login" => self.login(req, &mut session).await,
+                    _ => self.logout(&mut session).await,
+                };
+                session.with_cookie(api_response(&self.db, name, result).await.unwrap())
+            }
+            _ => return None,
+        })
+    }
+
+    async fn signup(
+        &self,
+        req: &mut Request<Body>,
+        session: &mut Session,
+    ) -> anyhow::Result<AccountResponse> {
+        let post_data = read_body(req, self.max_post_size).await?;
+        let req_data: SignupRequest = serde_json::from_slice(&post_data)?;
+        check_username(&req_data.username)?;
+        let password_hash = match req_data.password {
+            Some(password) => {
+                if password.chars().count() < MIN_PASSWORD_LENGTH {
+                    return Err(anyhow::Error::msg(format!(
+                        "password must be at least {} characters",
+                        MIN_PASSWORD_LENGTH
+                    )));
+                }
+                Some(spawn_blocking(move || hash_password(&password)).await??)
+            }
+            None if self.login_codes => None,
+            None => return Err(anyhow::Error::msg("a password is required")),
+        };
+        let account_id = self
+            .db
+            .create_account(req_data.username.clone(), password_hash)
+            .await?
+            .ok_or_else(|| anyhow::Error::msg("username is already taken"))?;
+        session.set_account(&self.db, Some(account_id)).await?;
+        Ok(AccountResponse {
+            username: Some(req_data.username),
+        })
+    }
+
+    async fn login(
+        &self,
+        req: &mut Request<Body>,
+        session: &mut Session,
+    ) -> anyhow::Result<AccountResponse> {
+        let post_data = read_body(req, self.max_post_size).await?;
+        let req_data: LoginRequest = serde_json::from_slice(&post_data)?;
+        let account = self.db.find_account(req_data.username).await?;
+        let valid = match (account.as_ref(), req_data.password, req_data.code) {
+            (Some(account), _, Some(code)) if self.login_codes => {
+                self.db.use_login_code(account.id, code).await?
+            }
+            (Some(account), Some(password), _) => match account.password_hash.clone() {
+                Some(hash) => spawn_blocking(move || verify_password(&hash, &password)).await?,
+                None => false,
+            },
+            _ => false,
+        };
+        match account {
+            Some(account) if valid => {
+                session.set_account(&self.db, Some(account.id)).await?;
+                Ok(AccountResponse {
+                    username: Some(account.username),
+                })
+            }
+            _ => Err(anyhow::Error::msg("incorrect username, password, or code")),
+        }
+    }
+
+    async fn logout(&self, session: &mut Session) -> anyhow::Result<AccountResponse> {
+        if session.account_id.is_some() {
+            session.set_account(&self.db, None).await?;
+        }
+        Ok(AccountResponse { username: None })
+    }
+
+    async fn send_code(&self, req: &mut Request<Body>) -> anyhow::Result<SendCodeResponse> {
+        if !self.login_codes {
+            return Err(anyhow::Error::msg("login codes are not enabled"));
+        }
+        let post_data = read_body(req, self.max_post_size).await?;
+        let req_data: SendCodeRequest = serde_json::from_slice(&post_data)?;
+        let account = self
+            .db
+            .find_account(req_data.username)
+            .await?
+            .ok_or_else(|| anyhow::Error::msg("no account found with the supplied username"))?;
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        self.db
+            .create_login_code(account.id, code.clone(), LOGIN_CODE_LIFETIME)
+            .await?;
+        log_async!(&self.db, "login code for {}: {}", account.username, code);
+        Ok(SendCodeResponse {
+            expires_in: LOGIN_CODE_LIFETIME,
+        })
+    }
+
+    async fn stats(&self, req: &Request<Body>) -> anyhow::Result<StatsResponse> {
+        let account_id = Session::find(&self.db, req)
+            .await?
+            .and_then(|x| x.account_id)
+            .ok_or_else(|| anyhow::Error::msg("not logged in"))?;
+        let stats = self
+            .db
+            .account_stats(account_id)
+            .await?
+            .ok_or_else(|| anyhow::Error::msg("account no longer exists"))?;
+        Ok(StatsResponse {
+            username: stats.username,
+            rounds: stats.levels.iter().map(|x| x.rounds).sum(),
+            wins: stats.levels.iter().map(|x| x.wins).sum(),
+            current_streak: stats.current_streak,
+            best_streak: stats.best_streak,
+            levels: stats
+                .levels
+                .into_iter()
+                .map(LevelStatsResponse::new)
+                .collect(),
+        })
+    }
+}
+
+fn check_username(username: &str) -> anyhow::Result<()> {
+    if username.is_empty() || username.len() > MAX_USERNAME_LENGTH {
+        Err(anyhow::Error::msg(format!(
+            "username must be between 1 and {} characters",
+            MAX_USERNAME_LENGTH
+        )))
+    } else if !username
+        .chars()
+        .all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '-' || x == '.')
+    {
+        Err(anyhow::Error::msg(
+            "username may only contain letters, numbers, '_', '-', and '.'",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::b64_encode(&rand::thread_rng().gen::<[u8; 16]>())?;
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[derive(Deserialize)]
+struct SignupRequest {
+    username: String,
+
+    // If omitted, the account can only be logged in to with login codes.
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    username: String,
+    password: Option<String>,
+    code: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SendCodeRequest {
+    username: String,
+}
+
+#[derive(Serialize)]
+struct AccountResponse {
+    username: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SendCodeResponse {
+    #[serde(rename(serialize = "expiresIn"))]
+    expires_in: i64,
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    username: String,
+    rounds: i64,
+    wins: i64,
+
+    #[serde(rename(serialize = "currentStreak"))]
+    current_streak: i64,
+
+    #[serde(rename(serialize = "bestStreak"))]
+    best_streak: i64,
+
+    levels: Vec<LevelStatsResponse>,
+}
+
+#[derive(Serialize)]
+struct LevelStatsResponse {
+    level: String,
+    rounds: i64,
+    wins: i64,
+
+    // The mean percent error of the account's guesses in the level.
+    #[serde(rename(serialize = "averageError"))]
+    average_error: f64,
+}
+
+impl LevelStatsResponse {
+    fn new(stats: LevelStats) -> LevelStatsResponse {
+        LevelStatsResponse {
+            average_error: stats.total_error / stats.rounds.max(1) as f64,
+            level: stats.level,
+            rounds: stats.rounds,
+            wins: stats.wins,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_hash_round_trip() {
+        let hash = hash_password("hunter22").unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password(&hash, "hunter22"));
+        assert!(!verify_password(&hash, "hunter23"));
+        assert!(!verify_password("not a hash", "hunter22"));
+    }
+
+    #[test]
+    fn username_characters() {
+        assert!(check_username("alex_99.b-c").is_ok());
+        assert!(check_username("").is_err());
+        assert!(check_username("has space").is_err());
+        assert!(check_username(&"a".repeat(MAX_USERNAME_LENGTH + 1)).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::spawn;
+
+use crate::assets::asset_response;
+use crate::db::{Database, LogQuery, SourceRun};
+use crate::http_util::{api_response, read_body};
+use crate::log::LogLevel;
+use crate::sources::SourceUpdater;
+
+const DEFAULT_LOGS_PAGE_SIZE: i64 = 100;
+const MAX_LOGS_PAGE_SIZE: i64 = 1000;
+
+// Endpoints for operators, which require a bearer token. The dashboard page
+// itself is public, and asks for the token before calling the endpoints.
+#[derive(Clone)]
+pub struct Admin {
+    token: String,
+    db: Database,
+    updater: SourceUpdater,
+    max_post_size: usize,
+}
+
+impl Admin {
+    pub fn new(token: String, db: Database, updater: SourceUpdater, max_post_size: usize) -> Admin {
+        Admin {
+            token,
+            db,
+            updater,
+            max_post_size,
+        }
+    }
+
+    // Handle a request under /admin, or return None if the path is unknown.
+    pub async fn handle(
+        &self,
+        req: &mut Request<Body>,
+        asset_dir: &Option<String>,
+    ) -> Option<Response<Body>> {
+        let path = req.uri().path().to_owned();
+        if path == "/admin" {
+            return Some(asset_response(asset_dir, "admin.html").await);
+        }
+        if !["/admin/sources", "/admin/refresh", "/admin/logs"].contains(&path.as_str()) {
+            return None;
+        }
+        if !self.is_authorized(req) {
+            return Some(unauthorized_response());
+        }
+        Some(match path.as_str() {
+            "/admin/sources" => api_response(&self.db, "list sources", self.sources().await)
+                .await
+                .unwrap(),
+            "/admin/logs" => api_response(&self.db, "query logs", self.logs(req).await)
+                .await
+                .unwrap(),
+            _ => api_response(&self.db, "refresh source", self.refresh(req).await)
+                .await
+                .unwrap(),
+        })
+    }
+
+    fn is_authorized(&self, req: &Request<Body>) -> bool {
+        req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "))
+            .map(|x| constant_time_eq(x.trim().as_bytes(), self.token.as_bytes()))
+            .unwrap_or_default()
+    }
+
+    async fn sources(&self) -> anyhow::Result<Vec<SourceStatus>> {
+        let mut health = self.db.source_health().await?;
+        Ok(self
+            .updater
+            .sources()
+            .iter()
+            .map(|source| {
+                let id = source.identifier();
+                let health = health.remove(&id);
+                SourceStatus {
+                    running: self.updater.is_running(&id),
+                    website: source.website(),
+                    last_success: health.as_ref().and_then(|x| x.last_success),
+                    last_error: health
+                        .as_ref()
+                        .and_then(|x| x.last_error.clone())
+                        .map(|(time, message)| SourceError { time, message }),
+                    last_run: health.map(|x| SourceRunStatus::new(x.last_run)),
+                    id,
+                }
+            })
+            .collect())
+    }
+
+    async fn logs(&self, req: &Request<Body>) -> anyhow::Result<LogsResponse> {
+        let req_data: LogsRequest = serde_urlencoded::from_str(req.uri().query().unwrap_or(""))?;
+        let mut levels = Vec::new();
+        for name in req_data.level.iter().flat_map(|x| x.split(',')) {
+            if !name.is_empty() {
+                levels.push(
+                    LogLevel::parse(name).ok_or_else(|| {
+                        anyhow::Error::msg(format!("unknown log level: {}", name))
+                    })?,
+                );
+            }
+        }
+        let limit = req_data
+            .limit
+            .unwrap_or(DEFAULT_LOGS_PAGE_SIZE)
+            .clamp(1, MAX_LOGS_PAGE_SIZE);
+        let messages = self
+            .db
+            .query_logs(LogQuery {
+                levels,
+                source: req_data.source.filter(|x| !x.is_empty()),
+                since: req_data.since,
+                until: req_data.until,
+                before_id: req_data.before,
+                limit,
+            })
+            .await?;
+        Ok(LogsResponse {
+            // A full page may be followed by more messages.
+            next_before: if messages.len() as i64 >= limit {
+                messages.last().map(|x| x.id)
+            } else {
+                None
+            },
+            messages: messages
+                .into_iter()
+                .map(|x| LogMessageResponse {
+                    id: x.id,
+                    timestamp: x.timestamp,
+                    level: x.level,
+                    source: x.source,
+                    message: x.message,
+                })
+                .collect(),
+        })
+    }
+
+    async fn refresh(&self, req: &mut Request<Body>) -> anyhow::Result<RefreshResponse> {
+        let post_data = read_body(req, self.max_post_size).await?;
+        let req_data: RefreshRequest = serde_json::from_slice(&post_data)?;
+        let source = self
+            .updater
+            .find(&req_data.source)
+            .ok_or_else(|| anyhow::Error::msg("no source found with the supplied ID"))?;
+        if self.updater.is_running(&req_data.source) {
+            return Err(anyhow::Error::msg("source is already being updated"));
+        }
+        let updater = self.updater.clone();
+        spawn(async move {
+            updater.update(vec![source]).await;
+        });
+        Ok(RefreshResponse { running: true })
+    }
+}
+
+fn unauthorized_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(CONTENT_TYPE, "application/json")
+        .header(WWW_AUTHENTICATE, "Bearer")
+        .body(Body::from(r#"{"error":"a valid admin token is required"}"#))
+        .unwrap()
+}
+
+// Compare secrets without leaking the length of the matching prefix.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Serialize)]
+struct SourceStatus {
+    id: String,
+    website: String,
+    running: bool,
+    last_success: Option<i64>,
+    last_error: Option<SourceError>,
+    last_run: Option<SourceRunStatus>,
+}
+
+#[derive(Serialize)]
+struct SourceError {
+    time: i64,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct SourceRunStatus {
+    started: i64,
+    duration_ms: i64,
+    error: Option<String>,
+    listings_added: i64,
+    listings_updated: i64,
+}
+
+impl SourceRunStatus {
+    fn new(run: SourceRun) -> SourceRunStatus {
+        SourceRunStatus {
+            started: run.started,
+            duration_ms: run.duration_ms,
+            error: run.error,
+            listings_added: run.listings_added,
+            listings_updated: run.listings_updated,
+        }
+    }
+}
+
+// Query parameters for /admin/logs. Levels are separated by commas, and times
+// are UNIX timestamps.
+#[derive(Deserialize)]
+struct LogsRequest {
+    level: Option<String>,
+    source: Option<String>,
+    since: Option<i64>,
+    until: Option<i64>,
+    before: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct LogsResponse {
+    messages: Vec<LogMessageResponse>,
+
+    // Pass this as the before parameter to get the next page.
+    next_before: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct LogMessageResponse {
+    id: i64,
+    timestamp: i64,
+    level: LogLevel,
+    source: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct RefreshRequest {
+    source: String,
+}
+
+#[derive(Serialize)]
+struct RefreshResponse {
+    running: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_time_eq_compares_contents() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
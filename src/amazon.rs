@@ -1,6 +1,10 @@
 use std::{io::Read, ops::Deref};
 
-use crate::{db::Listing, scraper::Client};
+use crate::{
+    db::Listing,
+    scraper::Client,
+    sources::{ScrapedItem, SkipReason},
+};
 use reqwest::Url;
 use serde::Deserialize;
 use tokio::{
@@ -54,7 +58,10 @@ struct AmazonResult {
     full_star_count: i64,
 }
 
-pub fn stream_category(client: Client, category_id: String) -> Receiver<anyhow::Result<Listing>> {
+pub fn stream_category(
+    client: Client,
+    category_id: String,
+) -> Receiver<anyhow::Result<ScrapedItem>> {
     let (tx, rx) = channel(1);
     spawn(async move {
         let mut search_blob = "".to_owned();
@@ -62,7 +69,7 @@ pub fn stream_category(client: Client, category_id: String) -> Receiver<anyhow::
         loop {
             match result_page(&client, &category_id, &mut search_blob, &mut offset).await {
                 Ok(results) => {
-                    if results.len() == 0 {
+                    if results.is_empty() {
                         break;
                     }
                     for item in results {
@@ -72,7 +79,7 @@ pub fn stream_category(client: Client, category_id: String) -> Receiver<anyhow::
                     }
                 }
                 Err(e) => {
-                    tx.send(Err(e.into())).await.ok();
+                    tx.send(Err(e)).await.ok();
                     break;
                 }
             }
@@ -86,7 +93,7 @@ async fn result_page(
     category_id: &str,
     search_blob: &mut String,
     offset: &mut i64,
-) -> anyhow::Result<Vec<Listing>> {
+) -> anyhow::Result<Vec<ScrapedItem>> {
     let mut url = Url::parse("https://www.amazon.com/gcx/-/gfhz/api/scroll?canBeEGifted=false&canBeGiftWrapped=false&isLimitedTimeOffer=false&isPrime=false&priceFrom&priceTo").unwrap();
 
     // The ID may have no sub-id, or may be "id:subid".
@@ -103,7 +110,7 @@ async fn result_page(
         .append_pair("categoryId", main_category_id)
         .append_pair("count", "50")
         .append_pair("offset", &format!("{}", offset))
-        .append_pair("searchBlob", &search_blob);
+        .append_pair("searchBlob", search_blob);
     if let Some(sub_category_id) = sub_category_id {
         url.query_pairs_mut().append_pair(
             "subcategoryIds",
@@ -123,32 +130,123 @@ async fn result_page(
     *offset += results.asins.len() as i64;
     *search_blob = results.search_blob.unwrap_or("".to_owned());
 
-    let mut listings = Vec::with_capacity(results.asins.len());
+    let mut items = Vec::with_capacity(results.asins.len());
     for item in results.asins {
-        if item.price.is_none() {
-            continue;
+        let price = match item.price {
+            Some(x) => x,
+            None => {
+                items.push(ScrapedItem::Skipped(SkipReason::MissingPrice));
+                continue;
+            }
+        };
+        let parsed_price = match price.replace([',', '$'], "").parse::<f64>() {
+            Ok(x) => x,
+            Err(_) => {
+                items.push(ScrapedItem::Skipped(SkipReason::UnparseablePrice));
+                continue;
+            }
+        };
+        let image_url = match item.display_large_image_url {
+            Some(x) => x,
+            None => {
+                items.push(ScrapedItem::Skipped(SkipReason::MissingImage));
+                continue;
+            }
+        };
+        let image_data = client.get_bytes(image_url).await?;
+        items.push(ScrapedItem::Listing(Listing {
+            website: "amazon.com".to_owned(),
+            website_id: item.asin,
+            price: (parsed_price * 100.0).round() as i64,
+            title: item.title,
+            image_data,
+            categories: vec![category_id.to_owned()],
+            star_rating: Some(if item.has_half_star {
+                item.star_rating
+            } else {
+                item.full_star_count as f64
+            }),
+            max_stars: Some(5.0),
+            num_reviews: item.review_count.replace(",", "").parse().ok(),
+        }));
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::scraper::{ClientPolicy, Transport};
+
+    fn replay_client() -> Client {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/cassettes/amazon");
+        Client::new(ClientPolicy::default(), Transport::Replay(dir))
+    }
+
+    #[tokio::test]
+    async fn stream_category_pages() {
+        let mut rx = stream_category(replay_client(), "interesting-finds".to_owned());
+        let mut listings = Vec::new();
+        let mut skipped = Vec::new();
+        while let Some(item) = rx.recv().await {
+            match item.unwrap() {
+                ScrapedItem::Listing(x) => listings.push(x),
+                ScrapedItem::Skipped(reason) => skipped.push(reason),
+            }
         }
-        if let Some(image_url) = item.display_large_image_url {
-            let image_data = client.get_bytes(image_url).await?;
-            if let Ok(parsed_price) = (&item.price.unwrap().replace(&[',', '$'], "")).parse::<f64>()
-            {
-                listings.push(Listing {
-                    website: "amazon.com".to_owned(),
-                    website_id: item.asin,
-                    price: (parsed_price * 100.0).round() as i64,
-                    title: item.title,
-                    image_data: image_data.into(),
-                    categories: vec![category_id.to_owned()],
-                    star_rating: Some(if item.has_half_star {
-                        item.star_rating
-                    } else {
-                        item.full_star_count as f64
-                    }),
-                    max_stars: Some(5.0),
-                    num_reviews: item.review_count.replace(",", "").parse().ok(),
-                });
+        let summary = listings
+            .iter()
+            .map(|x| {
+                (
+                    x.website_id.as_str(),
+                    x.price,
+                    x.title.as_str(),
+                    x.image_data.as_slice(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "B000000001",
+                    2499,
+                    "Levitating Desk Lamp",
+                    b"lamp image".as_slice()
+                ),
+                ("B000000003", 129900, "Robot Vacuum", b"vacuum image"),
+            ]
+        );
+        assert_eq!(skipped, vec![SkipReason::MissingPrice]);
+        assert_eq!(listings[0].star_rating, Some(4.5));
+        assert_eq!(listings[0].num_reviews, Some(1024));
+        assert_eq!(listings[0].categories, vec!["interesting-finds"]);
+    }
+
+    #[tokio::test]
+    async fn result_page_subcategory() {
+        let mut search_blob = "".to_owned();
+        let mut offset = 0;
+        let items = result_page(
+            &replay_client(),
+            "adult-neutral:retro",
+            &mut search_blob,
+            &mut offset,
+        )
+        .await
+        .unwrap();
+        assert_eq!(offset, 1);
+        assert_eq!(search_blob, "retro-blob");
+        assert_eq!(items.len(), 1);
+        match &items[0] {
+            ScrapedItem::Listing(listing) => {
+                assert_eq!(listing.website_id, "B000000004");
+                assert_eq!(listing.star_rating, Some(4.0));
+                assert_eq!(listing.num_reviews, Some(87));
             }
+            ScrapedItem::Skipped(reason) => panic!("unexpected skip: {}", reason),
         }
     }
-    Ok(listings)
 }
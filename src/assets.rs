@@ -20,12 +20,15 @@ macro_rules! asset_pair {
     };
 }
 
-const ASSETS: &'static [(&'static str, &'static [u8])] = &[
+const ASSETS: &[(&str, &[u8])] = &[
+    asset_pair!("admin.css"),
+    asset_pair!("admin.html"),
     asset_pair!("index.html"),
     asset_pair!("internal_error.html"),
     asset_pair!("not_found.html"),
     asset_pair!("style.css"),
     asset_pair!("favicon.ico"),
+    asset_pair!(path_join!("js", "admin.js")),
     asset_pair!(path_join!("js", "api.js")),
     asset_pair!(path_join!("js", "script.js")),
     asset_pair!(path_join!("js", "deps", "babel-standalone@6.26.0.js")),
@@ -41,6 +44,7 @@ const ASSETS: &'static [(&'static str, &'static [u8])] = &[
     asset_pair!(path_join!("svg", "t_shirt.svg")),
     asset_pair!(path_join!("svg", "target.svg")),
     asset_pair!(path_join!("svg", "treasure_chest.svg")),
+    asset_pair!(path_join!("svg", "walmart.svg")),
     asset_pair!(path_join!("svg", "wrench.svg")),
 ];
 
@@ -92,17 +96,10 @@ pub async fn read_asset_data<'a>(
         )
         .await
     } else {
-        return ASSETS
+        ASSETS
             .iter()
-            .filter(|(n, _)| {
-                *n == if name.starts_with("/") {
-                    &name[1..]
-                } else {
-                    name
-                }
-            })
-            .next()
+            .find(|(n, _)| *n == name.strip_prefix('/').unwrap_or(name))
             .map(|(_, content)| (*content).to_owned())
-            .ok_or(io::Error::new(io::ErrorKind::NotFound, "asset not found"));
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, "asset not found"))
     }
 }
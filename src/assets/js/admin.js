@@ -0,0 +1,357 @@
+class AdminClient {
+    constructor() {
+        this.base = '/admin';
+        this.token = localStorage.adminToken || null;
+    }
+
+    setToken(token) {
+        this.token = token;
+        if (token) {
+            localStorage.adminToken = token;
+        } else {
+            delete localStorage.adminToken;
+        }
+    }
+
+    async sources() {
+        return await this._getObject(this.base + '/sources');
+    }
+
+    async refresh(sourceID) {
+        return await this._postObject(this.base + '/refresh', { source: sourceID });
+    }
+
+    async logs(filters, before) {
+        const params = new URLSearchParams();
+        Object.entries(filters).forEach(([key, value]) => {
+            if (value !== null && value !== '') {
+                params.set(key, value);
+            }
+        });
+        if (before) {
+            params.set('before', before);
+        }
+        return await this._getObject(this.base + '/logs?' + params.toString());
+    }
+
+    async _getObject(url) {
+        return await this._getResult(fetch(url, {
+            cache: 'no-cache',
+            headers: this._headers(),
+        }));
+    }
+
+    async _postObject(url, object) {
+        const headers = this._headers();
+        headers['content-type'] = 'application/json';
+        return await this._getResult(fetch(url, {
+            method: 'POST',
+            cache: 'no-cache',
+            headers: headers,
+            body: JSON.stringify(object),
+        }));
+    }
+
+    _headers() {
+        return { 'authorization': 'Bearer ' + this.token };
+    }
+
+    async _getResult(respPromise) {
+        const response = await respPromise;
+        const data = await response.json();
+        if (data.error) {
+            throw new AdminError(data.error, response.status === 401);
+        } else {
+            return data.data;
+        }
+    }
+}
+
+class AdminError {
+    constructor(msg, unauthorized) {
+        this.msg = msg;
+        this.unauthorized = unauthorized;
+    }
+
+    toString() {
+        return this.msg;
+    }
+}
+
+const client = new AdminClient();
+
+// How often to reload the dashboard, in milliseconds.
+const RELOAD_INTERVAL = 10000;
+
+class AdminApp extends React.Component {
+    constructor() {
+        super();
+        this.state = {
+            hasToken: !!client.token,
+            error: null,
+            sources: null,
+        };
+        this._reloadTimer = null;
+    }
+
+    componentDidMount() {
+        if (this.state.hasToken) {
+            this.reload();
+        }
+    }
+
+    componentWillUnmount() {
+        clearTimeout(this._reloadTimer);
+    }
+
+    render() {
+        if (!this.state.hasToken) {
+            return <TokenForm error={this.state.error} onSubmit={(x) => this.setToken(x)} />;
+        }
+        return [
+            <div className="admin-section" key="sources">
+                <h1>Sources</h1>
+                {this.state.error ? <div className="admin-error">{this.state.error}</div> : null}
+                {this.state.sources === null
+                    ? 'Loading...'
+                    : <SourceTable sources={this.state.sources} onRefresh={(x) => this.refresh(x)} />}
+            </div>,
+            <LogViewer key="logs" onError={(e) => this.handleError(e)} />,
+        ];
+    }
+
+    setToken(token) {
+        client.setToken(token);
+        this.setState({ hasToken: true, error: null }, () => this.reload());
+    }
+
+    async reload() {
+        clearTimeout(this._reloadTimer);
+        try {
+            const sources = await client.sources();
+            this.setState({ sources: sources, error: null });
+        } catch (e) {
+            if (!this.handleError(e)) {
+                return;
+            }
+        }
+        this._reloadTimer = setTimeout(() => this.reload(), RELOAD_INTERVAL);
+    }
+
+    async refresh(sourceID) {
+        try {
+            await client.refresh(sourceID);
+        } catch (e) {
+            this.handleError(e);
+        }
+        this.reload();
+    }
+
+    // Show an error, returning false if the token was rejected.
+    handleError(e) {
+        if (e.unauthorized) {
+            client.setToken(null);
+            this.setState({ hasToken: false, error: e.toString(), sources: null });
+            return false;
+        }
+        this.setState({ error: e.toString() });
+        return true;
+    }
+}
+
+class LogViewer extends React.Component {
+    constructor() {
+        super();
+        this.state = {
+            level: '',
+            source: '',
+            since: '',
+            until: '',
+            messages: null,
+            nextBefore: null,
+            loading: false,
+        };
+    }
+
+    componentDidMount() {
+        this.search();
+    }
+
+    render() {
+        const field = (name) => ({
+            value: this.state[name],
+            onChange: (e) => this.setState({ [name]: e.target.value }),
+        });
+        return (
+            <div className="admin-section">
+                <h1>Logs</h1>
+                <form className="admin-filters" onSubmit={(e) => { e.preventDefault(); this.search(); }}>
+                    <select {...field('level')}>
+                        <option value="">All levels</option>
+                        <option value="info">Info</option>
+                        <option value="warn">Warn</option>
+                        <option value="error">Error</option>
+                        <option value="warn,error">Warn and error</option>
+                    </select>
+                    <input type="text" placeholder="Source file" {...field('source')} />
+                    <label>Since <input type="datetime-local" {...field('since')} /></label>
+                    <label>Until <input type="datetime-local" {...field('until')} /></label>
+                    <button className="admin-button" type="submit" disabled={this.state.loading}>
+                        Search
+                    </button>
+                </form>
+                {this.state.messages === null ? 'Loading...' : <LogTable messages={this.state.messages} />}
+                {this.state.nextBefore
+                    ? <button className="admin-button"
+                        disabled={this.state.loading}
+                        onClick={() => this.loadMore()}>Older</button>
+                    : null}
+            </div>
+        );
+    }
+
+    search() {
+        this.setState({ messages: null, nextBefore: null }, () => this.load(null));
+    }
+
+    loadMore() {
+        this.load(this.state.nextBefore);
+    }
+
+    async load(before) {
+        this.setState({ loading: true });
+        try {
+            const filters = {
+                level: this.state.level,
+                source: this.state.source,
+                since: parseLocalTime(this.state.since),
+                until: parseLocalTime(this.state.until),
+            };
+            const result = await client.logs(filters, before);
+            this.setState({
+                messages: (this.state.messages || []).concat(result.messages),
+                nextBefore: result.next_before,
+            });
+        } catch (e) {
+            this.props.onError(e);
+        } finally {
+            this.setState({ loading: false });
+        }
+    }
+}
+
+function LogTable(props) {
+    return (
+        <table className="admin-table">
+            <thead>
+                <tr>
+                    <th>Time</th>
+                    <th>Level</th>
+                    <th>Source</th>
+                    <th>Message</th>
+                </tr>
+            </thead>
+            <tbody>
+                {props.messages.map((msg) => (
+                    <tr key={msg.id} className={msg.level === 'error' ? 'failed' : ''}>
+                        <td>{formatTime(msg.timestamp)}</td>
+                        <td>{msg.level}</td>
+                        <td>{msg.source}</td>
+                        <td className="message">{msg.message}</td>
+                    </tr>
+                ))}
+            </tbody>
+        </table>
+    );
+}
+
+function TokenForm(props) {
+    const input = React.useRef(null);
+    const submit = (e) => {
+        e.preventDefault();
+        if (input.current.value) {
+            props.onSubmit(input.current.value);
+        }
+    };
+    return (
+        <form className="admin-section" onSubmit={submit}>
+            <h1>Admin token</h1>
+            {props.error ? <div className="admin-error">{props.error}</div> : null}
+            <input type="password" ref={input} autoFocus />
+            <button className="admin-button" type="submit">Sign in</button>
+        </form>
+    );
+}
+
+function SourceTable(props) {
+    return (
+        <table className="admin-table">
+            <thead>
+                <tr>
+                    <th>Source</th>
+                    <th>Last run</th>
+                    <th>Duration</th>
+                    <th>Added</th>
+                    <th>Updated</th>
+                    <th>Last success</th>
+                    <th>Last error</th>
+                    <th></th>
+                </tr>
+            </thead>
+            <tbody>
+                {props.sources.map((source) => {
+                    const run = source.last_run;
+                    return (
+                        <tr key={source.id}>
+                            <td>{source.id}</td>
+                            <td className={run && run.error ? 'failed' : ''}>
+                                {run ? formatTime(run.started) : 'never'}
+                            </td>
+                            <td>{run ? formatDuration(run.duration_ms) : ''}</td>
+                            <td>{run ? run.listings_added : ''}</td>
+                            <td>{run ? run.listings_updated : ''}</td>
+                            <td>{source.last_success ? formatTime(source.last_success) : 'never'}</td>
+                            <td className="message">
+                                {source.last_error
+                                    ? formatTime(source.last_error.time) + ': ' + source.last_error.message
+                                    : ''}
+                            </td>
+                            <td>
+                                <button className="admin-button"
+                                    disabled={source.running}
+                                    onClick={() => props.onRefresh(source.id)}>
+                                    {source.running ? 'Updating' : 'Refresh'}
+                                </button>
+                            </td>
+                        </tr>
+                    );
+                })}
+            </tbody>
+        </table>
+    );
+}
+
+function formatTime(timestamp) {
+    return new Date(timestamp * 1000).toLocaleString();
+}
+
+// Convert the value of a datetime-local input to a UNIX timestamp.
+function parseLocalTime(value) {
+    if (!value) {
+        return null;
+    }
+    return Math.floor(new Date(value).getTime() / 1000);
+}
+
+function formatDuration(millis) {
+    const seconds = Math.round(millis / 1000);
+    if (seconds < 60) {
+        return seconds + 's';
+    }
+    return Math.floor(seconds / 60) + 'm ' + (seconds % 60) + 's';
+}
+
+ReactDOM.render(
+    <AdminApp />,
+    document.getElementById('root'),
+);
@@ -1,10 +1,13 @@
 class APIClient {
     constructor() {
         this.base = '/api';
-        this.idTracker = new UsedIDTracker();
+
+        // Seen listings are now tracked by the server.
+        delete localStorage.seenIDs;
 
         this._isFetchingLevels = false;
         this._isSamplingListing = false;
+        this._isRevealingRound = false;
     }
 
     async levels() {
@@ -13,35 +16,71 @@ class APIClient {
         }
         this._isFetchingLevels = true;
         try {
-            const requestObject = {
-                seenIDs: this.idTracker.seenIDs(),
-            };
-            const data = await this._postObject(this.base + '/levels', requestObject);
+            const data = await this._getObject(this.base + '/levels');
             return data.map((x) => {
-                return new APILevel(x['website_name'], x['category_name'], x['id'], x['count']);
+                return new APILevel(
+                    x['website_name'],
+                    x['category_name'],
+                    x['id'],
+                    x['count'],
+                    x['website_icon'],
+                    x['category_icon'],
+                );
             });
         } finally {
             this._isFetchingLevels = false;
         }
     }
 
-    async sampleListing(levelID) {
+    async rules() {
+        const data = await this._getObject(this.base + '/rules');
+        return data.map((x) => new APIRule(x['id'], x['name'], x['description']));
+    }
+
+    async sampleListing(levelID, ruleID) {
         if (this._isSamplingListing) {
             return false;
         }
         this._isSamplingListing = true;
         try {
             const requestObject = {
-                seenIDs: this.idTracker.seenIDs(),
                 level: levelID,
+                rule: ruleID,
             };
             const data = await this._postObject(this.base + '/sample', requestObject);
-            return new APIListing(data.id, data.title, data.price, data.imageURL);
+            return new APIListing(data.id, data.token, data.title, data.imageURL);
         } finally {
             this._isSamplingListing = false;
         }
     }
 
+    async revealRound(token, guesses) {
+        if (this._isRevealingRound) {
+            return false;
+        }
+        this._isRevealingRound = true;
+        try {
+            const requestObject = {
+                token: token,
+                guesses: guesses,
+            };
+            const data = await this._postObject(this.base + '/reveal', requestObject);
+            return new APIReveal(data.price, data.points, data.winners, data.history);
+        } finally {
+            this._isRevealingRound = false;
+        }
+    }
+
+    // Forget which listings have been seen, so that they can be shown again.
+    async resetHistory() {
+        const data = await this._postObject(this.base + '/reset', {});
+        return data.cleared;
+    }
+
+    async _getObject(url) {
+        return await this._getResult(fetch(url, { cache: 'no-cache' }));
+    }
+
     async _postObject(url, object) {
         return await this._getResult(fetch(url, {
             method: 'POST',
@@ -73,45 +112,97 @@ class APIClient {
     }
 }
 
+class RoomConnection {
+    constructor(onMessage, onClose) {
+        const protocol = location.protocol === 'https:' ? 'wss:' : 'ws:';
+        this._socket = new WebSocket(protocol + '//' + location.host + '/api/room');
+        this._pending = [];
+        this._socket.onopen = () => {
+            this._pending.forEach((x) => this._socket.send(x));
+            this._pending = [];
+        };
+        this._socket.onmessage = (e) => onMessage(JSON.parse(e.data));
+        this._socket.onclose = () => onClose();
+    }
+
+    create(name, levelID, ruleID) {
+        this._send({ type: 'create', name: name, level: levelID, rule: ruleID });
+    }
+
+    join(code, name) {
+        this._send({ type: 'join', code: code, name: name });
+    }
+
+    start() {
+        this._send({ type: 'start' });
+    }
+
+    next() {
+        this._send({ type: 'next' });
+    }
+
+    guess(guess) {
+        this._send({ type: 'guess', guess: guess });
+    }
+
+    close() {
+        this._socket.onclose = null;
+        this._socket.close();
+    }
+
+    _send(object) {
+        const data = JSON.stringify(object);
+        if (this._socket.readyState === WebSocket.OPEN) {
+            this._socket.send(data);
+        } else {
+            this._pending.push(data);
+        }
+    }
+}
+
 class APILevel {
-    constructor(website, category, id, count) {
+    constructor(website, category, id, count, websiteIcon, categoryIcon) {
         this.website = website;
         this.category = category;
         this.id = id;
         this.count = count;
+        this.websiteIcon = websiteIcon || '/svg/unknown.svg';
+        this.categoryIcon = categoryIcon || '/svg/unknown.svg';
     }
 }
 
 class APIListing {
-    constructor(id, title, price, imageURL) {
+    constructor(id, token, title, imageURL) {
         this.id = id;
+        this.token = token;
         this.title = title;
-        this.price = price;
         this.imageURL = imageURL;
     }
 }
 
-class APIError {
-    constructor(msg) {
-        this.msg = msg;
-    }
-
-    toString() {
-        return this.msg;
+class APIRule {
+    constructor(id, name, description) {
+        this.id = id;
+        this.name = name;
+        this.description = description;
     }
 }
 
-class UsedIDTracker {
-    constructor() {
-        this._seenIDs = JSON.parse(localStorage.seenIDs || '[]');
+class APIReveal {
+    constructor(price, points, winners, history) {
+        this.price = price;
+        this.points = points;
+        this.winners = winners;
+        this.history = history;
     }
+}
 
-    seenIDs() {
-        return this._seenIDs;
+class APIError {
+    constructor(msg) {
+        this.msg = msg;
     }
 
-    add(id) {
-        this._seenIDs.push(id);
-        localStorage.seenIDs = JSON.stringify(this.seenIDs());
+    toString() {
+        return this.msg;
     }
 }
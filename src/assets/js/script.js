@@ -7,43 +7,62 @@ class App extends React.Component {
             page: 'loadingLevels',
             error: null,
             levels: null,
+            rules: null,
+            playMode: null,
             levelWebsite: null,
             selectedLevel: null,
+            selectedRule: null,
             numPlayers: null,
             currentListing: null,
             currentGuessValue: null,
             currentGuesses: null,
             roundResults: null,
+            roomOptions: null,
         }
     }
 
     render() {
         // Flow of pages looks like:
         //
-        //    loadingLevels => levelWebsite => levelCategory => levelPlayers
-        // => loadingListing => guessing | noListings => guesses => scoreboard
+        //    loadingLevels => playMode => levelWebsite => levelCategory
+        // => levelPlayers => levelRule => loadingListing => guessing | noListings
+        // => revealing => guesses => scoreboard
+        //
+        // When hosting a room, levelPlayers is skipped and levelRule is followed
+        // by roomSetup => room. When joining a room, playMode is followed
+        // directly by roomSetup => room.
         //
 
         if (this.state.page === 'loadingLevels') {
             return this.renderLoadingLevels();
         } else if (this.state.page === 'error') {
             return this.renderError();
+        } else if (this.state.page === 'playMode') {
+            return this.renderPlayMode();
         } else if (this.state.page === 'levelWebsite') {
             return this.renderLevelWebsite();
         } else if (this.state.page === 'levelCategory') {
             return this.renderLevelCategory();
         } else if (this.state.page === 'levelPlayers') {
             return this.renderLevelPlayers();
+        } else if (this.state.page === 'levelRule') {
+            return this.renderLevelRule();
         } else if (this.state.page === 'loadingListing') {
             return this.renderLoadingListing();
         } else if (this.state.page === 'noListings') {
             return this.renderNoListings();
         } else if (this.state.page === 'guessing') {
             return this.renderGuessing();
+        } else if (this.state.page === 'revealing') {
+            return this.renderRevealing();
         } else if (this.state.page === 'guesses') {
             return this.renderGuesses();
         } else if (this.state.page === 'scoreboard') {
             return this.renderScoreboard();
+        } else if (this.state.page === 'roomSetup') {
+            return this.renderRoomSetup();
+        } else if (this.state.page === 'room') {
+            return this.renderRoom();
         }
 
         return <Header />;
@@ -60,12 +79,24 @@ class App extends React.Component {
         this.setState({ page: 'loadingLevels' });
     }
 
+    resetHistory() {
+        if (!confirm('Allow items you have already seen to appear again?')) {
+            return;
+        }
+        client.resetHistory().then(() => {
+            this.newGame();
+        }).catch((e) => {
+            this.showError(e.toString());
+        });
+    }
+
     renderLoadingLevels() {
-        client.levels().then((levels) => {
-            if (this.state.page === 'loadingLevels') {
+        Promise.all([client.levels(), client.rules()]).then(([levels, rules]) => {
+            if (this.state.page === 'loadingLevels' && levels) {
                 this.setState({
-                    page: 'levelWebsite',
+                    page: 'playMode',
                     levels: levels,
+                    rules: rules,
                 });
             }
         }).catch((e) => {
@@ -78,15 +109,32 @@ class App extends React.Component {
         return [<Header onNewGame={() => this.newGame()} />, <Error message={this.state.error} />];
     }
 
-    renderLevelWebsite() {
+    renderPlayMode() {
         return [
             <Header />,
-            <WebsitePicker levels={this.state.levels} onChoice={(website) => {
-                this.setState({
-                    page: 'levelCategory',
-                    levelWebsite: website,
-                });
-            }} />,
+            <PlayModePicker
+                onChoice={(mode) => {
+                    this.setState({
+                        page: mode === 'join' ? 'roomSetup' : 'levelWebsite',
+                        playMode: mode,
+                    });
+                }}
+                onResetHistory={() => this.resetHistory()} />,
+        ];
+    }
+
+    renderLevelWebsite() {
+        return [
+            <Header onNewGame={() => this.newGame()} />,
+            <WebsitePicker
+                levels={this.state.levels}
+                onChoice={(website) => {
+                    this.setState({
+                        page: 'levelCategory',
+                        levelWebsite: website,
+                    });
+                }}
+                onBack={() => this.setState({ page: 'playMode' })} />,
         ];
     }
 
@@ -98,7 +146,7 @@ class App extends React.Component {
                 website={this.state.levelWebsite}
                 onChoice={(level) => {
                     this.setState({
-                        page: 'levelPlayers',
+                        page: this.state.playMode === 'host' ? 'levelRule' : 'levelPlayers',
                         selectedLevel: level,
                         numPlayers: 2,
                     });
@@ -113,17 +161,63 @@ class App extends React.Component {
             <PlayersPicker
                 onChoice={(count) => {
                     this.setState({
-                        page: 'loadingListing',
+                        page: 'levelRule',
                         numPlayers: count,
-                        roundResults: [],
                     })
                 }}
                 onBack={() => this.setState({ page: 'levelCategory' })} />
         ];
     }
 
+    renderLevelRule() {
+        return [
+            <Header onNewGame={() => this.newGame()} />,
+            <RulePicker
+                rules={this.state.rules}
+                onChoice={(rule) => {
+                    this.setState({
+                        page: this.state.playMode === 'host' ? 'roomSetup' : 'loadingListing',
+                        selectedRule: rule,
+                        roundResults: [],
+                    })
+                }}
+                onBack={() => this.setState({
+                    page: this.state.playMode === 'host' ? 'levelCategory' : 'levelPlayers',
+                })} />
+        ];
+    }
+
+    renderRoomSetup() {
+        const joining = this.state.playMode === 'join';
+        return [
+            <Header onNewGame={() => this.newGame()} />,
+            <RoomSetup
+                joining={joining}
+                onChoice={(options) => {
+                    this.setState({
+                        page: 'room',
+                        roomOptions: options,
+                    });
+                }}
+                onBack={() => this.setState({ page: joining ? 'playMode' : 'levelRule' })} />
+        ];
+    }
+
+    renderRoom() {
+        const options = this.state.roomOptions;
+        return [
+            <Header onNewGame={() => this.newGame()} />,
+            <RoomGame
+                name={options.name}
+                code={options.code}
+                level={this.state.playMode === 'host' ? this.state.selectedLevel : null}
+                rule={this.state.playMode === 'host' ? this.state.selectedRule : null}
+                onNewGame={() => this.newGame()} />
+        ];
+    }
+
     renderLoadingListing() {
-        client.sampleListing(this.state.selectedLevel.id).then((listing) => {
+        client.sampleListing(this.state.selectedLevel.id, this.state.selectedRule.id).then((listing) => {
             if (this.state.page === 'loadingListing') {
                 if (listing.title === null) {
                     this.setState({ page: 'noListings' });
@@ -165,20 +259,14 @@ class App extends React.Component {
                 listing={this.state.currentListing}
                 value={this.state.currentGuessValue}
                 onChange={(e) => this.setState({ currentGuessValue: e.target.value })}
-                onSkip={() => {
-                    client.idTracker.add(this.state.currentListing.id);
-                    this.setState({ page: 'loadingListing' });
-                }}
+                onSkip={() => this.setState({ page: 'loadingListing' })}
                 onChoice={(guess) => {
                     const newGuesses = this.state.currentGuesses.concat([guess]);
                     if (player === this.state.numPlayers) {
-                        client.idTracker.add(this.state.currentListing.id);
-                        const result = new RoundResult(this.state.currentListing, newGuesses);
                         this.setState({
-                            page: 'guesses',
+                            page: 'revealing',
                             currentGuessValue: '',
-                            currentGuesses: [],
-                            roundResults: this.state.roundResults.concat([result]),
+                            currentGuesses: newGuesses,
                         });
                     } else {
                         this.setState({
@@ -190,6 +278,31 @@ class App extends React.Component {
         ];
     }
 
+    renderRevealing() {
+        const listing = this.state.currentListing;
+        const guesses = this.state.currentGuesses;
+        client.revealRound(listing.token, guesses).then((reveal) => {
+            if (this.state.page === 'revealing' && reveal) {
+                const result = new RoundResult(
+                    listing,
+                    guesses,
+                    reveal.price,
+                    reveal.points,
+                    reveal.winners,
+                    reveal.history,
+                );
+                this.setState({
+                    page: 'guesses',
+                    currentGuesses: [],
+                    roundResults: this.state.roundResults.concat([result]),
+                });
+            }
+        }).catch((e) => {
+            this.showError(e.toString());
+        });
+        return [<Header onNewGame={() => this.newGame()} />, <Loader />];
+    }
+
     renderGuesses() {
         return [
             <Header onNewGame={() => this.newGame()} />,
@@ -225,14 +338,43 @@ function Error(props) {
     return <div class="content-pane error">{props.message}</div>
 }
 
+function PlayModePicker(props) {
+    const modes = [
+        ['local', 'Play on this device', '/svg/calculator.svg'],
+        ['host', 'Host a room', '/svg/treasure_chest.svg'],
+        ['join', 'Join a room', '/svg/gloves.svg'],
+    ];
+    const items = modes.map(([mode, name, icon]) => (
+        <li class="choice-list-item" onClick={() => props.onChoice(mode)}>
+            <img class="choice-list-item-icon" src={icon}></img>
+            <label class="choice-list-item-text">
+                <p>{name}</p>
+            </label>
+        </li>
+    ));
+    return <div class="content-pane">
+        <div class="content-pane-header">
+            <h1>How do you want to play?</h1>
+        </div>
+        <div class="choice-list-container">
+            <ul class="choice-list">{items}</ul>
+        </div>
+        <div class="skip-button-container">
+            <button
+                class="skip-button"
+                onClick={props.onResetHistory}>Reset history</button>
+        </div>
+    </div>;
+}
+
 function WebsitePicker(props) {
     const websites = {};
     props.levels.forEach((level) => {
-        websites[level.website] = true;
+        websites[level.website] = level.websiteIcon;
     });
     const items = Object.keys(websites).sort().map((website) => (
         <li class="choice-list-item" onClick={() => props.onChoice(website)}>
-            <img class="choice-list-item-icon" src={websiteIcon(website)}></img>
+            <img class="choice-list-item-icon" src={websites[website]}></img>
             <label class="choice-list-item-text">
                 <p>{website}</p>
             </label>
@@ -240,6 +382,7 @@ function WebsitePicker(props) {
     ));
     return <div class="content-pane">
         <div class="content-pane-header">
+            <button class="back-button" onClick={props.onBack}>Back</button>
             <h1>Select website</h1>
         </div>
         <div class="choice-list-container">
@@ -248,21 +391,11 @@ function WebsitePicker(props) {
     </div>;
 }
 
-function websiteIcon(website) {
-    if (website === 'Amazon') {
-        return '/svg/amazon_box.svg';
-    } else if (website === 'Target') {
-        return '/svg/target.svg';
-    } else {
-        return '/svg/unknown.svg';
-    }
-}
-
 function CategoryPicker(props) {
     const levels = props.levels.filter((x) => x.website == props.website);
     const items = levels.sort().map((level) => (
         <li class="choice-list-item" onClick={() => props.onChoice(level)}>
-            <img class="choice-list-item-icon" src={levelIcon(level)}></img>
+            <img class="choice-list-item-icon" src={level.categoryIcon}></img>
             <div class="choice-list-item-text">
                 <p>{level.category}</p>
             </div>
@@ -279,24 +412,6 @@ function CategoryPicker(props) {
     </div>;
 }
 
-function levelIcon(level) {
-    if (level.id === 'amazon-all') {
-        return '/svg/amazon_box.svg';
-    } else if (level.id === 'amazon-if') {
-        return '/svg/treasure_chest.svg';
-    } else if (level.id === 'amazon-thi') {
-        return '/svg/wrench.svg';
-    } else if (level.id === 'target-clothes') {
-        return '/svg/t_shirt.svg';
-    } else if (level.id === 'target-sports-outdoors') {
-        return '/svg/camping.svg';
-    } else if (level.id === 'target-all') {
-        return '/svg/target.svg';
-    } else {
-        return '/svg/unknown.svg';
-    }
-}
-
 function PlayersPicker(props) {
     const [numPlayers, setNumPlayers] = React.useState('2');
     const parsed = parseInt(numPlayers);
@@ -331,6 +446,29 @@ function PlayersPicker(props) {
     </div>;
 }
 
+function RulePicker(props) {
+    const items = props.rules.map((rule) => (
+        <li class="choice-list-item" onClick={() => props.onChoice(rule)}>
+            <img class="choice-list-item-icon" src="/svg/calculator.svg"></img>
+            <div class="choice-list-item-text">
+                <p>
+                    {rule.name}<br />
+                    <span class="choice-list-item-subtext">{rule.description}</span>
+                </p>
+            </div>
+        </li>
+    ));
+    return <div class="content-pane">
+        <div class="content-pane-header">
+            <button class="back-button" onClick={props.onBack}>Back</button>
+            <h1>How to score?</h1>
+        </div>
+        <div class="choice-list-container">
+            <ul class="choice-list">{items}</ul>
+        </div>
+    </div>;
+}
+
 function GuessPicker(props) {
     const price = props.value;
 
@@ -348,9 +486,10 @@ function GuessPicker(props) {
         parsed > 0
     );
 
+    const title = props.title || ("Guess for Player " + props.player);
     return <div class="content-pane">
         <div class="content-pane-header">
-            <h1>Guess for Player {props.player}</h1>
+            <h1>{title}</h1>
         </div>
         <div class="product-listing">
             <div class="product-listing-thumbnail-container">
@@ -368,7 +507,7 @@ function GuessPicker(props) {
                     props.onChoice(parsed);
                 }
             }}
-            placeholder={"Guess for Player " + props.player}
+            placeholder={title}
             onChange={props.onChange} />
         <button
             class={valid ? "ok-button" : "ok-button ok-button-disabled"}
@@ -377,16 +516,17 @@ function GuessPicker(props) {
                     props.onChoice(parsed);
                 }
             }}>Submit</button>
-        <div class="skip-button-container">
+        {props.onSkip ? <div class="skip-button-container">
             <button
                 class="skip-button"
                 onClick={props.onSkip}>Skip</button>
-        </div>
+        </div> : null}
     </div>;
 }
 
 function Guesses(props) {
     const results = props.lastResults;
+    const previousPrice = results.previousPrice();
 
     const rows = results.guesses.map((x, i) => {
         return <tr>
@@ -417,8 +557,11 @@ function Guesses(props) {
             {rows}
         </table>
         <div class="product-price-answer">
-            {"$" + (props.listing.price / 100).toFixed(2)}
+            {"$" + (results.price / 100).toFixed(2)}
         </div>
+        {previousPrice !== null ? <p class="product-price-previous">
+            {"Previously $" + (previousPrice / 100).toFixed(2)}
+        </p> : null}
         <button
             class="ok-button"
             onClick={props.onNext}>Next</button>
@@ -433,16 +576,15 @@ function Scoreboard(props) {
         scores[i] = 0;
     }
     results.forEach((result) => {
-        const winners = result.winners();
-        winners.forEach((i) => {
-            scores[i] += 1 / winners.length;
+        result.points.forEach((x, i) => {
+            scores[i] += x;
         });
     })
 
     const rows = scores.map((x, i) => {
         return <tr>
             <td>Player {i + 1}</td>
-            <td>{x} points</td>
+            <td>{parseFloat(x.toFixed(2))} points</td>
         </tr>;
     });
 
@@ -476,26 +618,255 @@ function WinnerStatus(props) {
     return <div class={"winner-status winner-status-" + (won ? "winner" : "loser")}></div>;
 }
 
+function RoomSetup(props) {
+    const [name, setName] = React.useState(localStorage.playerName || '');
+    const [code, setCode] = React.useState('');
+    const valid = name.trim().length > 0 && (!props.joining || code.trim().length > 0);
+    const submit = () => {
+        if (valid) {
+            localStorage.playerName = name.trim();
+            props.onChoice({ name: name.trim(), code: props.joining ? code.trim() : null });
+        }
+    };
+    const onKeyUp = (e) => {
+        if (e.key === 'Enter') {
+            submit();
+        }
+    };
+    return <div class="content-pane">
+        <div class="content-pane-header">
+            <button class="back-button" onClick={props.onBack}>Back</button>
+            <h1>{props.joining ? 'Join a room' : 'Host a room'}</h1>
+        </div>
+        {props.joining ? <input
+            class="room-code-input"
+            value={code}
+            placeholder="Room code"
+            autoFocus
+            onKeyUp={onKeyUp}
+            onChange={(e) => setCode(e.target.value.toUpperCase())} /> : null}
+        <input
+            class="player-name-input"
+            value={name}
+            placeholder="Your name"
+            autoFocus={!props.joining}
+            onKeyUp={onKeyUp}
+            onChange={(e) => setName(e.target.value)} />
+        <button
+            class={valid ? "ok-button" : "ok-button ok-button-disabled"}
+            onClick={submit}>{props.joining ? 'Join' : 'Create'}</button>
+    </div>;
+}
+
+class RoomGame extends React.Component {
+    constructor(props) {
+        super(props);
+        this.state = {
+            error: null,
+            code: null,
+            player: null,
+            host: null,
+            players: [],
+            round: null,
+            deadline: null,
+            guessValue: '',
+            guessed: false,
+            reveal: null,
+            done: false,
+        };
+        this._connection = null;
+        this._timer = null;
+    }
+
+    componentDidMount() {
+        this._connection = new RoomConnection(
+            (msg) => this.handleMessage(msg),
+            () => this.setState({ error: 'The connection to the room was lost.' }),
+        );
+        if (this.props.code) {
+            this._connection.join(this.props.code, this.props.name);
+        } else {
+            this._connection.create(this.props.name, this.props.level.id, this.props.rule.id);
+        }
+        this._timer = setInterval(() => this.forceUpdate(), 1000);
+    }
+
+    componentWillUnmount() {
+        this._connection.close();
+        clearInterval(this._timer);
+    }
+
+    handleMessage(msg) {
+        if (msg.type === 'joined') {
+            this.setState({ code: msg.code, player: msg.player });
+        } else if (msg.type === 'players') {
+            this.setState({ host: msg.host, players: msg.players });
+        } else if (msg.type === 'round') {
+            this.setState({
+                round: msg,
+                deadline: Date.now() + msg.timeLimit * 1000,
+                guessValue: '',
+                guessed: false,
+                reveal: null,
+            });
+        } else if (msg.type === 'reveal') {
+            this.setState({ reveal: msg });
+        } else if (msg.type === 'done') {
+            this.setState({ done: true });
+        } else if (msg.type === 'error') {
+            if (this.state.code === null) {
+                this.setState({ error: msg.message });
+            } else {
+                alert(msg.message);
+            }
+        }
+    }
+
+    render() {
+        if (this.state.error) {
+            return <Error message={this.state.error} />;
+        } else if (this.state.code === null) {
+            return <Loader />;
+        } else if (this.state.done) {
+            return this.renderDone();
+        } else if (this.state.reveal) {
+            return this.renderReveal();
+        } else if (this.state.round && !this.state.guessed) {
+            return this.renderGuessing();
+        } else if (this.state.round) {
+            return this.renderWaiting();
+        }
+        return this.renderLobby();
+    }
+
+    isHost() {
+        return this.state.host === this.state.player;
+    }
+
+    renderLobby() {
+        return <div class="content-pane">
+            <div class="content-pane-header">
+                <h1>Room {this.state.code}</h1>
+            </div>
+            <p class="room-instructions">
+                Other players can join from their own devices with the code <b>{this.state.code}</b>.
+            </p>
+            <RoomPlayers players={this.state.players} host={this.state.host} />
+            {this.isHost()
+                ? <button class="ok-button" onClick={() => this._connection.start()}>Start</button>
+                : <p class="room-instructions">Waiting for the host to start...</p>}
+        </div>;
+    }
+
+    renderGuessing() {
+        const remaining = Math.max(0, Math.ceil((this.state.deadline - Date.now()) / 1000));
+        return <GuessPicker
+            title={"Your guess (" + remaining + "s)"}
+            listing={this.state.round}
+            value={this.state.guessValue}
+            onChange={(e) => this.setState({ guessValue: e.target.value })}
+            onChoice={(guess) => {
+                this._connection.guess(guess);
+                this.setState({ guessed: true });
+            }} />;
+    }
+
+    renderWaiting() {
+        const remaining = Math.max(0, Math.ceil((this.state.deadline - Date.now()) / 1000));
+        return <div class="content-pane">
+            <div class="content-pane-header">
+                <h1>Waiting for guesses ({remaining}s)</h1>
+            </div>
+            <RoomPlayers players={this.state.players} host={this.state.host} showGuessed={true} />
+        </div>;
+    }
+
+    renderReveal() {
+        const reveal = this.state.reveal;
+        const rows = reveal.guesses.map((x, i) => {
+            const won = reveal.winners.includes(i);
+            return <tr>
+                <td>
+                    <div class={"winner-status winner-status-" + (won ? "winner" : "loser")}></div>
+                </td>
+                <td>{this.state.players[i] ? this.state.players[i].name : ''}</td>
+                <td>{x === null ? '-' : '$' + x}</td>
+            </tr>;
+        });
+        return <div class="content-pane">
+            <div class="content-pane-header">
+                <h1>Guesses</h1>
+            </div>
+            <div class="product-listing">
+                <div class="product-listing-thumbnail-container">
+                    <img class="product-listing-thumbnail" src={this.state.round.imageURL} />
+                </div>
+                <p class="product-listing-text">{this.state.round.title}</p>
+            </div>
+            <label class="product-price-guesses-label">Guesses:</label>
+            <table class="guesses-table">
+                {rows}
+            </table>
+            <div class="product-price-answer">
+                {"$" + (reveal.price / 100).toFixed(2)}
+            </div>
+            <RoomPlayers players={this.state.players} host={this.state.host} showScores={true} />
+            {this.isHost()
+                ? <button class="ok-button" onClick={() => this._connection.next()}>Next</button>
+                : <p class="room-instructions">Waiting for the host to continue...</p>}
+        </div>;
+    }
+
+    renderDone() {
+        return <div class="content-pane">
+            <div class="content-pane-header">
+                <h1>Scoreboard</h1>
+            </div>
+            <p class="error">There are no more items in this level</p>
+            <RoomPlayers players={this.state.players} host={this.state.host} showScores={true} />
+            <button class="ok-button" onClick={this.props.onNewGame}>New Game</button>
+        </div>;
+    }
+}
+
+function RoomPlayers(props) {
+    const rows = props.players.map((player, i) => {
+        let status = player.connected ? '' : '(left)';
+        if (props.showScores) {
+            status = parseFloat(player.score.toFixed(2)) + ' points';
+        } else if (props.showGuessed && player.connected) {
+            status = player.guessed ? 'Guessed' : 'Thinking...';
+        }
+        return <tr>
+            <td>{player.name}{i === props.host ? ' (host)' : ''}</td>
+            <td>{status}</td>
+        </tr>;
+    });
+    return <table class="scoreboard-table">
+        {rows}
+    </table>;
+}
+
 class RoundResult {
-    constructor(listing, guesses) {
+    constructor(listing, guesses, price, points, winners, history) {
         this.listing = listing;
         this.guesses = guesses;
+        this.price = price;
+        this.points = points;
+        this._winners = winners;
+        this.history = history;
+    }
+
+    previousPrice() {
+        const prices = this.history.map((x) => x.price);
+        while (prices.length > 0 && prices[prices.length - 1] === this.price) {
+            prices.pop();
+        }
+        return prices.length > 0 ? prices[prices.length - 1] : null;
     }
 
     winners() {
-        let bestGuess = Infinity;
-        let indices = [];
-        this.guesses.forEach((x, i) => {
-            const err = Math.abs(x - this.listing.price / 100);
-            const bestErr = Math.abs(bestGuess - this.listing.price / 100);
-            if (err < bestErr) {
-                bestGuess = x;
-                indices = [i];
-            } else if (err === bestErr) {
-                indices.push(i);
-            }
-        });
-        return indices;
+        return this._winners;
     }
 }
 
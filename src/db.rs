@@ -1,56 +1,200 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::ops::{Deref, DerefMut};
 use std::path::Path;
 use std::rc::Rc;
 use std::sync::Arc;
-use std::{fmt::Write, time::Duration};
+use std::time::{Duration, Instant};
 
-use rusqlite::{Connection, ErrorCode, Transaction};
+use rand::Rng;
+use rusqlite::{params_from_iter, types::ToSql, Connection, OpenFlags, Transaction};
 use sha2::Digest;
-use tokio::{sync::Mutex, task::spawn_blocking};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::task::spawn_blocking;
 
-use crate::levels::{Level, LEVELS};
+use crate::levels::{Level, LevelFilter};
+use crate::log::LogLevel;
+use crate::migrations::migrate;
 
-const LOG_LIMIT: i64 = 5000;
+// The default number of log messages to keep at each level.
+pub const DEFAULT_LOG_LIMIT: i64 = 5000;
+
+// The number of runs to remember for each source.
+const SOURCE_RUN_LIMIT: i64 = 100;
+
+// Rounds older than this many seconds are deleted during cleanup.
+const ROUND_LIFETIME: i64 = 60 * 60 * 24 * 7;
+
+// The percent error recorded for a round which was lost without a guess,
+// such as one which was never revealed.
+pub const FORFEIT_ERROR: f64 = 100.0;
+
+// Sessions which have not been used for this many seconds are deleted during
+// cleanup, along with the listings they have seen.
+pub const SESSION_LIFETIME: i64 = 60 * 60 * 24 * 365;
+
+// The last active time of a session is only updated when it is at least this
+// many seconds old, to avoid a write on every request.
+const SESSION_TOUCH_INTERVAL: i64 = 60 * 60 * 24;
+
+// A login code is discarded after this many wrong guesses.
+const MAX_LOGIN_CODE_ATTEMPTS: i64 = 5;
+
+// The number of connections used for reading from a database file.
+const READ_CONNECTIONS: usize = 4;
+
+// The longest time to wait for a lock held by another process, such as a
+// scrape command running alongside the server, before a query fails.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The longest time that cached level counts are used for.
+const LEVEL_COUNT_LIFETIME: Duration = Duration::from_secs(60);
 
 #[derive(Clone)]
 pub struct Database {
-    db: Arc<Mutex<Connection>>,
+    // Every write goes through this connection, so writes wait their turn
+    // here instead of contending for the database lock. The connection is
+    // removed once the database is closed.
+    writer: Arc<Mutex<Option<Connection>>>,
+
+    // Connections for queries which don't modify the database. Since the
+    // database uses write-ahead logging, these can run during a write.
+    readers: Arc<ReadPool>,
+
+    level_counts: Arc<std::sync::Mutex<LevelCountCache>>,
+    log_limits: LogLimits,
+}
+
+// Cached sizes of the levels in level_listings. Writes from this process
+// clear the cache, and it expires so that writes from other processes, such
+// as the scrape command, are seen eventually.
+#[derive(Default)]
+struct LevelCountCache {
+    // Incremented whenever the cache is cleared, so that a count which was
+    // started before a write can't be stored after it.
+    generation: u64,
+
+    counts: Option<(Instant, HashMap<String, i64>)>,
+}
+
+// The number of log messages to keep at each level, so that noisy levels
+// don't evict rarer and more important messages.
+#[derive(Clone, Copy)]
+pub struct LogLimits {
+    pub info: i64,
+    pub warn: i64,
+    pub error: i64,
+}
+
+impl Default for LogLimits {
+    fn default() -> LogLimits {
+        LogLimits {
+            info: DEFAULT_LOG_LIMIT,
+            warn: DEFAULT_LOG_LIMIT,
+            error: DEFAULT_LOG_LIMIT,
+        }
+    }
+}
+
+impl LogLimits {
+    fn limit(&self, level: LogLevel) -> i64 {
+        match level {
+            LogLevel::Info => self.info,
+            LogLevel::Warn => self.warn,
+            LogLevel::Error => self.error,
+        }
+    }
 }
 
 impl Database {
     pub async fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<Database> {
         let path = path.as_ref().to_owned();
-        spawn_blocking_rusqlite(move || Database::new_with_conn(Connection::open(&path)?)).await
+        spawn_blocking_rusqlite(move || {
+            let mut writer = Connection::open(&path)?;
+            writer.busy_timeout(BUSY_TIMEOUT)?;
+            writer.pragma_update_and_check(None, "journal_mode", "WAL", |row| {
+                row.get::<_, String>(0)
+            })?;
+            // With write-ahead logging, this can only lose the latest commits
+            // on power loss, and never corrupts the database.
+            writer.pragma_update(None, "synchronous", "NORMAL")?;
+            migrate(&mut writer)?;
+
+            let mut readers = Vec::new();
+            for _ in 0..READ_CONNECTIONS {
+                let reader = Connection::open_with_flags(
+                    &path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY
+                        | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                        | OpenFlags::SQLITE_OPEN_URI,
+                )?;
+                reader.busy_timeout(BUSY_TIMEOUT)?;
+                rusqlite::vtab::array::load_module(&reader)?;
+                readers.push(reader);
+            }
+            Database::new_with_conns(writer, readers)
+        })
+        .await
     }
 
     #[allow(dead_code)]
     pub async fn open_in_memory() -> rusqlite::Result<Database> {
-        spawn_blocking_rusqlite(move || Database::new_with_conn(Connection::open_in_memory()?))
-            .await
+        spawn_blocking_rusqlite(move || {
+            let mut conn = Connection::open_in_memory()?;
+            migrate(&mut conn)?;
+            // Other connections would open a separate database, so every
+            // query uses the writer.
+            Database::new_with_conns(conn, Vec::new())
+        })
+        .await
     }
 
-    fn new_with_conn(conn: Connection) -> rusqlite::Result<Database> {
-        create_tables(&conn)?;
-        rusqlite::vtab::array::load_module(&conn)?;
+    fn new_with_conns(writer: Connection, readers: Vec<Connection>) -> rusqlite::Result<Database> {
+        rusqlite::vtab::array::load_module(&writer)?;
         Ok(Database {
-            db: Arc::new(Mutex::new(conn)),
+            writer: Arc::new(Mutex::new(Some(writer))),
+            readers: Arc::new(ReadPool::new(readers)),
+            level_counts: Default::default(),
+            log_limits: Default::default(),
         })
     }
 
+    pub fn with_log_limits(self, log_limits: LogLimits) -> Database {
+        Database { log_limits, ..self }
+    }
+
+    // Close the connections once any pending queries have finished. Later
+    // queries on this database or its clones fail.
+    pub async fn close(&self) -> rusqlite::Result<()> {
+        // Close the readers first, so that the writer is the last connection
+        // and can clean up the write-ahead log.
+        for reader in self.readers.close().await {
+            reader.close().map_err(|(_, e)| e)?;
+        }
+        let writer = self.writer.clone();
+        spawn_blocking_rusqlite(move || match writer.blocking_lock().take() {
+            Some(conn) => conn.close().map_err(|(_, e)| e),
+            None => Ok(()),
+        })
+        .await
+    }
+
     // Either insert a new listing, or update the information if the website_id
     // is already present in the database.
-    pub async fn insert_or_update(&self, listing: Listing) -> rusqlite::Result<()> {
-        self.with_db(move |db| {
-            let mut tx = db.transaction()?;
-            let blob_id = insert_blob(&mut tx, &listing.image_data)?;
-            let result: rusqlite::Result<(i64, i64)> = tx.query_row(
-                "SELECT id, image_blob FROM listings WHERE website=?1 AND website_id=?2",
-                (&listing.website, &listing.website_id),
-                |row| Ok((row.get(0)?, row.get(1)?)),
-            );
-            match result {
-                Ok((id, old_image_blob)) => {
-                    tx.execute(
-                        "
+    pub async fn insert_or_update(&self, listing: Listing) -> rusqlite::Result<ListingChange> {
+        let result = self
+            .with_writer(move |db| {
+                let mut tx = db.transaction()?;
+                let blob_id = insert_blob(&mut tx, &listing.image_data)?;
+                let result: rusqlite::Result<(i64, i64)> = tx.query_row(
+                    "SELECT id, image_blob FROM listings WHERE website=?1 AND website_id=?2",
+                    (&listing.website, &listing.website_id),
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                );
+                let (id, change) = match result {
+                    Ok((id, old_image_blob)) => {
+                        tx.execute(
+                            "
                             UPDATE listings
                             SET image_blob = ?1,
                                 price = ?2,
@@ -58,14 +202,16 @@ impl Database {
                                 last_seen = unixepoch()
                             WHERE id=?4
                         ",
-                        rusqlite::params![blob_id, listing.price, listing.title, id],
-                    )?;
-                    garbage_collect_blob(&mut tx, old_image_blob)?;
-                    insert_categories(&mut tx, id, &listing.categories)?;
-                }
-                Err(rusqlite::Error::QueryReturnedNoRows) => {
-                    tx.execute(
-                        "
+                            rusqlite::params![blob_id, listing.price, listing.title, id],
+                        )?;
+                        garbage_collect_blob(&mut tx, old_image_blob)?;
+                        insert_categories(&mut tx, id, &listing.categories)?;
+                        record_price(&mut tx, id, listing.price)?;
+                        (id, ListingChange::Updated)
+                    }
+                    Err(rusqlite::Error::QueryReturnedNoRows) => {
+                        tx.execute(
+                            "
                         INSERT INTO listings (
                             created,
                             last_seen,
@@ -90,40 +236,93 @@ impl Database {
                             ?8
                         )
                     ",
-                        rusqlite::params![
-                            listing.website,
-                            listing.website_id,
-                            listing.price,
-                            listing.title,
-                            blob_id,
-                            listing.star_rating,
-                            listing.max_stars,
-                            listing.num_reviews,
-                        ],
-                    )?;
-                    let insert_id = tx.last_insert_rowid();
-                    insert_categories(&mut tx, insert_id, &listing.categories)?;
+                            rusqlite::params![
+                                listing.website,
+                                listing.website_id,
+                                listing.price,
+                                listing.title,
+                                blob_id,
+                                listing.star_rating,
+                                listing.max_stars,
+                                listing.num_reviews,
+                            ],
+                        )?;
+                        let insert_id = tx.last_insert_rowid();
+                        insert_categories(&mut tx, insert_id, &listing.categories)?;
+                        record_price(&mut tx, insert_id, listing.price)?;
+                        (insert_id, ListingChange::Inserted)
+                    }
+                    Err(e) => return Err(e),
+                };
+                index_listing(&tx, id)?;
+                tx.commit()?;
+                Ok(change)
+            })
+            .await;
+        self.clear_level_counts();
+        result
+    }
+
+    // Make level_listings match the given levels, indexing any level which
+    // is new or whose filter has changed. Levels which are not given are
+    // removed from the index.
+    pub async fn sync_levels(&self, levels: Vec<Level>) -> rusqlite::Result<()> {
+        let result = self
+            .with_writer(move |db| {
+                let tx = db.transaction()?;
+                let existing = tx
+                    .prepare("SELECT id, filter FROM levels")?
+                    .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<rusqlite::Result<HashMap<String, String>>>()?;
+                for id in existing.keys() {
+                    if !levels.iter().any(|x| &x.id == id) {
+                        tx.execute("DELETE FROM levels WHERE id=?1", (id,))?;
+                        tx.execute("DELETE FROM level_listings WHERE level_id=?1", (id,))?;
+                    }
                 }
-                x @ Err(_) => {
-                    x?;
+                for level in &levels {
+                    let filter = serde_json::to_string(&level.filter)
+                        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+                    if existing.get(&level.id) == Some(&filter) {
+                        continue;
+                    }
+                    tx.execute(
+                        "INSERT OR REPLACE INTO levels (id, filter) VALUES (?1, ?2)",
+                        (&level.id, &filter),
+                    )?;
+                    tx.execute("DELETE FROM level_listings WHERE level_id=?1", (&level.id,))?;
+                    let (condition, params) = level.listing_query(2);
+                    tx.execute(
+                        &format!(
+                            "
+                                INSERT INTO level_listings (level_id, listing_id, day)
+                                SELECT ?1, id, last_seen / (60*60*24) FROM listings
+                                WHERE {}
+                            ",
+                            condition
+                        ),
+                        params_from_iter(prepend_param(&level.id, &params)),
+                    )?;
                 }
-            }
-            tx.commit()?;
-            Ok(())
-        })
-        .await
+                tx.commit()
+            })
+            .await;
+        self.clear_level_counts();
+        result
     }
 
     pub async fn insert_log_message(
         &self,
+        level: LogLevel,
         source: String,
         message: String,
     ) -> rusqlite::Result<String> {
-        self.with_db(move |db| {
+        let limit = self.log_limits.limit(level);
+        self.with_writer(move |db| {
             let tx = db.transaction()?;
             tx.execute(
-                "INSERT INTO log (timestamp, source, message) VALUES (unixepoch(), ?1, ?2)",
-                (&source, &message),
+                "INSERT INTO log (timestamp, level, source, message) VALUES (unixepoch(), ?1, ?2, ?3)",
+                (level.as_str(), &source, &message),
             )?;
             let timestamp: String = tx
                 .prepare(
@@ -134,10 +333,10 @@ impl Database {
                 )?
                 .query_row((tx.last_insert_rowid(),), |row| row.get(0))?;
             tx.execute(
-                "DELETE FROM log WHERE id NOT IN (
-                    SELECT id FROM log ORDER BY timestamp DESC LIMIT ?1
+                "DELETE FROM log WHERE level = ?1 AND id <= (
+                    SELECT id FROM log WHERE level = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2
                 )",
-                (LOG_LIMIT,),
+                (level.as_str(), limit),
             )?;
             tx.commit()?;
             Ok(timestamp)
@@ -145,12 +344,72 @@ impl Database {
         .await
     }
 
+    // Find log messages matching a query, newest first.
+    pub async fn query_logs(&self, query: LogQuery) -> rusqlite::Result<Vec<LogMessage>> {
+        self.with_reader(move |db| {
+            let mut clauses = Vec::new();
+            let mut params: Vec<rusqlite::types::Value> = Vec::new();
+            let mut next_param = |value: rusqlite::types::Value| {
+                params.push(value);
+                format!("?{}", params.len())
+            };
+            if !query.levels.is_empty() {
+                let names = query
+                    .levels
+                    .iter()
+                    .map(|x| next_param(x.as_str().to_owned().into()))
+                    .collect::<Vec<_>>();
+                clauses.push(format!("level IN ({})", names.join(", ")));
+            }
+            if let Some(source) = &query.source {
+                let param = next_param(source.clone().into());
+                clauses.push(format!("SUBSTR(source, 1, LENGTH({0})) = {0}", param));
+            }
+            if let Some(since) = query.since {
+                clauses.push(format!("timestamp >= {}", next_param(since.into())));
+            }
+            if let Some(until) = query.until {
+                clauses.push(format!("timestamp < {}", next_param(until.into())));
+            }
+            if let Some(before_id) = query.before_id {
+                clauses.push(format!("id < {}", next_param(before_id.into())));
+            }
+            let limit = next_param(query.limit.into());
+            let condition = if clauses.is_empty() {
+                "1".to_owned()
+            } else {
+                clauses.join(" AND ")
+            };
+            db.prepare(&format!(
+                "
+                    SELECT id, timestamp, level, source, message FROM log
+                    WHERE {}
+                    ORDER BY id DESC
+                    LIMIT {}
+                ",
+                condition, limit
+            ))?
+            .query_map(params_from_iter(params.iter()), |row| {
+                let level: String = row.get(2)?;
+                Ok(LogMessage {
+                    id: row.get(0)?,
+                    timestamp: row.get(1)?,
+                    level: LogLevel::parse(&level).unwrap_or(LogLevel::Info),
+                    source: row.get(3)?,
+                    message: row.get(4)?,
+                })
+            })?
+            .collect()
+        })
+        .await
+    }
+
     pub async fn should_update_source(
         &self,
         source: String,
         max_seconds: i64,
     ) -> rusqlite::Result<bool> {
-        self.with_db(move |db| {
+        self.with_reader(move |db| {
             match db.query_row(
                 "SELECT (last_updated+?1 < unixepoch()) FROM source_status WHERE source_id=?2",
                 (max_seconds, &source),
@@ -158,14 +417,14 @@ impl Database {
             ) {
                 Ok(value) => Ok(value),
                 Err(rusqlite::Error::QueryReturnedNoRows) => Ok(true),
-                x @ Err(_) => x.map_err(|e| e.into()),
+                x @ Err(_) => x,
             }
         })
         .await
     }
 
     pub async fn updated_source(&self, source: String) -> rusqlite::Result<()> {
-        self.with_db(move |db| {
+        self.with_writer(move |db| {
             db.execute(
                 "INSERT OR REPLACE INTO source_status (source_id, last_updated) VALUES (?1, unixepoch())",
                 (&source,),
@@ -174,194 +433,1045 @@ impl Database {
         .await
     }
 
+    // Get the last time each source was updated, as a UNIX timestamp.
+    pub async fn source_update_times(&self) -> rusqlite::Result<HashMap<String, i64>> {
+        self.with_reader(|db| {
+            let mut stmt = db.prepare("SELECT source_id, last_updated FROM source_status")?;
+            let rows = stmt.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect()
+        })
+        .await
+    }
+
+    pub async fn record_source_run(&self, run: SourceRun) -> rusqlite::Result<()> {
+        self.with_writer(move |db| {
+            let tx = db.transaction()?;
+            tx.execute(
+                "
+                    INSERT INTO source_runs (
+                        source_id,
+                        started,
+                        duration_ms,
+                        error,
+                        listings_added,
+                        listings_updated
+                    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ",
+                rusqlite::params![
+                    run.source_id,
+                    run.started,
+                    run.duration_ms,
+                    run.error,
+                    run.listings_added,
+                    run.listings_updated,
+                ],
+            )?;
+            tx.execute(
+                "DELETE FROM source_runs WHERE source_id=?1 AND id NOT IN (
+                    SELECT id FROM source_runs WHERE source_id=?1 ORDER BY id DESC LIMIT ?2
+                )",
+                (&run.source_id, SOURCE_RUN_LIMIT),
+            )?;
+            tx.commit()
+        })
+        .await
+    }
+
+    // Summarize the recent runs of every source which has been updated.
+    pub async fn source_health(&self) -> rusqlite::Result<HashMap<String, SourceHealth>> {
+        self.with_reader(|db| {
+            let mut result = HashMap::new();
+            let mut stmt = db.prepare(
+                "
+                    SELECT source_id, started, duration_ms, error, listings_added, listings_updated
+                    FROM source_runs
+                    WHERE id IN (SELECT MAX(id) FROM source_runs GROUP BY source_id)
+                ",
+            )?;
+            for row in stmt.query_map((), |row| {
+                Ok(SourceRun {
+                    source_id: row.get(0)?,
+                    started: row.get(1)?,
+                    duration_ms: row.get(2)?,
+                    error: row.get(3)?,
+                    listings_added: row.get(4)?,
+                    listings_updated: row.get(5)?,
+                })
+            })? {
+                let run = row?;
+                result.insert(
+                    run.source_id.clone(),
+                    SourceHealth {
+                        last_run: run,
+                        last_success: None,
+                        last_error: None,
+                    },
+                );
+            }
+
+            let mut stmt = db.prepare(
+                "
+                    SELECT source_id, MAX(started) FROM source_runs
+                    WHERE error IS NULL GROUP BY source_id
+                ",
+            )?;
+            for row in stmt.query_map((), |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))? {
+                let (source_id, started) = row?;
+                if let Some(health) = result.get_mut(&source_id) {
+                    health.last_success = Some(started);
+                }
+            }
+
+            let mut stmt = db.prepare(
+                "
+                    SELECT source_id, started, error FROM source_runs
+                    WHERE id IN (
+                        SELECT MAX(id) FROM source_runs
+                        WHERE error IS NOT NULL GROUP BY source_id
+                    )
+                ",
+            )?;
+            for row in stmt.query_map((), |row| {
+                Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?))
+            })? {
+                let (source_id, started, error) = row?;
+                if let Some(health) = result.get_mut(&source_id) {
+                    health.last_error = Some((started, error));
+                }
+            }
+            Ok(result)
+        })
+        .await
+    }
+
     // Delete old listings in categories that have more than enough
     // listings. Retains listings which are needed for some category
     // when that category is sorted by last seen date.
     //
-    // Returns (deleted listings, deleted blobs).
+    // Also deletes rounds which are older than ROUND_LIFETIME, whether or not
+    // they were ever revealed, sessions which have been inactive for
+    // SESSION_LIFETIME, and expired login codes.
     pub async fn delete_old_listings(
         &self,
         category_capacity: i64,
+        levels: Vec<Level>,
     ) -> rusqlite::Result<DeleteCounts> {
-        self.with_db(move |db| {
-            let tx = db.transaction()?;
+        let result = self
+            .with_writer(move |db| {
+                let tx = db.transaction()?;
+
+                // Retain listings that are not in any levels, since they
+                // won't be updated and don't pose a leak threat as a result.
+                tx.execute("UPDATE listings SET sweep_mark = 1", ())?;
+
+                // By default, every listing contained with a level is dropped.
+                for level in &levels {
+                    let (condition, params) = level.listing_query(1);
+                    tx.execute(
+                        &format!("UPDATE listings SET sweep_mark = 0 WHERE {}", condition),
+                        params_from_iter(params),
+                    )?;
+                }
+
+                // Explicitly mark the latest listings of every level to be retained.
+                for level in &levels {
+                    let (condition, params) = level.listing_query(2);
+                    tx.execute(
+                        &format!(
+                            "
+                                UPDATE listings
+                                SET sweep_mark = 1
+                                WHERE id IN (
+                                    SELECT id FROM listings
+                                    WHERE {}
+                                    ORDER BY last_seen DESC
+                                    LIMIT ?1
+                                )
+                            ",
+                            condition
+                        ),
+                        params_from_iter(prepend_param(&category_capacity, &params)),
+                    )?;
+                }
+
+                let listing_count = tx.execute("DELETE FROM listings WHERE sweep_mark = 0", ())?;
+
+                let blob_count = tx.execute(
+                    "
+                        DELETE FROM blobs WHERE (
+                            SELECT COUNT(*) FROM listings WHERE listings.image_blob = blobs.id
+                        ) == 0
+                    ",
+                    (),
+                )?;
+
+                forfeit_rounds(&tx, "rounds.created < unixepoch() - ?1", (ROUND_LIFETIME,))?;
+                let round_count = tx.execute(
+                    "DELETE FROM rounds WHERE created < unixepoch() - ?1",
+                    (ROUND_LIFETIME,),
+                )?;
 
-            // Retain listings that are not in any levels, since they
-            // won't be updated and don't pose a leak threat as a result.
-            tx.execute("UPDATE listings SET sweep_mark = 1", ())?;
+                let category_count = tx.execute(
+                    "
+                        DELETE FROM categories WHERE NOT EXISTS (
+                            SELECT NULL FROM listings WHERE listings.id = categories.listing_id
+                        )
+                    ",
+                    (),
+                )?;
 
-            // By default, every listing contained with a level is dropped.
-            for level in LEVELS {
                 tx.execute(
-                    &format!(
-                        "UPDATE listings SET sweep_mark = 0 WHERE {}",
-                        level.listing_query()
-                    ),
+                    "
+                        DELETE FROM price_history WHERE NOT EXISTS (
+                            SELECT NULL FROM listings WHERE listings.id = price_history.listing_id
+                        )
+                    ",
                     (),
                 )?;
-            }
 
-            // Explicitly mark the latest listings of every level to be retained.
-            for level in LEVELS {
                 tx.execute(
-                    &format!(
-                        "
-                            UPDATE listings
-                            SET sweep_mark = 1
-                            WHERE id IN (
-                                SELECT id FROM listings
-                                WHERE {}
-                                ORDER BY last_seen DESC
-                                LIMIT ?1
-                            )
-                        ",
-                        level.listing_query()
-                    ),
-                    (category_capacity,),
+                    "
+                        DELETE FROM level_listings WHERE NOT EXISTS (
+                            SELECT NULL FROM listings WHERE listings.id = level_listings.listing_id
+                        )
+                    ",
+                    (),
+                )?;
+
+                tx.execute(
+                    "DELETE FROM sessions WHERE last_active < unixepoch() - ?1",
+                    (SESSION_LIFETIME,),
+                )?;
+
+                tx.execute("DELETE FROM login_codes WHERE expires < unixepoch()", ())?;
+
+                tx.execute(
+                    "
+                        DELETE FROM seen_listings WHERE NOT EXISTS (
+                            SELECT NULL FROM listings WHERE listings.id = seen_listings.listing_id
+                        ) OR NOT EXISTS (
+                            SELECT NULL FROM sessions WHERE sessions.id = seen_listings.session_id
+                        )
+                    ",
+                    (),
                 )?;
+
+                tx.commit()?;
+                Ok(DeleteCounts {
+                    listings: listing_count,
+                    blobs: blob_count,
+                    categories: category_count,
+                    rounds: round_count,
+                })
+            })
+            .await;
+        self.clear_level_counts();
+        result
+    }
+
+    // Count the listings in each level, leaving out the blacklisted ones.
+    pub async fn level_counts<I: 'static + Send + Sync + IntoIterator<Item = i64>>(
+        &self,
+        blacklist: I,
+    ) -> rusqlite::Result<HashMap<String, i64>> {
+        let mut counts = self.total_level_counts().await?;
+        let blacklist = blacklist.into_iter().collect::<Vec<_>>();
+        if blacklist.is_empty() {
+            return Ok(counts);
+        }
+        let seen = self
+            .with_reader(move |db| {
+                db.prepare(
+                    "
+                        SELECT level_id, COUNT(*) FROM level_listings
+                        WHERE listing_id IN rarray(?1)
+                        GROUP BY level_id
+                    ",
+                )?
+                .query_map((values_to_rarray(blacklist),), |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+        for (level_id, count) in seen {
+            if let Some(total) = counts.get_mut(&level_id) {
+                *total -= count;
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn total_level_counts(&self) -> rusqlite::Result<HashMap<String, i64>> {
+        let generation = {
+            let cache = self.level_counts.lock().unwrap();
+            if let Some((time, counts)) = &cache.counts {
+                if time.elapsed() < LEVEL_COUNT_LIFETIME {
+                    return Ok(counts.clone());
+                }
             }
+            cache.generation
+        };
+        let counts = self
+            .with_reader(|db| {
+                db.prepare("SELECT level_id, COUNT(*) FROM level_listings GROUP BY level_id")?
+                    .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<rusqlite::Result<HashMap<String, i64>>>()
+            })
+            .await?;
+        let mut cache = self.level_counts.lock().unwrap();
+        if cache.generation == generation {
+            cache.counts = Some((Instant::now(), counts.clone()));
+        }
+        Ok(counts)
+    }
 
-            let listing_count = tx.execute("DELETE FROM listings WHERE sweep_mark = 0", ())?;
+    fn clear_level_counts(&self) {
+        let mut cache = self.level_counts.lock().unwrap();
+        cache.generation += 1;
+        cache.counts = None;
+    }
 
-            let blob_count = tx.execute(
+    // Pick a random listing from a level, preferring the listings seen most
+    // recently by the scraper, and leaving out the blacklisted ones.
+    //
+    // Listings are grouped by the day they were last seen. The newest group
+    // with any listings left is counted using the level_listings index, and
+    // then a random offset into the group is chosen, so the cost depends on
+    // the size of the level rather than the whole database.
+    pub async fn sample_listing<I: 'static + Send + Sync + IntoIterator<Item = i64>>(
+        &self,
+        blacklist: I,
+        level_id: String,
+    ) -> rusqlite::Result<Option<SampledListing>> {
+        self.with_reader(move |db| {
+            let tx = db.transaction()?;
+            let blacklist = values_to_rarray(blacklist);
+            let days = tx
+                .prepare(
+                    "
+                        SELECT day, COUNT(*) FROM level_listings
+                        WHERE level_id=?1
+                        GROUP BY day
+                        ORDER BY day DESC
+                    ",
+                )?
+                .query_map((&level_id,), |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let seen = tx
+                .prepare(
+                    "
+                        SELECT day, COUNT(*) FROM level_listings
+                        WHERE level_id=?1 AND listing_id IN rarray(?2)
+                        GROUP BY day
+                    ",
+                )?
+                .query_map((&level_id, &blacklist), |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+                })?
+                .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+            let (day, remaining) = match days
+                .into_iter()
+                .map(|(day, count)| (day, count - seen.get(&day).copied().unwrap_or_default()))
+                .find(|(_, remaining)| *remaining > 0)
+            {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+            let offset = rand::thread_rng().gen_range(0..remaining);
+            let listing_id: i64 = tx.query_row(
                 "
-                    DELETE FROM blobs WHERE (
-                        SELECT COUNT(*) FROM listings WHERE listings.image_blob = blobs.id
-                    ) == 0
+                    SELECT listing_id FROM level_listings
+                    WHERE level_id=?1 AND day=?2 AND listing_id NOT IN rarray(?3)
+                    ORDER BY listing_id
+                    LIMIT 1 OFFSET ?4
                 ",
-                (),
+                (&level_id, day, &blacklist, offset),
+                |row| row.get(0),
             )?;
 
-            let category_count = tx.execute(
+            let (mut listing, image_id) =
+                tx.query_row("SELECT * FROM listings WHERE id=?1", (listing_id,), |row| {
+                    Ok((
+                        Listing {
+                            website: row.get("website")?,
+                            website_id: row.get("website_id")?,
+                            price: row.get("price")?,
+                            title: row.get("title")?,
+                            image_data: Vec::default(),
+                            categories: Vec::default(),
+                            star_rating: row.get("star_rating")?,
+                            max_stars: row.get("max_stars")?,
+                            num_reviews: row.get("num_reviews")?,
+                        },
+                        row.get::<_, i64>("image_blob")?,
+                    ))
+                })?;
+            let categories: rusqlite::Result<Vec<String>> = tx
+                .prepare("SELECT category FROM categories WHERE listing_id=?1")?
+                .query_map((&listing_id,), |row| row.get("category"))?
+                .collect();
+            listing.categories = categories?;
+            let image_hash =
+                tx.query_row("SELECT hash FROM blobs WHERE id=?1", (&image_id,), |row| {
+                    row.get("hash")
+                })?;
+            Ok(Some(SampledListing {
+                id: listing_id,
+                listing,
+                image_hash,
+            }))
+        })
+        .await
+    }
+
+    // Get every price change that has been observed for a listing, from
+    // oldest to newest. A price may appear more than once if it changed and
+    // later changed back.
+    pub async fn price_history(&self, listing_id: i64) -> rusqlite::Result<Vec<PricePoint>> {
+        self.with_reader(move |db| {
+            db.prepare(
                 "
-                    DELETE FROM categories WHERE NOT EXISTS (
-                        SELECT NULL FROM listings WHERE listings.id = categories.listing_id
-                    )
+                    SELECT price, observed_at FROM price_history
+                    WHERE listing_id=?1
+                    ORDER BY observed_at, id
                 ",
-                (),
-            )?;
+            )?
+            .query_map((listing_id,), |row| {
+                Ok(PricePoint {
+                    price: row.get(0)?,
+                    observed_at: row.get(1)?,
+                })
+            })?
+            .collect()
+        })
+        .await
+    }
 
-            tx.commit()?;
-            Ok(DeleteCounts {
-                listings: listing_count,
-                blobs: blob_count,
-                categories: category_count,
+    pub async fn blob(&self, hash: String) -> rusqlite::Result<Option<Vec<u8>>> {
+        self.with_reader(move |db| {
+            match db.query_row("SELECT data FROM blobs WHERE hash=?1", (&hash,), |row| {
+                row.get(0)
+            }) {
+                Ok(data) => Ok(Some(data)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+        .await
+    }
+
+    pub async fn storage_stats(&self) -> rusqlite::Result<StorageStats> {
+        self.with_reader(|db| {
+            Ok(StorageStats {
+                size_bytes: db.query_row(
+                    "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+                    (),
+                    |row| row.get(0),
+                )?,
+                blobs: db.query_row("SELECT COUNT(*) FROM blobs", (), |row| row.get(0))?,
             })
         })
         .await
     }
 
-    pub async fn level_count<I: 'static + Send + Sync + Clone + IntoIterator<Item = i64>>(
+    // Start a new round for a sampled listing and return its token.
+    //
+    // The price is copied into the round so that the answer is stable even if
+    // the listing is updated or deleted before the round is revealed.
+    //
+    // Rounds sampled for a session belong to it, and only that session's
+    // account is credited with the result.
+    pub async fn create_round(
         &self,
-        blacklist: I,
-        level: &'static Level,
-    ) -> rusqlite::Result<i64> {
-        self.with_db(move |db| {
-            let query = format!(
-                "SELECT COUNT(*) FROM listings WHERE {} AND id NOT IN rarray(?1)",
-                level.listing_query()
-            );
-            db.query_row(&query, (&values_to_rarray(blacklist.clone()),), |row| {
-                row.get(0)
-            })
+        session_id: Option<i64>,
+        listing_id: i64,
+        level_id: String,
+        rule_id: String,
+        price: i64,
+    ) -> rusqlite::Result<String> {
+        self.with_writer(move |db| {
+            let tx = db.transaction()?;
+            if let Some(session_id) = session_id {
+                forfeit_rounds(&tx, "rounds.session_id = ?1", (session_id,))?;
+            }
+            let token = random_token();
+            tx.execute(
+                "
+                    INSERT INTO rounds (token, created, session_id, listing_id, level, rule, price)
+                    VALUES (?1, unixepoch(), ?2, ?3, ?4, ?5, ?6)
+                ",
+                rusqlite::params![&token, session_id, listing_id, &level_id, &rule_id, price],
+            )?;
+            tx.commit()?;
+            Ok(token)
         })
         .await
     }
 
-    pub async fn sample_listing<I: 'static + Send + Sync + Clone + IntoIterator<Item = i64>>(
+    // Mark a round as revealed, recording the submitted guesses, and return
+    // the price of the round's listing and the round's scoring rule.
+    //
+    // Each round may only be revealed once.
+    pub async fn reveal_round(
         &self,
-        blacklist: I,
-        level: &'static Level,
-    ) -> rusqlite::Result<Option<(Listing, i64)>> {
-        self.with_db(move |db| {
+        token: String,
+        guesses: String,
+    ) -> rusqlite::Result<RoundReveal> {
+        self.with_writer(move |db| {
             let tx = db.transaction()?;
-            let query = format!(
+            let result = tx.query_row(
                 "
-                    SELECT * FROM listings
-                    WHERE {} AND id NOT IN rarray(?1)
-                    ORDER BY -round(last_seen / (60*60*24)), RANDOM()
-                    LIMIT 1
+                    SELECT session_id, listing_id, price, level, rule, revealed
+                    FROM rounds WHERE token=?1
                 ",
-                level.listing_query(),
+                (&token,),
+                |row| {
+                    Ok((
+                        RoundReveal::Revealed {
+                            session_id: row.get(0)?,
+                            listing_id: row.get(1)?,
+                            price: row.get(2)?,
+                            level: row.get(3)?,
+                            rule: row.get(4)?,
+                        },
+                        row.get::<_, Option<i64>>(5)?,
+                    ))
+                },
             );
-            let result = tx.query_row(&query, (&values_to_rarray(blacklist.clone()),), |row| {
-                Ok((
-                    Listing {
-                        website: row.get("website")?,
-                        website_id: row.get("website_id")?,
-                        price: row.get("price")?,
-                        title: row.get("title")?,
-                        image_data: Vec::default(),
-                        categories: Vec::default(),
-                        star_rating: row.get("star_rating")?,
-                        max_stars: row.get("max_stars")?,
-                        num_reviews: row.get("num_reviews")?,
-                    },
-                    row.get::<_, i64>("id")?,
-                    row.get::<_, i64>("image_blob")?,
-                ))
-            });
-            match result {
-                Ok((mut listing, listing_id, image_id)) => {
-                    let categories: rusqlite::Result<Vec<String>> = tx
-                        .prepare("SELECT category FROM categories WHERE listing_id=?1")?
-                        .query_map((&listing_id,), |row| row.get("category"))?
-                        .into_iter()
-                        .collect();
-                    listing.categories = categories?;
-                    listing.image_data =
-                        tx.query_row("SELECT data FROM blobs WHERE id=?1", (&image_id,), |row| {
-                            row.get("data")
-                        })?;
-                    Ok(Some((listing, listing_id)))
+            let reveal = match result {
+                Ok((_, Some(_))) => RoundReveal::AlreadyRevealed,
+                Ok((reveal, None)) => {
+                    tx.execute(
+                        "UPDATE rounds SET revealed=unixepoch(), guesses=?1 WHERE token=?2",
+                        (&guesses, &token),
+                    )?;
+                    reveal
                 }
-                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-                Err(e) => Err(e),
-            }
+                Err(rusqlite::Error::QueryReturnedNoRows) => RoundReveal::NotFound,
+                Err(e) => return Err(e),
+            };
+            tx.commit()?;
+            Ok(reveal)
         })
         .await
     }
 
-    async fn with_db<
-        T: 'static + Send,
-        F: 'static + Send + FnMut(&mut Connection) -> rusqlite::Result<T>,
-    >(
+    // Create a new session, returning its ID and the token which identifies
+    // it to clients.
+    pub async fn create_session(&self) -> rusqlite::Result<(i64, String)> {
+        self.with_writer(|db| {
+            let token = random_token();
+            db.execute(
+                "INSERT INTO sessions (token, created, last_active) VALUES (?1, unixepoch(), unixepoch())",
+                (&token,),
+            )?;
+            Ok((db.last_insert_rowid(), token))
+        })
+        .await
+    }
+
+    // Look up the ID of the session with the given token and the account it is
+    // signed in to, marking the session as active.
+    pub async fn find_session(
         &self,
-        mut f: F,
-    ) -> rusqlite::Result<T> {
-        let db_ref = self.db.clone();
-        spawn_blocking_rusqlite(move || {
-            let mut db = db_ref.blocking_lock();
-            loop {
-                let res = f(&mut db);
-                match res {
-                    Ok(x) => return Ok(x),
-                    Err(e) => {
-                        let code = e.sqlite_error_code();
-                        if code == Some(ErrorCode::DatabaseLocked)
-                            || code == Some(ErrorCode::DatabaseBusy)
-                        {
-                            std::thread::sleep(Duration::from_millis(10));
-                        } else {
-                            return Err(e);
-                        }
-                    }
+        token: String,
+    ) -> rusqlite::Result<Option<(i64, Option<i64>)>> {
+        let session = self
+            .with_reader(move |db| {
+                match db.query_row(
+                    "
+                        SELECT id, account_id, last_active < unixepoch() - ?2
+                        FROM sessions WHERE token=?1
+                    ",
+                    (&token, SESSION_TOUCH_INTERVAL),
+                    |row| Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get::<_, bool>(2)?)),
+                ) {
+                    Ok(x) => Ok(Some(x)),
+                    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                    Err(e) => Err(e),
                 }
+            })
+            .await?;
+        match session {
+            Some((id, account_id, true)) => {
+                self.with_writer(move |db| {
+                    db.execute(
+                        "UPDATE sessions SET last_active=unixepoch() WHERE id=?1",
+                        (id,),
+                    )
+                })
+                .await?;
+                Ok(Some((id, account_id)))
             }
+            Some((id, account_id, false)) => Ok(Some((id, account_id))),
+            None => Ok(None),
+        }
+    }
+
+    // Sign a session in to an account, or out of its account if account_id
+    // is None. The session is given a new token, which is returned, so that
+    // a token seen before signing in cannot be used to act as the account.
+    pub async fn set_session_account(
+        &self,
+        session_id: i64,
+        account_id: Option<i64>,
+    ) -> rusqlite::Result<String> {
+        self.with_writer(move |db| {
+            let token = random_token();
+            db.execute(
+                "UPDATE sessions SET token=?1, account_id=?2, last_active=unixepoch() WHERE id=?3",
+                (&token, account_id, session_id),
+            )?;
+            Ok(token)
         })
         .await
     }
-}
 
-pub struct DeleteCounts {
-    pub listings: usize,
-    pub blobs: usize,
-    pub categories: usize,
-}
+    // Create an account, returning its ID, or None if the username is taken.
+    pub async fn create_account(
+        &self,
+        username: String,
+        password_hash: Option<String>,
+    ) -> rusqlite::Result<Option<i64>> {
+        self.with_writer(move |db| {
+            let count = db.execute(
+                "
+                    INSERT INTO accounts (username, password_hash, created)
+                    VALUES (?1, ?2, unixepoch())
+                    ON CONFLICT DO NOTHING
+                ",
+                (&username, &password_hash),
+            )?;
+            Ok(if count == 0 {
+                None
+            } else {
+                Some(db.last_insert_rowid())
+            })
+        })
+        .await
+    }
+
+    // Find an account by its username, ignoring case.
+    pub async fn find_account(&self, username: String) -> rusqlite::Result<Option<Account>> {
+        self.with_reader(move |db| {
+            match db.query_row(
+                "SELECT id, username, password_hash FROM accounts WHERE username=?1",
+                (&username,),
+                |row| {
+                    Ok(Account {
+                        id: row.get(0)?,
+                        username: row.get(1)?,
+                        password_hash: row.get(2)?,
+                    })
+                },
+            ) {
+                Ok(x) => Ok(Some(x)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+        .await
+    }
+
+    // Store a code which can be used once to sign in to an account, replacing
+    // any previous code for the account.
+    pub async fn create_login_code(
+        &self,
+        account_id: i64,
+        code: String,
+        lifetime: i64,
+    ) -> rusqlite::Result<()> {
+        self.with_writer(move |db| {
+            db.execute(
+                "
+                    INSERT OR REPLACE INTO login_codes (account_id, code, expires, attempts)
+                    VALUES (?1, ?2, unixepoch() + ?3, 0)
+                ",
+                (account_id, &code, lifetime),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    // Check a login code for an account, consuming it if it is correct.
+    //
+    // Codes are also discarded once they expire or have been guessed wrong
+    // MAX_LOGIN_CODE_ATTEMPTS times.
+    pub async fn use_login_code(&self, account_id: i64, code: String) -> rusqlite::Result<bool> {
+        self.with_writer(move |db| {
+            let tx = db.transaction()?;
+            let result: rusqlite::Result<(String, bool, i64)> = tx.query_row(
+                "SELECT code, expires < unixepoch(), attempts FROM login_codes WHERE account_id=?1",
+                (account_id,),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            );
+            let valid = match result {
+                Ok((expected, expired, attempts)) => {
+                    let valid = !expired && expected == code;
+                    if valid || expired || attempts + 1 >= MAX_LOGIN_CODE_ATTEMPTS {
+                        tx.execute("DELETE FROM login_codes WHERE account_id=?1", (account_id,))?;
+                    } else {
+                        tx.execute(
+                            "UPDATE login_codes SET attempts=attempts+1 WHERE account_id=?1",
+                            (account_id,),
+                        )?;
+                    }
+                    valid
+                }
+                Err(rusqlite::Error::QueryReturnedNoRows) => false,
+                Err(e) => return Err(e),
+            };
+            tx.commit()?;
+            Ok(valid)
+        })
+        .await
+    }
+
+    // Add the result of a revealed round to an account's stats, where error
+    // is the percent error of the account's guess.
+    pub async fn record_round_result(
+        &self,
+        account_id: i64,
+        level: String,
+        error: f64,
+        won: bool,
+    ) -> rusqlite::Result<()> {
+        self.with_writer(move |db| {
+            let tx = db.transaction()?;
+            record_result(&tx, account_id, &level, error, won)?;
+            tx.commit()
+        })
+        .await
+    }
+
+    // Get the lifetime stats of an account, or None if it does not exist.
+    pub async fn account_stats(&self, account_id: i64) -> rusqlite::Result<Option<AccountStats>> {
+        self.with_reader(move |db| {
+            let result = db.query_row(
+                "SELECT username, current_streak, best_streak FROM accounts WHERE id=?1",
+                (account_id,),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            );
+            let (username, current_streak, best_streak) = match result {
+                Ok(x) => x,
+                Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let mut stmt = db.prepare_cached(
+                "
+                    SELECT level, rounds, wins, total_error FROM account_stats
+                    WHERE account_id=?1 ORDER BY level
+                ",
+            )?;
+            let levels = stmt
+                .query_map((account_id,), |row| {
+                    Ok(LevelStats {
+                        level: row.get(0)?,
+                        rounds: row.get(1)?,
+                        wins: row.get(2)?,
+                        total_error: row.get(3)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(Some(AccountStats {
+                username,
+                current_streak,
+                best_streak,
+                levels,
+            }))
+        })
+        .await
+    }
+
+    // Record that a session has been shown a listing.
+    pub async fn mark_seen(&self, session_id: i64, listing_id: i64) -> rusqlite::Result<()> {
+        self.with_writer(move |db| {
+            db.execute(
+                "
+                    INSERT OR REPLACE INTO seen_listings (session_id, listing_id, seen_at)
+                    VALUES (?1, ?2, unixepoch())
+                ",
+                (session_id, listing_id),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    // Get the IDs of the listings which a session has seen, optionally only
+    // counting those seen in the last max_age seconds.
+    pub async fn seen_listings(
+        &self,
+        session_id: i64,
+        max_age: Option<i64>,
+    ) -> rusqlite::Result<Vec<i64>> {
+        self.with_reader(move |db| {
+            let mut stmt = db.prepare_cached(
+                "
+                    SELECT listing_id FROM seen_listings
+                    WHERE session_id=?1 AND (?2 IS NULL OR seen_at >= unixepoch() - ?2)
+                ",
+            )?;
+            let rows = stmt.query_map((session_id, max_age), |row| row.get(0))?;
+            rows.collect()
+        })
+        .await
+    }
+
+    // Forget every listing a session has seen, returning how many there were.
+    pub async fn reset_seen(&self, session_id: i64) -> rusqlite::Result<usize> {
+        self.with_writer(move |db| {
+            db.execute(
+                "DELETE FROM seen_listings WHERE session_id=?1",
+                (session_id,),
+            )
+        })
+        .await
+    }
+
+    async fn with_writer<
+        T: 'static + Send,
+        F: 'static + Send + FnOnce(&mut Connection) -> rusqlite::Result<T>,
+    >(
+        &self,
+        f: F,
+    ) -> rusqlite::Result<T> {
+        let writer = self.writer.clone();
+        spawn_blocking_rusqlite(move || match writer.blocking_lock().as_mut() {
+            Some(conn) => f(conn),
+            None => Err(closed_error()),
+        })
+        .await
+    }
+
+    async fn with_reader<
+        T: 'static + Send,
+        F: 'static + Send + FnOnce(&mut Connection) -> rusqlite::Result<T>,
+    >(
+        &self,
+        f: F,
+    ) -> rusqlite::Result<T> {
+        if self.readers.is_empty() {
+            return self.with_writer(f).await;
+        }
+        let mut conn = self.readers.get().await?;
+        spawn_blocking_rusqlite(move || f(&mut conn)).await
+    }
+}
+
+// A fixed set of connections which are handed out one query at a time.
+struct ReadPool {
+    conns: std::sync::Mutex<Vec<Connection>>,
+    size: usize,
+
+    // There is a permit for each idle connection. The semaphore is closed
+    // along with the pool.
+    permits: Arc<Semaphore>,
+}
+
+impl ReadPool {
+    fn new(conns: Vec<Connection>) -> ReadPool {
+        ReadPool {
+            size: conns.len(),
+            permits: Arc::new(Semaphore::new(conns.len())),
+            conns: std::sync::Mutex::new(conns),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    async fn get(self: &Arc<Self>) -> rusqlite::Result<PooledConnection> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| closed_error())?;
+        let conn = self
+            .conns
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("a permit should guarantee an idle connection");
+        Ok(PooledConnection {
+            conn: Some(conn),
+            pool: self.clone(),
+            _permit: permit,
+        })
+    }
+
+    // Wait for every connection to be returned, and take them out of the
+    // pool for good.
+    async fn close(&self) -> Vec<Connection> {
+        if let Ok(permits) = self.permits.acquire_many(self.size as u32).await {
+            permits.forget();
+            self.permits.close();
+        }
+        std::mem::take(&mut *self.conns.lock().unwrap())
+    }
+}
+
+// A connection which goes back to its pool when dropped, even if the query
+// using it panics.
+struct PooledConnection {
+    conn: Option<Connection>,
+    pool: Arc<ReadPool>,
+
+    // Released after the connection is back in the pool.
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.conns.lock().unwrap().push(conn);
+        }
+    }
+}
+
+fn closed_error() -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(
+        rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_MISUSE),
+        Some("database is closed".to_owned()),
+    )
+}
+
+// A filter on log messages. Empty or missing criteria match every message.
+pub struct LogQuery {
+    pub levels: Vec<LogLevel>,
+
+    // A prefix of the source location, such as "src/sources.rs".
+    pub source: Option<String>,
+
+    // Bounds on the UNIX timestamp.
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+
+    // Only return messages older than this ID, for pagination.
+    pub before_id: Option<i64>,
+
+    pub limit: i64,
+}
+
+pub struct LogMessage {
+    pub id: i64,
+    pub timestamp: i64,
+    pub level: LogLevel,
+    pub source: String,
+    pub message: String,
+}
+
+pub enum ListingChange {
+    Inserted,
+    Updated,
+}
+
+pub struct SourceRun {
+    pub source_id: String,
+    pub started: i64,
+    pub duration_ms: i64,
+
+    // Set if the run failed or timed out.
+    pub error: Option<String>,
+
+    pub listings_added: i64,
+    pub listings_updated: i64,
+}
+
+pub struct SourceHealth {
+    pub last_run: SourceRun,
+
+    // The start time of the latest successful run.
+    pub last_success: Option<i64>,
+
+    // The start time and error of the latest failed run.
+    pub last_error: Option<(i64, String)>,
+}
+
+pub struct StorageStats {
+    pub size_bytes: i64,
+    pub blobs: i64,
+}
+
+pub struct DeleteCounts {
+    pub listings: usize,
+    pub blobs: usize,
+    pub categories: usize,
+    pub rounds: usize,
+}
+
+// A listing chosen for a round. The listing's image_data is left empty, and
+// the image should instead be fetched by its hash.
+pub struct SampledListing {
+    pub id: i64,
+    pub listing: Listing,
+    pub image_hash: String,
+}
+
+pub struct PricePoint {
+    pub price: i64,
+    pub observed_at: i64,
+}
+
+pub enum RoundReveal {
+    NotFound,
+    AlreadyRevealed,
+    Revealed {
+        session_id: Option<i64>,
+        listing_id: i64,
+        price: i64,
+        level: String,
+        rule: String,
+    },
+}
+
+pub struct Account {
+    pub id: i64,
+    pub username: String,
+
+    // Accounts without a password can only sign in with a login code.
+    pub password_hash: Option<String>,
+}
+
+pub struct AccountStats {
+    pub username: String,
+    pub current_streak: i64,
+    pub best_streak: i64,
+    pub levels: Vec<LevelStats>,
+}
+
+pub struct LevelStats {
+    pub level: String,
+    pub rounds: i64,
+    pub wins: i64,
+
+    // The sum of the percent errors of every round's guess.
+    pub total_error: f64,
+}
 
 async fn spawn_blocking_rusqlite<
     T: 'static + Send,
-    F: 'static + Send + FnMut() -> rusqlite::Result<T>,
+    F: 'static + Send + FnOnce() -> rusqlite::Result<T>,
 >(
     f: F,
 ) -> rusqlite::Result<T> {
@@ -377,76 +1487,35 @@ async fn spawn_blocking_rusqlite<
     }
 }
 
-fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
-    conn.execute(
-        "CREATE TABLE if not exists listings (
-            id           INTEGER PRIMARY KEY AUTOINCREMENT,
-            created      INTEGER NOT NULL,
-            last_seen    INTEGER NOT NULL,
-            website      CHAR(32) NOT NULL,
-            website_id   CHAR(32) NOT NULL,
-            price        INTEGER NOT NULL,
-            title        CHAR(128) NOT NULL,
-            image_blob   INTEGER NOT NULL,
-            star_rating  REAL,
-            max_stars    REAL,
-            num_reviews  INTEGER,
-            sweep_mark   INT,
-            UNIQUE (website, website_id)
-        )",
-        (),
-    )?;
-    conn.execute(
-        "CREATE TABLE if not exists blobs (
-            id           INTEGER PRIMARY KEY AUTOINCREMENT,
-            hash         CHAR(32),
-            data         BLOB,
-            UNIQUE (hash)
-        )",
-        (),
-    )?;
-    conn.execute(
-        "CREATE TABLE if not exists categories (
-            listing_id   INTEGER,
-            category     CHAR(64),
-            PRIMARY KEY (listing_id, category)
-        )",
-        (),
-    )?;
-    conn.execute(
-        "CREATE TABLE if not exists source_status (
-            source_id    CHAR(64),
-            last_updated INTEGER,
-            PRIMARY KEY (source_id)
-        )",
-        (),
-    )?;
-    conn.execute(
-        "CREATE TABLE if not exists log (
-            id         INTEGER PRIMARY KEY,
-            timestamp  INTEGER,
-            source     TEXT,
-            message    TEXT
-        )",
-        (),
-    )?;
-    conn.execute(
-        "CREATE INDEX if not exists listings_website_id ON listings(website, website_id)",
-        (),
-    )?;
-    conn.execute(
-        "CREATE INDEX if not exists listings_image_blob ON listings(image_blob)",
-        (),
-    )?;
-    conn.execute(
-        "CREATE INDEX if not exists listings_last_seen ON listings(last_seen)",
-        (),
-    )?;
-    conn.execute("CREATE INDEX if not exists blobs_hash ON blobs(hash)", ())?;
-    conn.execute(
-        "CREATE INDEX if not exists log_timestamp ON log(timestamp)",
-        (),
-    )?;
+// Update the levels which contain a listing after it has been inserted or
+// changed, using the filters stored by sync_levels.
+fn index_listing(tx: &Transaction, id: i64) -> rusqlite::Result<()> {
+    tx.execute("DELETE FROM level_listings WHERE listing_id=?1", (id,))?;
+    let levels = tx
+        .prepare("SELECT id, filter FROM levels")?
+        .query_map((), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (level_id, filter) in levels {
+        let filter: LevelFilter = serde_json::from_str(&filter).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(e))
+        })?;
+        let (condition, params) = filter.listing_query(3);
+        let mut all_params: Vec<&dyn ToSql> = vec![&level_id, &id];
+        all_params.extend(params.iter().map(|x| x as &dyn ToSql));
+        tx.execute(
+            &format!(
+                "
+                    INSERT INTO level_listings (level_id, listing_id, day)
+                    SELECT ?1, id, last_seen / (60*60*24) FROM listings
+                    WHERE id=?2 AND {}
+                ",
+                condition
+            ),
+            params_from_iter(all_params),
+        )?;
+    }
     Ok(())
 }
 
@@ -487,6 +1556,106 @@ fn insert_categories(tx: &mut Transaction, id: i64, categories: &[String]) -> ru
     Ok(())
 }
 
+// Record a listing's price if it differs from the last recorded price, or if
+// no price has been recorded yet.
+fn record_price(tx: &mut Transaction, id: i64, price: i64) -> rusqlite::Result<()> {
+    let last_price: Option<i64> = match tx.query_row(
+        "
+            SELECT price FROM price_history WHERE listing_id=?1
+            ORDER BY observed_at DESC, id DESC
+            LIMIT 1
+        ",
+        (id,),
+        |row| row.get(0),
+    ) {
+        Ok(x) => Some(x),
+        Err(rusqlite::Error::QueryReturnedNoRows) => None,
+        Err(e) => return Err(e),
+    };
+    if last_price != Some(price) {
+        tx.execute(
+            "INSERT INTO price_history (listing_id, price, observed_at) VALUES (?1, ?2, unixepoch())",
+            (id, price),
+        )?;
+    }
+    Ok(())
+}
+
+fn record_result(
+    tx: &Transaction,
+    account_id: i64,
+    level: &str,
+    error: f64,
+    won: bool,
+) -> rusqlite::Result<()> {
+    tx.execute(
+        "
+            INSERT INTO account_stats (account_id, level, rounds, wins, total_error)
+            VALUES (?1, ?2, 1, ?3, ?4)
+            ON CONFLICT DO UPDATE SET
+                rounds = rounds + 1,
+                wins = wins + excluded.wins,
+                total_error = total_error + excluded.total_error
+        ",
+        (account_id, level, won as i64, error),
+    )?;
+    // Every assignment sees the old current_streak.
+    tx.execute(
+        "
+            UPDATE accounts SET
+                current_streak = IIF(?2, current_streak + 1, 0),
+                best_streak = MAX(best_streak, IIF(?2, current_streak + 1, 0))
+            WHERE id=?1
+        ",
+        (account_id, won),
+    )?;
+    Ok(())
+}
+
+// Close the unrevealed rounds which match a condition, counting each one as
+// a loss for the account its session is signed in to, so that a round can't
+// be dropped to protect a streak.
+fn forfeit_rounds<P: rusqlite::Params + Copy>(
+    tx: &Transaction,
+    condition: &str,
+    params: P,
+) -> rusqlite::Result<()> {
+    let forfeits = tx
+        .prepare(&format!(
+            "
+                SELECT sessions.account_id, rounds.level FROM rounds
+                JOIN sessions ON sessions.id = rounds.session_id
+                WHERE rounds.revealed IS NULL AND sessions.account_id IS NOT NULL AND {}
+            ",
+            condition
+        ))?
+        .query_map(params, |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (account_id, level) in forfeits {
+        record_result(tx, account_id, &level, FORFEIT_ERROR, false)?;
+    }
+    tx.execute(
+        &format!(
+            "UPDATE rounds SET revealed=unixepoch() WHERE revealed IS NULL AND {}",
+            condition
+        ),
+        params,
+    )?;
+    Ok(())
+}
+
+// Combine a leading parameter with the parameters of a level query.
+fn prepend_param<'a>(
+    first: &'a dyn ToSql,
+    rest: &'a [rusqlite::types::Value],
+) -> Vec<&'a dyn ToSql> {
+    let mut result = vec![first];
+    result.extend(rest.iter().map(|x| x as &dyn ToSql));
+    result
+}
+
 fn values_to_rarray<T, I: 'static + Send + Sync + IntoIterator<Item = T>>(
     blacklist: I,
 ) -> Rc<Vec<rusqlite::types::Value>>
@@ -501,6 +1670,14 @@ where
     )
 }
 
+fn random_token() -> String {
+    let mut res = String::with_capacity(32);
+    for ch in rand::thread_rng().gen::<[u8; 16]>() {
+        write!(&mut res, "{:02x}", ch).unwrap();
+    }
+    res
+}
+
 fn hash_blob(data: &[u8]) -> String {
     let mut hasher = sha2::Sha256::new();
     hasher.update(data);
@@ -525,3 +1702,375 @@ pub struct Listing {
     pub max_stars: Option<f64>,
     pub num_reviews: Option<i64>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_listing(website_id: &str, price: i64) -> Listing {
+        Listing {
+            website: "test".to_owned(),
+            website_id: website_id.to_owned(),
+            price,
+            title: format!("Listing {}", website_id),
+            image_data: website_id.as_bytes().to_vec(),
+            categories: Vec::new(),
+            star_rating: None,
+            max_stars: None,
+            num_reviews: None,
+        }
+    }
+
+    fn test_level(id: &str, filter: LevelFilter) -> Level {
+        Level {
+            id: id.to_owned(),
+            website: "test".to_owned(),
+            name: id.to_owned(),
+            icon: "icon.svg".to_owned(),
+            filter,
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_use_the_pool() {
+        let path = std::env::temp_dir().join(format!("db-test-{}.db", rand::random::<u64>()));
+        let db = Database::open(&path).await.unwrap();
+        db.sync_levels(vec![test_level("all", Default::default())])
+            .await
+            .unwrap();
+        db.insert_or_update(test_listing("a", 100)).await.unwrap();
+
+        // More reads than there are connections wait their turn, and every
+        // one of them sees the committed write.
+        let reads = (0..READ_CONNECTIONS * 4).map(|_| {
+            let db = db.clone();
+            tokio::spawn(async move { db.sample_listing(Vec::new(), "all".to_owned()).await })
+        });
+        for read in reads {
+            let sample = read.await.unwrap().unwrap().unwrap();
+            assert_eq!(sample.listing.website_id, "a");
+        }
+
+        // Reader connections can't write.
+        let result = db
+            .with_reader(|db| db.execute("DELETE FROM listings", ()))
+            .await;
+        assert!(result.is_err());
+
+        db.close().await.unwrap();
+        assert!(db
+            .sample_listing(Vec::new(), "all".to_owned())
+            .await
+            .is_err());
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            std::fs::remove_file(file).ok();
+        }
+    }
+
+    async fn set_day(db: &Database, listing_id: i64, day: i64) {
+        db.with_writer(move |db| {
+            db.execute(
+                "UPDATE level_listings SET day=?1 WHERE listing_id=?2",
+                (day, listing_id),
+            )
+        })
+        .await
+        .unwrap();
+    }
+
+    async fn sample_id(db: &Database, blacklist: Vec<i64>) -> Option<i64> {
+        db.sample_listing(blacklist, "all".to_owned())
+            .await
+            .unwrap()
+            .map(|x| x.id)
+    }
+
+    #[tokio::test]
+    async fn sample_newest_day_first() {
+        let db = Database::open_in_memory().await.unwrap();
+        db.sync_levels(vec![test_level("all", Default::default())])
+            .await
+            .unwrap();
+        for id in ["a", "b", "c"] {
+            db.insert_or_update(test_listing(id, 100)).await.unwrap();
+        }
+        set_day(&db, 1, 100).await;
+        set_day(&db, 2, 200).await;
+        set_day(&db, 3, 200).await;
+
+        for _ in 0..20 {
+            assert!([2, 3].contains(&sample_id(&db, vec![]).await.unwrap()));
+        }
+        assert_eq!(sample_id(&db, vec![2]).await, Some(3));
+        assert_eq!(sample_id(&db, vec![3, 2]).await, Some(1));
+        assert_eq!(sample_id(&db, vec![1, 2, 3]).await, None);
+        assert!(db
+            .sample_listing(Vec::new(), "missing".to_owned())
+            .await
+            .unwrap()
+            .is_none());
+
+        let sample = db
+            .sample_listing(vec![2, 3], "all".to_owned())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sample.listing.title, "Listing a");
+        assert_eq!(sample.image_hash, hash_blob(b"a"));
+    }
+
+    #[tokio::test]
+    async fn sync_levels_reindexes_changed_filters() {
+        let db = Database::open_in_memory().await.unwrap();
+        let cheap = LevelFilter {
+            max_price: Some(1000),
+            ..Default::default()
+        };
+        db.sync_levels(vec![test_level("all", cheap)])
+            .await
+            .unwrap();
+        db.insert_or_update(test_listing("a", 500)).await.unwrap();
+        db.insert_or_update(test_listing("b", 5000)).await.unwrap();
+        assert_eq!(sample_id(&db, vec![1]).await, None);
+
+        let expensive = LevelFilter {
+            min_price: Some(1000),
+            ..Default::default()
+        };
+        db.sync_levels(vec![test_level("all", expensive.clone())])
+            .await
+            .unwrap();
+        assert_eq!(sample_id(&db, vec![]).await, Some(2));
+
+        // Levels which are no longer configured are removed from the index.
+        db.sync_levels(vec![test_level("other", expensive)])
+            .await
+            .unwrap();
+        assert_eq!(sample_id(&db, vec![]).await, None);
+        assert_eq!(
+            db.level_counts(Vec::new()).await.unwrap(),
+            HashMap::from([("other".to_owned(), 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn level_counts_are_cached_until_written() {
+        let db = Database::open_in_memory().await.unwrap();
+        db.sync_levels(vec![test_level("all", Default::default())])
+            .await
+            .unwrap();
+        db.insert_or_update(test_listing("a", 100)).await.unwrap();
+        db.insert_or_update(test_listing("b", 200)).await.unwrap();
+        let counts = |x: i64| HashMap::from([("all".to_owned(), x)]);
+        assert_eq!(db.level_counts(Vec::new()).await.unwrap(), counts(2));
+        assert_eq!(db.level_counts(vec![1]).await.unwrap(), counts(1));
+
+        // Writes which bypass the Database don't clear the cache...
+        db.with_writer(|db| db.execute("DELETE FROM level_listings WHERE listing_id=1", ()))
+            .await
+            .unwrap();
+        assert_eq!(db.level_counts(Vec::new()).await.unwrap(), counts(2));
+
+        // ...but storing a listing does.
+        db.insert_or_update(test_listing("c", 300)).await.unwrap();
+        assert_eq!(db.level_counts(Vec::new()).await.unwrap(), counts(2));
+        assert_eq!(db.level_counts(vec![2, 3]).await.unwrap(), counts(0));
+    }
+
+    #[tokio::test]
+    async fn seen_listings_expire() {
+        let db = Database::open_in_memory().await.unwrap();
+        let (session, _) = db.create_session().await.unwrap();
+        let (other_session, _) = db.create_session().await.unwrap();
+        for listing_id in [1, 2] {
+            db.mark_seen(session, listing_id).await.unwrap();
+        }
+        db.mark_seen(other_session, 3).await.unwrap();
+        db.with_writer(|db| {
+            db.execute(
+                "UPDATE seen_listings SET seen_at=unixepoch()-1000 WHERE listing_id=1",
+                (),
+            )
+        })
+        .await
+        .unwrap();
+
+        let seen = |max_age| {
+            let db = db.clone();
+            async move {
+                let mut ids = db.seen_listings(session, max_age).await.unwrap();
+                ids.sort();
+                ids
+            }
+        };
+        assert_eq!(seen(None).await, vec![1, 2]);
+        assert_eq!(seen(Some(500)).await, vec![2]);
+        assert_eq!(seen(Some(5000)).await, vec![1, 2]);
+
+        // Seeing a listing again makes it fresh.
+        db.mark_seen(session, 1).await.unwrap();
+        assert_eq!(seen(Some(500)).await, vec![1, 2]);
+
+        assert_eq!(db.reset_seen(session).await.unwrap(), 2);
+        assert_eq!(seen(None).await, Vec::<i64>::new());
+        assert_eq!(
+            db.seen_listings(other_session, None).await.unwrap(),
+            vec![3]
+        );
+    }
+
+    #[tokio::test]
+    async fn rounds_are_revealed_once_with_their_session() {
+        let db = Database::open_in_memory().await.unwrap();
+        let (session, _) = db.create_session().await.unwrap();
+        let token = db
+            .create_round(
+                Some(session),
+                7,
+                "all".to_owned(),
+                "closest".to_owned(),
+                1999,
+            )
+            .await
+            .unwrap();
+        match db
+            .reveal_round(token.clone(), "[1.0]".to_owned())
+            .await
+            .unwrap()
+        {
+            RoundReveal::Revealed {
+                session_id, price, ..
+            } => assert_eq!((session_id, price), (Some(session), 1999)),
+            _ => panic!("expected the round to be revealed"),
+        }
+        assert!(matches!(
+            db.reveal_round(token, "[1.0]".to_owned()).await.unwrap(),
+            RoundReveal::AlreadyRevealed
+        ));
+        assert!(matches!(
+            db.reveal_round(random_token(), "[1.0]".to_owned())
+                .await
+                .unwrap(),
+            RoundReveal::NotFound
+        ));
+    }
+
+    #[tokio::test]
+    async fn unrevealed_rounds_are_losses() {
+        let db = Database::open_in_memory().await.unwrap();
+        let account = db
+            .create_account("alice".to_owned(), None)
+            .await
+            .unwrap()
+            .unwrap();
+        let (session, _) = db.create_session().await.unwrap();
+        db.set_session_account(session, Some(account))
+            .await
+            .unwrap();
+        let new_round = || {
+            db.create_round(
+                Some(session),
+                7,
+                "all".to_owned(),
+                "closest".to_owned(),
+                1999,
+            )
+        };
+        let stats = || async {
+            let stats = db.account_stats(account).await.unwrap().unwrap();
+            let level = &stats.levels[0];
+            (
+                stats.current_streak,
+                stats.best_streak,
+                level.rounds,
+                level.wins,
+            )
+        };
+
+        let first = new_round().await.unwrap();
+        db.record_round_result(account, "all".to_owned(), 1.0, true)
+            .await
+            .unwrap();
+        assert_eq!(stats().await, (1, 1, 1, 1));
+
+        // Sampling another round gives up on the first one.
+        new_round().await.unwrap();
+        assert_eq!(stats().await, (0, 1, 2, 1));
+        assert!(matches!(
+            db.reveal_round(first, "[19.99]".to_owned()).await.unwrap(),
+            RoundReveal::AlreadyRevealed
+        ));
+
+        // So does leaving a round until it is deleted.
+        db.record_round_result(account, "all".to_owned(), 1.0, true)
+            .await
+            .unwrap();
+        db.with_writer(|db| {
+            db.execute(
+                "UPDATE rounds SET created = created - ?1",
+                (ROUND_LIFETIME + 1,),
+            )
+        })
+        .await
+        .unwrap();
+        db.delete_old_listings(10, Vec::new()).await.unwrap();
+        assert_eq!(stats().await, (0, 1, 4, 2));
+
+        // Rounds which were revealed, or not sampled for a session, are left
+        // alone.
+        let (other_session, _) = db.create_session().await.unwrap();
+        let revealed = new_round().await.unwrap();
+        db.reveal_round(revealed, "[19.99]".to_owned())
+            .await
+            .unwrap();
+        db.create_round(None, 7, "all".to_owned(), "closest".to_owned(), 1999)
+            .await
+            .unwrap();
+        db.create_round(
+            Some(other_session),
+            7,
+            "all".to_owned(),
+            "closest".to_owned(),
+            1999,
+        )
+        .await
+        .unwrap();
+        new_round().await.unwrap();
+        assert_eq!(stats().await, (0, 1, 4, 2));
+    }
+
+    #[tokio::test]
+    async fn login_code_attempts_are_limited() {
+        let db = Database::open_in_memory().await.unwrap();
+        let account = db
+            .create_account("alice".to_owned(), None)
+            .await
+            .unwrap()
+            .unwrap();
+        let code = |x: &str| db.use_login_code(account, x.to_owned());
+
+        db.create_login_code(account, "123456".to_owned(), 900)
+            .await
+            .unwrap();
+        for _ in 0..MAX_LOGIN_CODE_ATTEMPTS - 1 {
+            assert!(!code("000000").await.unwrap());
+        }
+        assert!(code("123456").await.unwrap());
+        assert!(!code("123456").await.unwrap(), "codes are single use");
+
+        db.create_login_code(account, "123456".to_owned(), 900)
+            .await
+            .unwrap();
+        for _ in 0..MAX_LOGIN_CODE_ATTEMPTS {
+            assert!(!code("000000").await.unwrap());
+        }
+        assert!(!code("123456").await.unwrap(), "too many wrong guesses");
+
+        db.create_login_code(account, "123456".to_owned(), -1)
+            .await
+            .unwrap();
+        assert!(!code("123456").await.unwrap(), "code has expired");
+    }
+}
@@ -7,28 +7,11 @@ use hyper::{Body, Request, Response};
 use serde::Serialize;
 use tokio::task::spawn_blocking;
 
-use crate::{db::Database, log_async, log_error_async};
+use crate::{db::Database, log_error_async};
 
 const ERROR_PAGE: &str = include_str!("assets/internal_error.html");
 
-pub async fn log_response(
-    db: &Database,
-    req: &Request<Body>,
-    response: &Response<Body>,
-) -> anyhow::Result<()> {
-    let mut req_url = req.uri().to_string();
-    req_url.truncate(128);
-
-    let method = req.method().to_string();
-    log_async!(
-        db,
-        "{} {} => {}",
-        method,
-        req_url,
-        response.status().as_u16()
-    );
-    Ok(())
-}
+pub const IMAGE_PATH_PREFIX: &str = "/images/";
 
 pub async fn read_body(req: &mut Request<Body>, max_size: usize) -> anyhow::Result<Vec<u8>> {
     let mut data = Vec::new();
@@ -46,8 +29,24 @@ pub async fn maybe_compress_response(
     req: &Request<Body>,
     mut resp: Response<Body>,
 ) -> Response<Body> {
+    if resp.status() == StatusCode::SWITCHING_PROTOCOLS || resp.status() == StatusCode::NOT_MODIFIED
+    {
+        return resp;
+    }
     let is_api = resp.headers().get("content-type")
         == Some(&HeaderValue::from_str("application/json").unwrap());
+
+    // Most image formats are already compressed.
+    let is_image = resp
+        .headers()
+        .get("content-type")
+        .map(|x| x.to_str().unwrap_or_default())
+        .map(|x| x.starts_with("image/") && x != "image/svg+xml")
+        .unwrap_or_default();
+    if is_image {
+        return resp;
+    }
+
     let accept_gzip = req
         .headers()
         .get("accept-encoding")
@@ -87,6 +86,10 @@ pub async fn maybe_compress_response(
     }
 }
 
+pub fn image_url(hash: &str) -> String {
+    format!("{}{}", IMAGE_PATH_PREFIX, hash)
+}
+
 pub fn detect_image_mime(data: &[u8]) -> Option<&'static str> {
     if data.starts_with(&[0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a]) {
         Some("image/png")
@@ -136,7 +139,7 @@ fn api_error_response<E: Display>(ctx: &str, err: E) -> Response<Body> {
         .header("content-type", "application/json")
         .body(Body::from(
             serde_json::to_vec(&ErrorResponse {
-                error: format!("{}: {}", ctx, err.to_string()),
+                error: format!("{}: {}", ctx, err),
             })
             .expect("serialize error struct"),
         ))
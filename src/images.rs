@@ -0,0 +1,204 @@
+use std::fmt::Display;
+
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, ColorType, DynamicImage, RgbImage};
+use tokio::task::spawn_blocking;
+
+const JPEG_QUALITY: u8 = 85;
+
+// Images are shrunk to this size before measuring how blank they are.
+const BLANK_CHECK_SIZE: u32 = 32;
+
+// Images whose brightness has a standard deviation below this threshold (out
+// of 255) are considered to be blank placeholders.
+const BLANK_STDDEV_THRESHOLD: f64 = 4.0;
+
+// The reason that an image could not be normalized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ImageRejection {
+    Undecodable,
+    TooSmall,
+    Blank,
+    Unencodable,
+}
+
+impl Display for ImageRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ImageRejection::Undecodable => "undecodable",
+                ImageRejection::TooSmall => "too small",
+                ImageRejection::Blank => "nearly blank",
+                ImageRejection::Unencodable => "unencodable",
+            }
+        )
+    }
+}
+
+impl std::error::Error for ImageRejection {}
+
+// Decodes, validates, and re-encodes listing images before they are stored,
+// so that every stored image is a reasonably sized JPEG.
+#[derive(Clone)]
+pub struct ImageNormalizer {
+    max_size: u32,
+    min_size: u32,
+}
+
+impl ImageNormalizer {
+    pub fn new(max_size: u32, min_size: u32) -> anyhow::Result<ImageNormalizer> {
+        if min_size > max_size {
+            return Err(anyhow::Error::msg(format!(
+                "minimum image size ({}) is larger than maximum image size ({})",
+                min_size, max_size
+            )));
+        }
+        Ok(ImageNormalizer { max_size, min_size })
+    }
+
+    // Produce a normalized JPEG from raw image data, or fail if the image is
+    // corrupt, too small, or nearly blank.
+    pub async fn normalize(&self, data: Vec<u8>) -> Result<Vec<u8>, ImageRejection> {
+        let normalizer = self.clone();
+        // A decoder which panics has been handed an image it can't read.
+        spawn_blocking(move || normalizer.normalize_blocking(&data))
+            .await
+            .unwrap_or(Err(ImageRejection::Undecodable))
+    }
+
+    fn normalize_blocking(&self, data: &[u8]) -> Result<Vec<u8>, ImageRejection> {
+        let img = image::load_from_memory(data).map_err(|_| ImageRejection::Undecodable)?;
+        if img.width() < self.min_size || img.height() < self.min_size {
+            return Err(ImageRejection::TooSmall);
+        }
+        let img = if img.width() > self.max_size || img.height() > self.max_size {
+            img.resize(self.max_size, self.max_size, FilterType::CatmullRom)
+        } else {
+            img
+        };
+        let rgb = flatten_alpha(&img);
+        if is_blank(&rgb) {
+            return Err(ImageRejection::Blank);
+        }
+        let mut result = Vec::new();
+        JpegEncoder::new_with_quality(&mut result, JPEG_QUALITY)
+            .encode(&rgb, rgb.width(), rgb.height(), ColorType::Rgb8)
+            .map_err(|_| ImageRejection::Unencodable)?;
+        Ok(result)
+    }
+}
+
+// Composite an image onto a white background, since product photos with
+// transparency are meant to be viewed on a white page.
+fn flatten_alpha(img: &DynamicImage) -> RgbImage {
+    let rgba = img.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let alpha = a as u32;
+        let blend = |c: u8| ((c as u32 * alpha + 255 * (255 - alpha)) / 255) as u8;
+        image::Rgb([blend(r), blend(g), blend(b)])
+    })
+}
+
+fn is_blank(img: &RgbImage) -> bool {
+    let small = DynamicImage::ImageRgb8(img.clone())
+        .resize_exact(BLANK_CHECK_SIZE, BLANK_CHECK_SIZE, FilterType::Triangle)
+        .to_luma8();
+    let count = small.pixels().len() as f64;
+    let mean = small.pixels().map(|x| x.0[0] as f64).sum::<f64>() / count;
+    let variance = small
+        .pixels()
+        .map(|x| (x.0[0] as f64 - mean).powi(2))
+        .sum::<f64>()
+        / count;
+    variance.sqrt() < BLANK_STDDEV_THRESHOLD
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    // A horizontal gradient, which is nowhere near blank at any size.
+    fn gradient(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, _| {
+            let value = (x * 255 / (width - 1).max(1)) as u8;
+            Rgba([value, value, value, 255])
+        })
+    }
+
+    fn encode_png(img: RgbaImage) -> Vec<u8> {
+        let mut data = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(img)
+            .write_to(&mut data, ImageOutputFormat::Png)
+            .unwrap();
+        data.into_inner()
+    }
+
+    #[tokio::test]
+    async fn normalize_resizes_to_jpeg() {
+        let normalizer = ImageNormalizer::new(64, 16).unwrap();
+        let data = normalizer
+            .normalize(encode_png(gradient(256, 128)))
+            .await
+            .unwrap();
+        let img = image::load_from_memory_with_format(&data, image::ImageFormat::Jpeg).unwrap();
+        assert_eq!((img.width(), img.height()), (64, 32));
+
+        // Images within the limits keep their size.
+        let data = normalizer
+            .normalize(encode_png(gradient(40, 20)))
+            .await
+            .unwrap();
+        let img = image::load_from_memory(&data).unwrap();
+        assert_eq!((img.width(), img.height()), (40, 20));
+    }
+
+    #[tokio::test]
+    async fn normalize_rejections() {
+        let normalizer = ImageNormalizer::new(64, 16).unwrap();
+        assert_eq!(
+            normalizer.normalize(b"not an image".to_vec()).await,
+            Err(ImageRejection::Undecodable)
+        );
+        assert_eq!(
+            normalizer.normalize(encode_png(gradient(64, 15))).await,
+            Err(ImageRejection::TooSmall)
+        );
+        let gray = RgbaImage::from_pixel(32, 32, Rgba([128, 128, 128, 255]));
+        assert_eq!(
+            normalizer.normalize(encode_png(gray)).await,
+            Err(ImageRejection::Blank)
+        );
+
+        // A fully transparent image is blank once placed on a white page.
+        let mut transparent = gradient(32, 32);
+        for pixel in transparent.pixels_mut() {
+            pixel.0[3] = 0;
+        }
+        assert_eq!(
+            normalizer.normalize(encode_png(transparent)).await,
+            Err(ImageRejection::Blank)
+        );
+    }
+
+    #[test]
+    fn flatten_alpha_onto_white() {
+        let mut img = RgbaImage::new(3, 1);
+        img.put_pixel(0, 0, Rgba([0, 0, 0, 0]));
+        img.put_pixel(1, 0, Rgba([0, 0, 0, 255]));
+        img.put_pixel(2, 0, Rgba([255, 0, 0, 128]));
+        let flat = flatten_alpha(&DynamicImage::ImageRgba8(img));
+        assert_eq!(flat.get_pixel(0, 0).0, [255, 255, 255]);
+        assert_eq!(flat.get_pixel(1, 0).0, [0, 0, 0]);
+        assert_eq!(flat.get_pixel(2, 0).0, [255, 127, 127]);
+    }
+
+    #[test]
+    fn size_limits_are_ordered() {
+        assert!(ImageNormalizer::new(64, 64).is_ok());
+        assert!(ImageNormalizer::new(64, 65).is_err());
+    }
+}
@@ -0,0 +1,467 @@
+// A generic source for stores which describe their products with schema.org
+// JSON-LD, configured with a JSON file such as:
+//
+//     {
+//         "stores": [
+//             {
+//                 "id": "example-chairs",
+//                 "website": "example.com",
+//                 "seed_urls": ["https://www.example.com/category/chairs"],
+//                 "product_pattern": "^https://www\\.example\\.com/p/",
+//                 "currency": "USD"
+//             }
+//         ]
+//     }
+//
+// Product links on the seed pages which match the pattern are followed, and
+// each product page's JSON-LD is turned into a listing. The store's ID is
+// used as the category of its listings.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use regex::Regex;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::{
+    spawn,
+    sync::mpsc::{channel, Receiver},
+};
+
+use crate::{
+    db::Listing,
+    scraper::{Client, ClientError},
+    sources::{ScrapedItem, SkipReason},
+};
+
+const DEFAULT_CURRENCY: &str = "USD";
+const DEFAULT_MAX_STARS: f64 = 5.0;
+
+#[derive(Deserialize)]
+pub struct StoreConfig {
+    pub stores: Vec<Store>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Store {
+    pub id: String,
+    pub website: String,
+    pub seed_urls: Vec<String>,
+    pub product_pattern: String,
+
+    // Offers in other currencies are ignored.
+    #[serde(default = "default_currency")]
+    pub currency: String,
+}
+
+fn default_currency() -> String {
+    DEFAULT_CURRENCY.to_owned()
+}
+
+impl StoreConfig {
+    pub async fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<StoreConfig> {
+        let data = tokio::fs::read_to_string(path).await?;
+        let config: StoreConfig = serde_json::from_str(&data)?;
+        let mut ids = HashSet::new();
+        for store in &config.stores {
+            if !ids.insert(store.id.as_str()) {
+                return Err(anyhow::Error::msg(format!(
+                    "duplicate store ID: {}",
+                    store.id
+                )));
+            }
+            Regex::new(&store.product_pattern)?;
+            for url in &store.seed_urls {
+                Url::parse(url)?;
+            }
+        }
+        Ok(config)
+    }
+}
+
+pub fn stream_store(client: Client, store: Store) -> Receiver<anyhow::Result<ScrapedItem>> {
+    let (tx, rx) = channel(1);
+    spawn(async move {
+        let pattern = match Regex::new(&store.product_pattern) {
+            Ok(x) => x,
+            Err(e) => {
+                tx.send(Err(e.into())).await.ok();
+                return;
+            }
+        };
+        let mut seen = HashSet::new();
+        for seed_url in &store.seed_urls {
+            let links = client
+                .run_get(seed_url, |resp| async {
+                    let base = resp.url().clone();
+                    Ok(product_links(&resp.text().await?, &base, &pattern))
+                })
+                .await;
+            let links = match links {
+                Ok(x) => x,
+                Err(e) => {
+                    tx.send(Err(e.into())).await.ok();
+                    return;
+                }
+            };
+            for link in links {
+                if !seen.insert(link.clone()) {
+                    continue;
+                }
+                // A single broken product page shouldn't cost the rest of the
+                // store, but there's no point carrying on once we're blocked.
+                let item = match product_listing(&client, &store, link).await {
+                    Ok(x) => x,
+                    Err(e) if is_blocked(&e) => {
+                        tx.send(Err(e)).await.ok();
+                        return;
+                    }
+                    Err(_) => ScrapedItem::Skipped(SkipReason::FailedRequest),
+                };
+                if tx.send(Ok(item)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    rx
+}
+
+async fn product_listing(client: &Client, store: &Store, url: Url) -> anyhow::Result<ScrapedItem> {
+    let product = client
+        .run_get(url.clone(), |resp| async {
+            Ok(parse_product_page(&resp.text().await?, &store.currency))
+        })
+        .await?;
+    let product = match product {
+        Ok(x) => x,
+        Err(reason) => return Ok(ScrapedItem::Skipped(reason)),
+    };
+    let image_url = url.join(&product.image_url)?;
+    let image_data = client.get_bytes(image_url).await?;
+    Ok(ScrapedItem::Listing(Listing {
+        website: store.website.clone(),
+        website_id: product.sku.unwrap_or_else(|| url.path().to_owned()),
+        price: product.price,
+        title: product.name,
+        image_data,
+        categories: vec![store.id.clone()],
+        star_rating: product.star_rating,
+        max_stars: product.max_stars,
+        num_reviews: product.num_reviews,
+    }))
+}
+
+fn is_blocked(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<ClientError>(),
+        Some(ClientError::Blocked { .. })
+    )
+}
+
+// Find the distinct links on a page which match a product URL pattern.
+fn product_links(html: &str, base: &Url, pattern: &Regex) -> Vec<Url> {
+    let href = Regex::new(r#"href\s*=\s*["']([^"']+)["']"#).unwrap();
+    let mut seen = HashSet::new();
+    let mut result = Vec::new();
+    for group in href.captures_iter(html) {
+        let link = html_escape::decode_html_entities(group.get(1).unwrap().as_str()).into_owned();
+        if let Ok(mut url) = base.join(&link) {
+            url.set_fragment(None);
+            if pattern.is_match(url.as_str()) && seen.insert(url.clone()) {
+                result.push(url);
+            }
+        }
+    }
+    result
+}
+
+#[derive(Debug, PartialEq)]
+struct Product {
+    name: String,
+    sku: Option<String>,
+    price: i64,
+    image_url: String,
+    star_rating: Option<f64>,
+    max_stars: Option<f64>,
+    num_reviews: Option<i64>,
+}
+
+// Extract the first product on a page which has a name, an image, and a price
+// in the requested currency. If there is no such product, the reason that the
+// first product was unusable is returned.
+fn parse_product_page(html: &str, currency: &str) -> Result<Product, SkipReason> {
+    let script = Regex::new(
+        r#"(?is)<script[^>]*type\s*=\s*["']application/ld\+json["'][^>]*>(.*?)</script>"#,
+    )
+    .unwrap();
+    let mut objects = Vec::new();
+    for group in script.captures_iter(html) {
+        if let Ok(value) = serde_json::from_str::<Value>(group.get(1).unwrap().as_str()) {
+            flatten_objects(value, &mut objects);
+        }
+    }
+    let mut first_reason = None;
+    for product in objects.iter().filter(|x| has_type(x, "Product")) {
+        match parse_product(product, currency) {
+            Ok(x) => return Ok(x),
+            Err(reason) => {
+                first_reason.get_or_insert(reason);
+            }
+        }
+    }
+    Err(first_reason.unwrap_or(SkipReason::MissingDetails))
+}
+
+// Collect the top-level objects of a JSON-LD document, which may be a single
+// object, a list of objects, or an object with a @graph.
+fn flatten_objects(value: Value, result: &mut Vec<Value>) {
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                flatten_objects(item, result);
+            }
+        }
+        Value::Object(mut obj) => {
+            if let Some(graph) = obj.remove("@graph") {
+                flatten_objects(graph, result);
+            }
+            result.push(Value::Object(obj));
+        }
+        _ => (),
+    }
+}
+
+fn has_type(value: &Value, type_name: &str) -> bool {
+    match &value["@type"] {
+        Value::String(x) => x == type_name,
+        Value::Array(xs) => xs.iter().any(|x| x.as_str() == Some(type_name)),
+        _ => false,
+    }
+}
+
+fn parse_product(product: &Value, currency: &str) -> Result<Product, SkipReason> {
+    let name = product["name"].as_str().ok_or(SkipReason::MissingDetails)?;
+    let image_url = first_image(&product["image"]).ok_or(SkipReason::MissingImage)?;
+    let price = offers(&product["offers"])
+        .into_iter()
+        .filter_map(|x| offer_price(x, currency))
+        .next()
+        .ok_or(SkipReason::MissingPrice)?;
+    let rating = &product["aggregateRating"];
+    let star_rating = number(&rating["ratingValue"]);
+    let num_reviews = number(&rating["reviewCount"])
+        .or_else(|| number(&rating["ratingCount"]))
+        .map(|x| x as i64);
+    Ok(Product {
+        name: html_escape::decode_html_entities(name.trim()).into_owned(),
+        sku: product["sku"]
+            .as_str()
+            .or_else(|| product["productID"].as_str())
+            .map(|x| x.to_owned()),
+        price,
+        image_url,
+        star_rating,
+        max_stars: star_rating.map(|_| number(&rating["bestRating"]).unwrap_or(DEFAULT_MAX_STARS)),
+        num_reviews,
+    })
+}
+
+// An image may be a URL, an ImageObject, or a list of either.
+fn first_image(value: &Value) -> Option<String> {
+    match value {
+        Value::String(x) => Some(x.clone()),
+        Value::Object(obj) => obj
+            .get("url")
+            .and_then(|x| x.as_str())
+            .map(|x| x.to_owned()),
+        Value::Array(xs) => xs.iter().find_map(first_image),
+        _ => None,
+    }
+}
+
+fn offers(value: &Value) -> Vec<&Value> {
+    match value {
+        Value::Array(xs) => xs.iter().collect(),
+        Value::Object(_) => vec![value],
+        _ => Vec::new(),
+    }
+}
+
+// Get the price of an Offer or the lowest price of an AggregateOffer, in
+// cents.
+fn offer_price(offer: &Value, currency: &str) -> Option<i64> {
+    let spec = &offer["priceSpecification"];
+    let offer_currency = offer["priceCurrency"]
+        .as_str()
+        .or_else(|| spec["priceCurrency"].as_str());
+    if let Some(offer_currency) = offer_currency {
+        if !offer_currency.eq_ignore_ascii_case(currency) {
+            return None;
+        }
+    }
+    let price = number(&offer["price"])
+        .or_else(|| number(&offer["lowPrice"]))
+        .or_else(|| number(&spec["price"]))?;
+    if price > 0.0 {
+        Some((price * 100.0).round() as i64)
+    } else {
+        None
+    }
+}
+
+// Numbers are often encoded as strings in JSON-LD.
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(x) => x.as_f64(),
+        Value::String(x) => x.trim().replace([',', '$'], "").parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+
+    use super::*;
+    use crate::scraper::{ClientPolicy, Transport};
+
+    const CATEGORY_PAGE: &str = include_str!("../testdata/jsonld/category.html");
+    const PRODUCT_PAGE: &str = include_str!("../testdata/jsonld/product.html");
+    const AGGREGATE_OFFER_PAGE: &str = include_str!("../testdata/jsonld/aggregate_offer.html");
+
+    #[test]
+    fn product_links_match_pattern() {
+        let base = Url::parse("https://shop.example.com/c/chairs?page=1").unwrap();
+        let pattern = Regex::new(r"^https://shop\.example\.com/p/").unwrap();
+        let links = product_links(CATEGORY_PAGE, &base, &pattern)
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            links,
+            vec![
+                "https://shop.example.com/p/oak-chair-1001",
+                "https://shop.example.com/p/velvet-armchair-1002?color=green&size=l",
+                "https://shop.example.com/p/folding-chair-1003",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_offer_product() {
+        assert_eq!(
+            parse_product_page(PRODUCT_PAGE, "USD"),
+            Ok(Product {
+                name: "Oak Dining Chair & Cushion".to_owned(),
+                sku: Some("1001".to_owned()),
+                price: 12999,
+                image_url: "/images/oak-chair-1001.jpg".to_owned(),
+                star_rating: Some(4.5),
+                max_stars: Some(5.0),
+                num_reviews: Some(1234),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_aggregate_offer_product() {
+        assert_eq!(
+            parse_product_page(AGGREGATE_OFFER_PAGE, "usd"),
+            Ok(Product {
+                name: "Velvet Armchair".to_owned(),
+                sku: None,
+                price: 34900,
+                image_url: "https://cdn.example.com/armchair-green.jpg".to_owned(),
+                star_rating: Some(8.6),
+                max_stars: Some(10.0),
+                num_reviews: Some(57),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_product_other_currency() {
+        assert_eq!(
+            parse_product_page(PRODUCT_PAGE, "EUR"),
+            Err(SkipReason::MissingPrice)
+        );
+    }
+
+    #[test]
+    fn parse_page_without_product() {
+        assert_eq!(
+            parse_product_page(CATEGORY_PAGE, "USD"),
+            Err(SkipReason::MissingDetails)
+        );
+    }
+
+    // Serve a store whose seed page links to a missing product, a working
+    // product, and a product behind a block page, in that order.
+    fn serve_store() -> (Store, tokio::task::JoinHandle<hyper::Result<()>>) {
+        let make_service = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let (status, body) = match req.uri().path() {
+                    "/c/chairs" => (
+                        200,
+                        r#"<a href="/p/missing">x</a> <a href="/p/1001">y</a> <a href="/p/blocked">z</a>"#,
+                    ),
+                    "/p/1001" => (200, PRODUCT_PAGE),
+                    "/images/oak-chair-1001.jpg" => (200, "image data"),
+                    "/p/blocked" => (403, "Access Denied"),
+                    _ => (404, "not found"),
+                };
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(status)
+                        .header("content-type", "text/html")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        let store = Store {
+            id: "chairs".to_owned(),
+            website: "example.com".to_owned(),
+            seed_urls: vec![format!("{}/c/chairs", url)],
+            product_pattern: format!("^{}/p/", regex::escape(&url)),
+            currency: "USD".to_owned(),
+        };
+        (store, tokio::spawn(server))
+    }
+
+    #[tokio::test]
+    async fn stream_store_skips_failed_products() {
+        let (store, server) = serve_store();
+        let policy = ClientPolicy {
+            num_retries: 1,
+            requests_per_second: 0.0,
+            ..Default::default()
+        };
+        let mut items = stream_store(Client::new(policy, Transport::Live), store);
+        assert!(matches!(
+            items.recv().await,
+            Some(Ok(ScrapedItem::Skipped(SkipReason::FailedRequest)))
+        ));
+        match items.recv().await {
+            Some(Ok(ScrapedItem::Listing(listing))) => {
+                assert_eq!(listing.website_id, "1001");
+                assert_eq!(listing.image_data, b"image data");
+            }
+            _ => panic!("expected a listing"),
+        }
+        match items.recv().await {
+            Some(Err(e)) => assert!(is_blocked(&e)),
+            _ => panic!("expected the store to be blocked"),
+        }
+        assert!(items.recv().await.is_none());
+        server.abort();
+    }
+}
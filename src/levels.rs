@@ -1,63 +1,311 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use rusqlite::types::Value;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_LEVELS: &str = include_str!("levels.json");
+
+// The set of websites and levels which players can choose from.
+#[derive(Deserialize)]
+pub struct LevelConfig {
+    pub websites: Vec<Website>,
+    pub levels: Vec<Level>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Website {
+    pub name: String,
+    pub icon: String,
+}
+
+#[derive(Clone, Deserialize)]
 pub struct Level {
-    pub id: &'static str,
-    pub website_name: &'static str,
-    pub category_name: &'static str,
-    query: &'static str,
-}
-
-pub const LEVELS: [Level; 6] = [
-    Level{
-        id: "amazon-all",
-        website_name: "Amazon",
-        category_name: "All Amazon",
-        query: "website=\"amazon.com\"",
-    },
-    Level{
-        id: "amazon-if",
-        website_name: "Amazon",
-        category_name: "Interesting Finds",
-        query: "website=\"amazon.com\" AND (
-            SELECT COUNT(*) FROM categories WHERE categories.listing_id = listings.id AND categories.category = \"interesting-finds\"
-        ) > 0",
-    },
-    Level{
-        id: "amazon-thi",
-        website_name: "Amazon",
-        category_name: "Home Improvement",
-        query: "website=\"amazon.com\" AND (
-            SELECT COUNT(*) FROM categories WHERE categories.listing_id = listings.id AND categories.category = \"adult-neutral:home-improvement\"
-        ) > 0",
-    },
-    Level{
-        id: "target-all",
-        website_name: "Target",
-        category_name: "All Target",
-        query: "website=\"target.com\"",
-    },
-    Level{
-        id: "target-clothes",
-        website_name: "Target",
-        category_name: "Clothes, Shoes & Accessories",
-        query: "website=\"target.com\" AND (
-            SELECT COUNT(*) FROM categories WHERE categories.listing_id = listings.id AND categories.category = \"rdihz\"
-        ) > 0",
-    },
-    Level{
-        id: "target-sports-outdoors",
-        website_name: "Target",
-        category_name: "Sports & Outdoors",
-        query: "website=\"target.com\" AND (
-            SELECT COUNT(*) FROM categories WHERE categories.listing_id = listings.id AND categories.category = \"5xt85\"
-        ) > 0",
-    },
-];
+    pub id: String,
+    pub website: String,
+    pub name: String,
+    pub icon: String,
+    pub filter: LevelFilter,
+}
+
+// Criteria for the listings included in a level. Every specified criterion
+// must match, and list criteria match if any of their entries match.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct LevelFilter {
+    #[serde(default)]
+    pub websites: Vec<String>,
+
+    #[serde(default)]
+    pub categories: Vec<String>,
+
+    // Bounds on the price, in cents.
+    pub min_price: Option<i64>,
+    pub max_price: Option<i64>,
+
+    pub min_rating: Option<f64>,
+}
+
+impl LevelConfig {
+    pub fn builtin() -> LevelConfig {
+        LevelConfig::parse(DEFAULT_LEVELS).expect("built-in levels should be valid")
+    }
+
+    pub async fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<LevelConfig> {
+        let data = tokio::fs::read_to_string(path).await?;
+        LevelConfig::parse(&data)
+    }
+
+    fn parse(data: &str) -> anyhow::Result<LevelConfig> {
+        let config: LevelConfig = serde_json::from_str(data)?;
+        let websites = config
+            .websites
+            .iter()
+            .map(|x| x.name.as_str())
+            .collect::<HashSet<_>>();
+        let mut ids = HashSet::new();
+        for level in &config.levels {
+            if !ids.insert(level.id.as_str()) {
+                return Err(anyhow::Error::msg(format!(
+                    "duplicate level ID: {}",
+                    level.id
+                )));
+            }
+            if !websites.contains(level.website.as_str()) {
+                return Err(anyhow::Error::msg(format!(
+                    "level {} has unknown website: {}",
+                    level.id, level.website
+                )));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn find_by_id(&self, id: &str) -> Option<&Level> {
+        self.levels.iter().find(|x| x.id == id)
+    }
+
+    pub fn website(&self, name: &str) -> Option<&Website> {
+        self.websites.iter().find(|x| x.name == name)
+    }
+}
 
 impl Level {
-    pub fn find_by_id(id: &str) -> Option<&'static Level> {
-        LEVELS.iter().filter(|x| x.id == id).next()
+    pub fn listing_query(&self, first_param: usize) -> (String, Vec<Value>) {
+        self.filter.listing_query(first_param)
+    }
+}
+
+impl LevelFilter {
+    // Create a condition on the listings table which selects the listings
+    // matching this filter, along with the values for its parameters.
+    //
+    // Parameters are numbered starting at first_param, so that the
+    // condition can be combined with other parameterized clauses.
+    pub fn listing_query(&self, first_param: usize) -> (String, Vec<Value>) {
+        let filter = self;
+        let mut clauses = Vec::new();
+        let mut params = Vec::new();
+        let mut next_param = |value: Value| {
+            params.push(value);
+            format!("?{}", first_param + params.len() - 1)
+        };
+        if !filter.websites.is_empty() {
+            let names = filter
+                .websites
+                .iter()
+                .map(|x| next_param(x.clone().into()))
+                .collect::<Vec<_>>();
+            clauses.push(format!("website IN ({})", names.join(", ")));
+        }
+        if !filter.categories.is_empty() {
+            let names = filter
+                .categories
+                .iter()
+                .map(|x| next_param(x.clone().into()))
+                .collect::<Vec<_>>();
+            clauses.push(format!(
+                "EXISTS (
+                    SELECT NULL FROM categories
+                    WHERE categories.listing_id = listings.id
+                    AND categories.category IN ({})
+                )",
+                names.join(", ")
+            ));
+        }
+        if let Some(min_price) = filter.min_price {
+            clauses.push(format!("price >= {}", next_param(min_price.into())));
+        }
+        if let Some(max_price) = filter.max_price {
+            clauses.push(format!("price <= {}", next_param(max_price.into())));
+        }
+        if let Some(min_rating) = filter.min_rating {
+            clauses.push(format!("star_rating >= {}", next_param(min_rating.into())));
+        }
+        if clauses.is_empty() {
+            ("1".to_owned(), params)
+        } else {
+            (clauses.join(" AND "), params)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::migrate;
+    use rusqlite::{params_from_iter, Connection};
+
+    // Listings 1-4, as (website, price, star rating, categories).
+    const LISTINGS: &[(&str, i64, Option<f64>, &[&str])] = &[
+        ("amazon", 500, Some(4.5), &["toys"]),
+        ("amazon", 5000, None, &["kitchen", "home"]),
+        ("target", 1500, Some(3.0), &["home"]),
+        ("walmart", 20000, Some(4.8), &[]),
+    ];
+
+    fn test_db() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        for (i, (website, price, rating, categories)) in LISTINGS.iter().enumerate() {
+            conn.execute(
+                "
+                    INSERT INTO listings
+                        (id, created, last_seen, website, website_id, price, title, image_blob, star_rating)
+                    VALUES (?1, 0, 0, ?2, ?1, ?3, 'title', 0, ?4)
+                ",
+                rusqlite::params![i + 1, website, price, rating],
+            )
+            .unwrap();
+            for category in categories.iter() {
+                conn.execute(
+                    "INSERT INTO categories (listing_id, category) VALUES (?1, ?2)",
+                    rusqlite::params![i + 1, category],
+                )
+                .unwrap();
+            }
+        }
+        conn
+    }
+
+    // Run a filter's query after first_param - 1 other parameters, which
+    // must not interfere with the filter's own parameters.
+    fn matching_ids(conn: &Connection, filter: &LevelFilter, first_param: usize) -> Vec<i64> {
+        let (condition, params) = filter.listing_query(first_param);
+        let mut all_params = Vec::new();
+        let mut prefix = Vec::new();
+        for i in 1..first_param {
+            all_params.push(Value::Text(format!("prefix{}", i)));
+            prefix.push(format!("?{} = 'prefix{}'", i, i));
+        }
+        prefix.push(condition);
+        all_params.extend(params);
+        conn.prepare(&format!(
+            "SELECT id FROM listings WHERE {} ORDER BY id",
+            prefix.join(" AND ")
+        ))
+        .unwrap()
+        .query_map(params_from_iter(all_params), |row| row.get(0))
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap()
+    }
+
+    #[test]
+    fn listing_query_fields() {
+        let conn = test_db();
+        let cases = [
+            (LevelFilter::default(), vec![1, 2, 3, 4]),
+            (
+                LevelFilter {
+                    websites: vec!["amazon".to_owned(), "walmart".to_owned()],
+                    ..Default::default()
+                },
+                vec![1, 2, 4],
+            ),
+            (
+                LevelFilter {
+                    categories: vec!["home".to_owned(), "toys".to_owned()],
+                    ..Default::default()
+                },
+                vec![1, 2, 3],
+            ),
+            (
+                LevelFilter {
+                    min_price: Some(1500),
+                    ..Default::default()
+                },
+                vec![2, 3, 4],
+            ),
+            (
+                LevelFilter {
+                    max_price: Some(5000),
+                    ..Default::default()
+                },
+                vec![1, 2, 3],
+            ),
+            (
+                LevelFilter {
+                    min_rating: Some(4.0),
+                    ..Default::default()
+                },
+                vec![1, 4],
+            ),
+            (
+                LevelFilter {
+                    websites: vec!["amazon".to_owned(), "target".to_owned()],
+                    categories: vec!["home".to_owned()],
+                    min_price: Some(1000),
+                    max_price: Some(2000),
+                    min_rating: Some(2.0),
+                },
+                vec![3],
+            ),
+        ];
+        for (filter, expected) in cases {
+            for first_param in 1..=3 {
+                assert_eq!(
+                    matching_ids(&conn, &filter, first_param),
+                    expected,
+                    "filter {} at offset {}",
+                    serde_json::to_string(&filter).unwrap(),
+                    first_param
+                );
+            }
+        }
     }
 
-    pub fn listing_query(&self) -> &'static str {
-        self.query
+    #[test]
+    fn listing_query_placeholders() {
+        let filter = LevelFilter {
+            websites: vec!["amazon".to_owned(), "target".to_owned()],
+            categories: vec!["home".to_owned()],
+            min_price: Some(100),
+            max_price: Some(200),
+            min_rating: Some(4.0),
+        };
+        for first_param in 1..=3 {
+            let (condition, params) = filter.listing_query(first_param);
+            assert_eq!(params.len(), 6);
+            let mut rest = condition.as_str();
+            for i in 0..params.len() {
+                let placeholder = format!("?{}", first_param + i);
+                let index = rest
+                    .find(&placeholder)
+                    .unwrap_or_else(|| panic!("missing {} in {}", placeholder, condition));
+                rest = &rest[index + placeholder.len()..];
+            }
+            assert!(!condition.contains(&format!("?{}", first_param + params.len())));
+            if first_param > 1 {
+                assert!(!condition.contains(&format!("?{}", first_param - 1)));
+            }
+        }
+        assert_eq!(
+            LevelFilter {
+                min_price: Some(100),
+                ..Default::default()
+            }
+            .listing_query(3),
+            ("price >= ?3".to_owned(), vec![Value::Integer(100)])
+        );
     }
 }
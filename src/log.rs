@@ -1,22 +1,89 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    pub const ALL: [LogLevel; 3] = [LogLevel::Info, LogLevel::Warn, LogLevel::Error];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<LogLevel> {
+        LogLevel::ALL.into_iter().find(|x| x.as_str() == s)
+    }
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 #[macro_export]
-macro_rules! log_async {
-    ($db:expr, $formatstr:expr, $($args:expr),*) => {{
+macro_rules! log_at_async {
+    ($db:expr, $level:expr, $formatstr:expr, $($args:expr),*) => {{
+        let level: $crate::log::LogLevel = $level;
         let source = format!("{}:{}", file!(), line!());
         let message = format!($formatstr, $($args),*);
-        let timestamp = $db.insert_log_message(source.clone(), message.clone()).await?;
-        eprintln!("{} {}: {}", timestamp, source, message);
+        let timestamp = $db.insert_log_message(level, source.clone(), message.clone()).await?;
+        eprintln!("{} {} {}: {}", timestamp, level, source, message);
     }};
 }
 
+#[macro_export]
+macro_rules! log_async {
+    ($db:expr, $formatstr:expr, $($args:expr),*) => {
+        $crate::log_at_async!($db, $crate::log::LogLevel::Info, $formatstr, $($args),*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn_async {
+    ($db:expr, $formatstr:expr, $($args:expr),*) => {
+        $crate::log_at_async!($db, $crate::log::LogLevel::Warn, $formatstr, $($args),*)
+    };
+}
+
 #[macro_export]
 macro_rules! log_error_async {
     ($db:expr, $name:expr, $result:expr) => {{
         match $result {
             Ok(x) => Ok(x),
             Err(e) => {
-                log_async!($db, "{}: {}", $name, e);
+                $crate::log_at_async!($db, $crate::log::LogLevel::Error, "{}: {}", $name, e);
                 Err(e)
             }
         }
     }};
 }
+
+// Log a message, falling back to stderr if the log table can't be written,
+// such as when the message is about the database being unavailable.
+#[macro_export]
+macro_rules! log_best_effort {
+    ($db:expr, $level:expr, $formatstr:expr, $($args:expr),*) => {{
+        let level: $crate::log::LogLevel = $level;
+        let message = format!($formatstr, $($args),*);
+        let result: anyhow::Result<()> = async {
+            $crate::log_at_async!($db, level, "{}", message);
+            Ok(())
+        }
+        .await;
+        if result.is_err() {
+            eprintln!("{} {}:{}: {}", level, file!(), line!(), message);
+        }
+    }};
+}
@@ -1,39 +1,116 @@
 use assets::read_asset_data;
-use hyper::header::CONTENT_TYPE;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use hyper::StatusCode;
 use rand::thread_rng;
 use std::convert::Infallible;
+use std::ffi::OsString;
 use std::process::ExitCode;
+use std::sync::Arc;
 
+use crate::access_log::AccessLog;
+use crate::accounts::Accounts;
+use crate::admin::Admin;
 use crate::assets::asset_response;
 use crate::bg::Background;
-use crate::db::Database;
-use crate::http_util::maybe_compress_response;
-use crate::scraper::Client;
-use crate::sources::{default_sources, update_sources_loop};
-use clap::Parser;
-use http_util::{api_response, detect_image_mime, log_response, read_body};
+use crate::db::{Database, LogLimits, RoundReveal, DEFAULT_LOG_LIMIT};
+use crate::http_util::{error_response, maybe_compress_response};
+use crate::images::ImageNormalizer;
+use crate::jsonld::StoreConfig;
+use crate::metrics::Metrics;
+use crate::rooms::Rooms;
+use crate::scraper::{Client, ClientPolicy, Transport};
+use crate::sessions::Session;
+use crate::sources::{
+    default_sources, delete_old_listings, dry_run_sources, jsonld_source, Source, SourceUpdater,
+    UpdateLimits,
+};
+use clap::{CommandFactory, Parser, Subcommand};
+use http_util::{api_response, detect_image_mime, image_url, read_body, IMAGE_PATH_PREFIX};
+use httpdate::fmt_http_date;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
-use levels::{Level, LEVELS};
+use levels::LevelConfig;
+use scoring::{find_rule, DEFAULT_RULE, RULES};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::net::SocketAddr;
-use std::time::Duration;
-use tokio::spawn;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::signal::ctrl_c;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+use tokio::{select, spawn};
 
+mod access_log;
+mod accounts;
+mod admin;
 mod amazon;
 mod assets;
 mod bg;
 mod db;
 mod http_util;
+mod images;
+mod jsonld;
 mod levels;
 mod log;
+mod metrics;
+mod migrations;
+mod rate_limit;
+mod rooms;
+mod scoring;
 mod scraper;
+mod sessions;
 mod sources;
 mod target;
+mod walmart;
+
+// The environment variable which may hold the token for the /admin
+// endpoints.
+const ADMIN_TOKEN_VAR: &str = "PRICE_PUNCHOUT_ADMIN_TOKEN";
 
 #[derive(Clone, Parser)]
 pub struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+impl Args {
+    // Parse the command line, running the server if no subcommand is named,
+    // since that used to be the only way to run the program.
+    fn parse_with_default() -> Args {
+        Args::parse_from(default_to_serve(std::env::args_os().collect()))
+    }
+}
+
+fn default_to_serve(mut args: Vec<OsString>) -> Vec<OsString> {
+    let names_command = match args.get(1).and_then(|x| x.to_str()) {
+        Some(arg) => {
+            ["-h", "--help", "help"].contains(&arg)
+                || Args::command().find_subcommand(arg).is_some()
+        }
+        None => args.len() < 2,
+    };
+    if !names_command {
+        args.insert(1, "serve".into());
+    }
+    args
+}
+
+#[derive(Clone, Subcommand)]
+enum Command {
+    #[clap(about = "Run the game server, updating sources in the background")]
+    Serve(ServeArgs),
+
+    #[clap(about = "Update sources once and exit")]
+    Scrape(ScrapeArgs),
+
+    #[clap(about = "List the sources and when they were last updated")]
+    Sources(SourcesArgs),
+}
+
+#[derive(Clone, clap::Args)]
+pub struct ServeArgs {
     #[clap(short, long)]
     asset_dir: Option<String>,
 
@@ -43,23 +120,308 @@ pub struct Args {
     #[clap(short, long, value_parser, default_value_t = false)]
     no_updates: bool,
 
-    #[clap(short, long, value_parser, default_value_t = 10)]
-    client_retries: i32,
-
     #[clap(long, value_parser, default_value_t = 1<<20)]
     max_post_size: usize,
 
     #[clap(short, long, value_parser, default_value_t = 8080)]
     port: u16,
 
+    /// A file containing a bearer token which grants access to the /admin
+    /// endpoints. The token may instead be given in the
+    /// PRICE_PUNCHOUT_ADMIN_TOKEN environment variable. The endpoints are
+    /// disabled if neither is set.
+    #[clap(long, value_parser)]
+    admin_token_file: Option<String>,
+
+    /// A JSON file defining the available levels. If not specified, the
+    /// built-in levels are used.
+    #[clap(long, value_parser)]
+    levels: Option<String>,
+
+    /// Listings which a player saw more than this many days ago may be shown
+    /// to them again. By default, a listing is never shown twice.
+    #[clap(long, value_parser)]
+    seen_expiry_days: Option<u64>,
+
+    /// Allow signing in with one-time codes, which are written to the log.
+    /// This also allows accounts without passwords, so it is only suitable
+    /// for local use.
+    #[clap(long, value_parser, default_value_t = false)]
+    login_codes: bool,
+
+    #[clap(flatten)]
+    sources: SourceArgs,
+
+    #[clap(flatten)]
+    client: ClientArgs,
+
+    #[clap(flatten)]
+    update: UpdateArgs,
+
+    #[clap(flatten)]
+    log: LogArgs,
+
+    #[clap(flatten)]
+    access_log: AccessLogArgs,
+
     #[clap(value_parser)]
     db_path: String,
 }
 
+#[derive(Clone, clap::Args)]
+struct ScrapeArgs {
+    /// The identifier of a source to update, which may be repeated. If not
+    /// specified, every source is updated.
+    #[clap(long, value_parser)]
+    source: Vec<String>,
+
+    /// The number of listings to fetch from each source, overriding the
+    /// source's own limit.
+    #[clap(long, value_parser)]
+    limit: Option<i64>,
+
+    /// Print the listings as JSON Lines instead of storing them. The database
+    /// is not used.
+    #[clap(long, value_parser, default_value_t = false)]
+    dry_run: bool,
+
+    /// A JSON file defining the levels, used to decide which old listings
+    /// to delete.
+    #[clap(long, value_parser)]
+    levels: Option<String>,
+
+    #[clap(flatten)]
+    sources: SourceArgs,
+
+    #[clap(flatten)]
+    client: ClientArgs,
+
+    #[clap(flatten)]
+    update: UpdateArgs,
+
+    #[clap(flatten)]
+    log: LogArgs,
+
+    #[clap(value_parser, required_unless_present = "dry-run")]
+    db_path: Option<String>,
+}
+
+#[derive(Clone, clap::Args)]
+struct SourcesArgs {
+    #[clap(flatten)]
+    sources: SourceArgs,
+
+    #[clap(value_parser)]
+    db_path: String,
+}
+
+// Options which determine the set of sources.
+#[derive(Clone, clap::Args)]
+struct SourceArgs {
+    #[clap(long, value_parser, default_value_t = 512)]
+    max_image_size: u32,
+
+    #[clap(long, value_parser, default_value_t = 64)]
+    min_image_size: u32,
+
+    /// A JSON file listing extra stores to scrape using their JSON-LD
+    /// product data.
+    #[clap(long, value_parser)]
+    stores: Option<String>,
+}
+
+// Options for the scraper's HTTP client.
+#[derive(Clone, clap::Args)]
+struct ClientArgs {
+    #[clap(short, long, value_parser, default_value_t = 10)]
+    client_retries: i32,
+
+    /// The sustained number of scraper requests per second sent to each host.
+    /// Set to 0 to disable rate limiting.
+    #[clap(long, value_parser, default_value_t = 1.0)]
+    host_rate: f64,
+
+    /// The number of scraper requests which may be sent to a host at once.
+    #[clap(long, value_parser, default_value_t = 4)]
+    host_burst: u32,
+
+    /// The initial delay, in seconds, before retrying a failed request.
+    #[clap(long, value_parser, default_value_t = 10)]
+    retry_backoff: u64,
+
+    /// The longest delay, in seconds, before retrying a failed request.
+    #[clap(long, value_parser, default_value_t = 600)]
+    max_retry_backoff: u64,
+
+    /// Save every scraper response to this directory.
+    #[clap(long, value_parser, conflicts_with = "replay-dir")]
+    record_dir: Option<String>,
+
+    /// Serve scraper responses from a directory created with --record-dir
+    /// instead of the network.
+    #[clap(long, value_parser)]
+    replay_dir: Option<String>,
+}
+
+// Options for how sources are updated.
+#[derive(Clone, clap::Args)]
+struct UpdateArgs {
+    /// The number of sources which may be updated at once.
+    #[clap(long, value_parser, default_value_t = 4)]
+    source_concurrency: usize,
+
+    /// The number of sources for the same website which may be updated at once.
+    #[clap(long, value_parser, default_value_t = 1)]
+    website_concurrency: usize,
+
+    /// The longest time, in seconds, that a single source may spend updating.
+    #[clap(long, value_parser, default_value_t = 60*60)]
+    source_timeout: u64,
+}
+
+// Options for the log table.
+#[derive(Clone, clap::Args)]
+struct LogArgs {
+    /// The number of info messages to keep in the log table.
+    #[clap(long, value_parser, default_value_t = DEFAULT_LOG_LIMIT)]
+    log_limit_info: i64,
+
+    /// The number of warnings to keep in the log table.
+    #[clap(long, value_parser, default_value_t = DEFAULT_LOG_LIMIT)]
+    log_limit_warn: i64,
+
+    /// The number of errors to keep in the log table.
+    #[clap(long, value_parser, default_value_t = DEFAULT_LOG_LIMIT)]
+    log_limit_error: i64,
+}
+
+// Options for the HTTP access log.
+#[derive(Clone, clap::Args)]
+struct AccessLogArgs {
+    /// The file to write HTTP requests to, in Combined Log Format.
+    #[clap(long, value_parser, default_value = "access.log")]
+    access_log: String,
+
+    #[clap(long, value_parser, default_value_t = false)]
+    no_access_log: bool,
+
+    /// The size, in bytes, at which the access log is rotated.
+    #[clap(long, value_parser, default_value_t = 10<<20)]
+    access_log_max_size: u64,
+
+    /// The number of rotated access logs to keep.
+    #[clap(long, value_parser, default_value_t = 5)]
+    access_log_files: usize,
+
+    /// The address of a reverse proxy whose X-Forwarded-For header is used
+    /// to find the client's address. May be repeated.
+    #[clap(long, value_parser)]
+    trusted_proxy: Vec<IpAddr>,
+}
+
+impl ServeArgs {
+    // Read the admin token from a file or the environment, since command
+    // line arguments are visible to other users of the machine.
+    async fn admin_token(&self) -> anyhow::Result<Option<String>> {
+        let from_env = std::env::var(ADMIN_TOKEN_VAR)
+            .ok()
+            .filter(|x| !x.is_empty());
+        let token = match (&self.admin_token_file, from_env) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow::Error::msg(format!(
+                    "--admin-token-file and {} cannot both be set",
+                    ADMIN_TOKEN_VAR
+                )))
+            }
+            (Some(path), None) => tokio::fs::read_to_string(path).await?.trim().to_owned(),
+            (None, Some(token)) => token,
+            (None, None) => return Ok(None),
+        };
+        if token.is_empty() {
+            Err(anyhow::Error::msg("the admin token is empty"))
+        } else {
+            Ok(Some(token))
+        }
+    }
+}
+
+impl AccessLogArgs {
+    fn open(&self) -> anyhow::Result<Option<AccessLog>> {
+        if self.no_access_log {
+            return Ok(None);
+        }
+        Ok(Some(AccessLog::open(
+            &self.access_log,
+            self.access_log_max_size,
+            self.access_log_files,
+            self.trusted_proxy.clone(),
+        )?))
+    }
+}
+
+impl LogArgs {
+    fn limits(&self) -> LogLimits {
+        LogLimits {
+            info: self.log_limit_info,
+            warn: self.log_limit_warn,
+            error: self.log_limit_error,
+        }
+    }
+}
+
+impl SourceArgs {
+    async fn sources(&self) -> anyhow::Result<Vec<Box<dyn Source>>> {
+        let images = ImageNormalizer::new(self.max_image_size, self.min_image_size)?;
+        let mut sources = default_sources(images.clone());
+        if let Some(path) = &self.stores {
+            for store in StoreConfig::load(path).await?.stores {
+                sources.push(jsonld_source(store, &images));
+            }
+        }
+        Ok(sources)
+    }
+}
+
+impl ClientArgs {
+    fn client(&self) -> Client {
+        let transport = if let Some(dir) = &self.record_dir {
+            Transport::Record(dir.into())
+        } else if let Some(dir) = &self.replay_dir {
+            Transport::Replay(dir.into())
+        } else {
+            Transport::Live
+        };
+        let policy = ClientPolicy {
+            num_retries: self.client_retries,
+            requests_per_second: self.host_rate,
+            burst: self.host_burst,
+            retry_backoff: Duration::from_secs(self.retry_backoff),
+            max_retry_backoff: Duration::from_secs(self.max_retry_backoff),
+        };
+        Client::new(policy, transport)
+    }
+}
+
+impl UpdateArgs {
+    fn limits(&self, max_items: Option<i64>) -> UpdateLimits {
+        UpdateLimits {
+            concurrency: self.source_concurrency,
+            website_concurrency: self.website_concurrency,
+            source_timeout: Duration::from_secs(self.source_timeout),
+            max_items,
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> ExitCode {
-    let args = Args::parse();
-    match run(args).await {
+    let args = Args::parse_with_default();
+    let result = match args.command {
+        Command::Serve(args) => serve(args).await,
+        Command::Scrape(args) => scrape(args).await,
+        Command::Sources(args) => list_sources(args).await,
+    };
+    match result {
         Err(e) => {
             eprintln!("{}", e);
             ExitCode::FAILURE
@@ -68,80 +430,345 @@ async fn main() -> ExitCode {
     }
 }
 
-async fn run(args: Args) -> anyhow::Result<()> {
-    let db = Database::open(&args.db_path).await?;
-
-    let http_client = Client::new(args.client_retries);
-    if !args.no_updates {
-        let sources_db = db.clone();
-        spawn(async move {
-            update_sources_loop(
-                http_client,
-                sources_db,
-                Duration::from_secs(args.update_interval),
-                default_sources(),
-            )
-            .await
-            .expect("update sources loop should never fail; this is a fatal error");
-        });
+async fn load_levels(path: &Option<String>) -> anyhow::Result<LevelConfig> {
+    match path {
+        Some(path) => LevelConfig::load(path).await,
+        None => Ok(LevelConfig::builtin()),
     }
+}
+
+async fn serve(args: ServeArgs) -> anyhow::Result<()> {
+    let admin_token = args.admin_token().await?;
+    let db = Database::open(&args.db_path)
+        .await?
+        .with_log_limits(args.log.limits());
+    let levels = Arc::new(load_levels(&args.levels).await?);
+    db.sync_levels(levels.levels.clone()).await?;
+    let metrics = Metrics::default();
+    let updater = SourceUpdater::new(
+        args.client.client().with_metrics(metrics.clone()),
+        db.clone(),
+        args.update.limits(None),
+        args.sources.sources().await?,
+    )
+    .with_metrics(metrics.clone());
+    let update_task = if args.no_updates {
+        None
+    } else {
+        let loop_updater = updater.clone();
+        let loop_levels = levels.clone();
+        let update_interval = Duration::from_secs(args.update_interval);
+        Some(spawn(async move {
+            loop_updater.update_loop(loop_levels, update_interval).await;
+        }))
+    };
+    let admin =
+        admin_token.map(|token| Admin::new(token, db.clone(), updater.clone(), args.max_post_size));
+
+    let accounts = Accounts::new(db.clone(), args.max_post_size, args.login_codes);
+
+    let rooms = Rooms::new(levels.clone());
+    let rooms_clone = rooms.clone();
+    let rooms_db = db.clone();
+    spawn(async move {
+        rooms_clone.cleanup_loop(rooms_db).await;
+    });
+
+    let access_log = args.access_log.open()?;
 
     let addr = SocketAddr::from(([0, 0, 0, 0], args.port));
+    let args_db_path = args.db_path.clone();
     let state = ServerState {
         args,
         db: db.clone(),
+        levels,
+        rooms,
+        accounts,
+        admin,
+        access_log,
+        metrics,
     };
-    let make_service = make_service_fn(move |_conn| {
+    let make_service = make_service_fn(move |conn: &AddrStream| {
         let state_clone = state.clone();
+        let remote_ip = conn.remote_addr().ip();
         async move {
             Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
                 let state_clone_clone = state_clone.clone();
-                async move { handle_request(req, state_clone_clone).await }
+                async move { handle_request(req, state_clone_clone, remote_ip).await }
             }))
         }
     });
 
     log_async!(&db, "creating server at {}...", addr);
-    Server::bind(&addr).serve(make_service).await?;
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let server = Server::bind(&addr)
+        .serve(make_service)
+        .with_graceful_shutdown(async {
+            stop_rx.await.ok();
+        });
+    tokio::pin!(server);
+    select! {
+        result = &mut server => return Ok(result?),
+        signal = shutdown_signal() => {
+            log_async!(&db, "received {}, shutting down...", signal?);
+        }
+    }
 
+    // Drain connections before stopping the updater, since admin requests
+    // may start new updates.
+    stop_tx.send(()).ok();
+    server.await?;
+    updater.shutdown().await;
+    if let Some(task) = update_task {
+        task.await?;
+    }
+    log_async!(&db, "closing database {}", args_db_path);
+    db.close().await?;
+    Ok(())
+}
+
+// Wait for a signal asking the server to stop, and return its name.
+#[cfg(unix)]
+async fn shutdown_signal() -> anyhow::Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    select! {
+        result = ctrl_c() => result.map(|_| "SIGINT").map_err(Into::into),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> anyhow::Result<&'static str> {
+    ctrl_c().await?;
+    Ok("Ctrl-C")
+}
+
+async fn scrape(args: ScrapeArgs) -> anyhow::Result<()> {
+    let levels = load_levels(&args.levels).await?;
+    let mut sources = args.sources.sources().await?;
+    if !args.source.is_empty() {
+        for id in &args.source {
+            if !sources.iter().any(|x| &x.identifier() == id) {
+                return Err(anyhow::Error::msg(format!("unknown source: {}", id)));
+            }
+        }
+        sources.retain(|x| args.source.contains(&x.identifier()));
+    }
+
+    let num_sources = sources.len();
+    let failures = if args.dry_run {
+        let sources = sources.into_iter().map(Arc::from).collect();
+        dry_run_sources(&args.client.client(), sources, args.limit).await
+    } else {
+        let db = Database::open(args.db_path.as_ref().unwrap())
+            .await?
+            .with_log_limits(args.log.limits());
+        db.sync_levels(levels.levels.clone()).await?;
+        let updater = SourceUpdater::new(
+            args.client.client(),
+            db.clone(),
+            args.update.limits(args.limit),
+            sources,
+        );
+        let failures = updater.update(updater.sources().to_vec()).await;
+        delete_old_listings(&db, &levels).await?;
+        failures
+    };
+    if failures > 0 {
+        Err(anyhow::Error::msg(format!(
+            "failed to update {} of {} sources",
+            failures, num_sources
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+async fn list_sources(args: SourcesArgs) -> anyhow::Result<()> {
+    let db = Database::open(&args.db_path).await?;
+    let update_times = db.source_update_times().await?;
+    for source in args.sources.sources().await? {
+        let id = source.identifier();
+        let last_updated = match update_times.get(&id) {
+            Some(&timestamp) => {
+                fmt_http_date(UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64))
+            }
+            None => "never".to_owned(),
+        };
+        println!("{}\t{}\t{}", id, source.website(), last_updated);
+    }
     Ok(())
 }
 
 #[derive(Clone)]
 struct ServerState {
-    args: Args,
+    args: ServeArgs,
     db: Database,
+    levels: Arc<LevelConfig>,
+    rooms: Rooms,
+    accounts: Accounts,
+
+    // Set if the admin endpoints are enabled.
+    admin: Option<Admin>,
+
+    access_log: Option<AccessLog>,
+    metrics: Metrics,
 }
 
 async fn handle_request(
     mut req: Request<Body>,
     state: ServerState,
+    remote_ip: IpAddr,
 ) -> Result<Response<Body>, Infallible> {
+    let start_time = SystemTime::now();
+    let start_instant = Instant::now();
     let response = match req.uri().path() {
         "" | "/" => homepage(&state).await,
         "/api/levels" => api_response(
             &state.db,
             "list levels",
-            non_empty_levels(&state, &mut req).await,
+            non_empty_levels(&state, &req).await,
+        )
+        .await
+        .unwrap(),
+        "/api/rules" => api_response(&state.db, "list rules", scoring_rules())
+            .await
+            .unwrap(),
+        "/api/sample" => match Session::find_or_create(&state.db, &req).await {
+            Ok(session) => session.with_cookie(
+                api_response(
+                    &state.db,
+                    "sample listing",
+                    sample_listing(&state, &mut req, &session).await,
+                )
+                .await
+                .unwrap(),
+            ),
+            Err(e) => api_response(&state.db, "sample listing", Err::<Value, _>(e))
+                .await
+                .unwrap(),
+        },
+        "/api/reveal" => api_response(
+            &state.db,
+            "reveal round",
+            reveal_round(&state, &mut req).await,
         )
         .await
         .unwrap(),
-        "/api/sample" => api_response(
+        "/api/reset" => api_response(
             &state.db,
-            "sample listing",
-            sample_listing(&state, &mut req).await,
+            "reset history",
+            reset_history(&state, &req).await,
         )
         .await
         .unwrap(),
-        path => asset_response(&state.args.asset_dir, &path).await,
+        "/healthz" => text_response(StatusCode::OK, "ok\n"),
+        "/readyz" => match check_ready(&state).await {
+            Ok(_) => text_response(StatusCode::OK, "ok\n"),
+            Err(e) => text_response(StatusCode::SERVICE_UNAVAILABLE, format!("{}\n", e)),
+        },
+        "/metrics" => metrics_response(&state).await,
+        "/api/room" => match Session::find(&state.db, &req).await {
+            Ok(session) => state
+                .rooms
+                .upgrade_response(
+                    &mut req,
+                    &state.db,
+                    session.and_then(|x| x.account_id),
+                    state.args.max_post_size,
+                )
+                .unwrap_or_else(|e| error_response(true, "join room", e)),
+            Err(e) => error_response(true, "join room", e),
+        },
+        path if path.starts_with("/api/account/") => match state.accounts.handle(&mut req).await {
+            Some(x) => x,
+            None => not_found_response(&state).await,
+        },
+        path if path == "/admin" || path.starts_with("/admin/") => {
+            let response = match &state.admin {
+                Some(admin) => admin.handle(&mut req, &state.args.asset_dir).await,
+                None => None,
+            };
+            match response {
+                Some(x) => x,
+                None => not_found_response(&state).await,
+            }
+        }
+        path if path.starts_with(IMAGE_PATH_PREFIX) => {
+            image_response(&state, &req, &path[IMAGE_PATH_PREFIX.len()..]).await
+        }
+        path => asset_response(&state.args.asset_dir, path).await,
     };
     let response = maybe_compress_response(&req, response).await;
-    log_response(&state.db, &req, &response)
-        .await
-        .expect("logging response should always work");
+    let latency = start_instant.elapsed();
+    state.metrics.record_request(
+        route_name(req.uri().path()),
+        response.status().as_u16(),
+        latency,
+    );
+    if let Some(access_log) = &state.access_log {
+        access_log.log(remote_ip, &req, &response, start_time, latency);
+    }
     Ok(response)
 }
 
+// Get the name under which a request is counted in the metrics. Paths which
+// aren't API endpoints are grouped together, since there is no limit on the
+// number of distinct paths a client can request.
+fn route_name(path: &str) -> &'static str {
+    match path {
+        "" | "/" => "/",
+        "/api/levels" => "/api/levels",
+        "/api/rules" => "/api/rules",
+        "/api/sample" => "/api/sample",
+        "/api/reveal" => "/api/reveal",
+        "/api/reset" => "/api/reset",
+        "/api/room" => "/api/room",
+        path if path.starts_with("/api/account/") => "/api/account",
+        "/healthz" => "/healthz",
+        "/readyz" => "/readyz",
+        "/metrics" => "/metrics",
+        path if path == "/admin" || path.starts_with("/admin/") => "/admin",
+        path if path.starts_with(IMAGE_PATH_PREFIX) => IMAGE_PATH_PREFIX,
+        _ => "asset",
+    }
+}
+
+// Check that the game can be played: the database must be usable and at
+// least one level must have listings.
+async fn check_ready(state: &ServerState) -> anyhow::Result<()> {
+    let counts = state.db.level_counts(Vec::new()).await?;
+    if state
+        .levels
+        .levels
+        .iter()
+        .any(|level| counts.get(&level.id).copied().unwrap_or_default() > 0)
+    {
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg("no levels have any listings"))
+    }
+}
+
+fn text_response<T: Into<Body>>(status: StatusCode, text: T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain")
+        .header(CACHE_CONTROL, "no-store")
+        .body(text.into())
+        .unwrap()
+}
+
+async fn metrics_response(state: &ServerState) -> Response<Body> {
+    match state.metrics.render(&state.db, &state.levels).await {
+        Ok(text) => Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(text))
+            .unwrap(),
+        Err(e) => error_response(false, "render metrics", e),
+    }
+}
+
 async fn homepage(state: &ServerState) -> Response<Body> {
     match read_asset_data(&state.args.asset_dir, "index.html").await {
         Ok(bytes) => {
@@ -160,31 +787,69 @@ async fn homepage(state: &ServerState) -> Response<Body> {
     }
 }
 
-async fn non_empty_levels(
-    state: &ServerState,
-    req: &mut Request<Body>,
-) -> anyhow::Result<Vec<Value>> {
-    let post_data = read_body(req, state.args.max_post_size).await?;
-    let req_data: LevelsRequest = serde_json::from_slice(&post_data)?;
+// Serve an image blob by its hash. Since blobs are content-addressed, the
+// response never changes and may be cached indefinitely.
+async fn image_response(state: &ServerState, req: &Request<Body>, hash: &str) -> Response<Body> {
+    if hash.len() != 32 || !hash.chars().all(|x| x.is_ascii_hexdigit()) {
+        return not_found_response(state).await;
+    }
+    let data = match state.db.blob(hash.to_owned()).await {
+        Ok(Some(data)) => data,
+        Ok(None) => return not_found_response(state).await,
+        Err(e) => return error_response(false, "load image", e),
+    };
+    let etag = format!("\"{}\"", hash);
+    let not_modified = req
+        .headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.split(',').any(|x| x.trim() == etag || x.trim() == "*"))
+        .unwrap_or_default();
+    let resp = Response::builder()
+        .header(ETAG, &etag)
+        .header(CACHE_CONTROL, "public, max-age=31536000, immutable");
+    if not_modified {
+        resp.status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap()
+    } else {
+        resp.header(
+            CONTENT_TYPE,
+            detect_image_mime(&data).unwrap_or("application/octet-stream"),
+        )
+        .body(Body::from(data))
+        .unwrap()
+    }
+}
 
+async fn not_found_response(state: &ServerState) -> Response<Body> {
+    let mut resp = asset_response(&state.args.asset_dir, "not_found.html").await;
+    *resp.status_mut() = StatusCode::NOT_FOUND;
+    resp
+}
+
+async fn non_empty_levels(state: &ServerState, req: &Request<Body>) -> anyhow::Result<Vec<Value>> {
+    let seen_ids = match Session::find(&state.db, req).await? {
+        Some(session) => seen_listings(state, &session).await?,
+        None => Vec::new(),
+    };
+    let counts = state.db.level_counts(seen_ids).await?;
     let mut levels = Vec::new();
-    for level in &LEVELS {
-        let count = state
-            .db
-            .level_count(req_data.seen_ids.clone(), level)
-            .await?;
+    for level in &state.levels.levels {
+        let count = counts.get(&level.id).copied().unwrap_or_default();
         if count > 0 {
+            let website_icon = state
+                .levels
+                .website(&level.website)
+                .map(|x| x.icon.clone())
+                .unwrap_or_default();
             levels.push(Value::Object(
                 [
-                    ("id".to_owned(), level.id.to_owned().into()),
-                    (
-                        "website_name".to_owned(),
-                        level.website_name.to_owned().into(),
-                    ),
-                    (
-                        "category_name".to_owned(),
-                        level.category_name.to_owned().into(),
-                    ),
+                    ("id".to_owned(), level.id.clone().into()),
+                    ("website_name".to_owned(), level.website.clone().into()),
+                    ("website_icon".to_owned(), website_icon.into()),
+                    ("category_name".to_owned(), level.name.clone().into()),
+                    ("category_icon".to_owned(), level.icon.clone().into()),
                     ("count".to_owned(), count.into()),
                 ]
                 .into_iter()
@@ -195,21 +860,37 @@ async fn non_empty_levels(
     Ok(levels)
 }
 
-async fn sample_listing(state: &ServerState, req: &mut Request<Body>) -> anyhow::Result<Value> {
+async fn sample_listing(
+    state: &ServerState,
+    req: &mut Request<Body>,
+    session: &Session,
+) -> anyhow::Result<Value> {
     let post_data = read_body(req, state.args.max_post_size).await?;
     let req_data: ListingRequest = serde_json::from_slice(&post_data)?;
-    if let Some(level) = Level::find_by_id(&req_data.level) {
-        match state.db.sample_listing(req_data.seen_ids, level).await? {
-            Some((item, id)) => Ok(serde_json::to_value(ListingResponse {
-                id,
-                title: Some(item.title),
-                price: Some(item.price),
-                image_url: Some(format!(
-                    "data:{};base64,{}",
-                    detect_image_mime(&item.image_data).unwrap_or("image/jpeg"),
-                    base64::encode(item.image_data)
-                )),
-            })?),
+    let rule = find_rule(&req_data.rule)
+        .ok_or_else(|| anyhow::Error::msg("no scoring rule found with the supplied ID"))?;
+    if let Some(level) = state.levels.find_by_id(&req_data.level) {
+        let seen_ids = seen_listings(state, session).await?;
+        match state.db.sample_listing(seen_ids, level.id.clone()).await? {
+            Some(sample) => {
+                let token = state
+                    .db
+                    .create_round(
+                        Some(session.id),
+                        sample.id,
+                        level.id.to_owned(),
+                        rule.id().to_owned(),
+                        sample.listing.price,
+                    )
+                    .await?;
+                state.db.mark_seen(session.id, sample.id).await?;
+                Ok(serde_json::to_value(ListingResponse {
+                    id: sample.id,
+                    token: Some(token),
+                    title: Some(sample.listing.title),
+                    image_url: Some(image_url(&sample.image_hash)),
+                })?)
+            }
             None => Ok(serde_json::to_value(ListingResponse::default())?),
         }
     } else {
@@ -217,25 +898,263 @@ async fn sample_listing(state: &ServerState, req: &mut Request<Body>) -> anyhow:
     }
 }
 
-#[derive(Deserialize)]
-struct LevelsRequest {
-    #[serde(rename(deserialize = "seenIDs"))]
-    seen_ids: Vec<i64>,
+// Get the listings which should not be shown to a session again.
+async fn seen_listings(state: &ServerState, session: &Session) -> anyhow::Result<Vec<i64>> {
+    let max_age = state
+        .args
+        .seen_expiry_days
+        .map(|days| (days * 60 * 60 * 24) as i64);
+    Ok(state.db.seen_listings(session.id, max_age).await?)
+}
+
+// Forget the listings a session has seen, so that they can be shown again.
+async fn reset_history(state: &ServerState, req: &Request<Body>) -> anyhow::Result<Value> {
+    let cleared = match Session::find(&state.db, req).await? {
+        Some(session) => state.db.reset_seen(session.id).await?,
+        None => 0,
+    };
+    Ok(serde_json::to_value(ResetResponse { cleared })?)
+}
+
+async fn reveal_round(state: &ServerState, req: &mut Request<Body>) -> anyhow::Result<Value> {
+    let post_data = read_body(req, state.args.max_post_size).await?;
+    let req_data: RevealRequest = serde_json::from_slice(&post_data)?;
+    if req_data.guesses.is_empty() {
+        return Err(anyhow::Error::msg("at least one guess is required"));
+    }
+    for guess in &req_data.guesses {
+        scoring::check_guess(*guess)?;
+    }
+    match state
+        .db
+        .reveal_round(req_data.token, serde_json::to_string(&req_data.guesses)?)
+        .await?
+    {
+        RoundReveal::NotFound => Err(anyhow::Error::msg("no round found with the supplied token")),
+        RoundReveal::AlreadyRevealed => Err(anyhow::Error::msg("round was already revealed")),
+        RoundReveal::Revealed {
+            session_id,
+            listing_id,
+            price,
+            level,
+            rule,
+        } => {
+            let rule = find_rule(&rule)
+                .ok_or_else(|| anyhow::Error::msg("round has an unknown scoring rule"))?;
+            let guesses = req_data
+                .guesses
+                .iter()
+                .copied()
+                .map(scoring::dollars_to_cents)
+                .collect::<Vec<_>>();
+            let points = rule.points(price, &guesses);
+            let winners = scoring::winners(&points);
+
+            // The first player is the one using the device, and so the one
+            // who is signed in. Only the session which the round was sampled
+            // for is credited, so that rounds can't be replayed by another
+            // account.
+            let account_id = Session::find(&state.db, req)
+                .await?
+                .filter(|x| session_id == Some(x.id))
+                .and_then(|x| x.account_id);
+            if let Some(account_id) = account_id {
+                state
+                    .db
+                    .record_round_result(
+                        account_id,
+                        level,
+                        scoring::percent_error(price, guesses[0]),
+                        scoring::wins(rule, price, &guesses)[0],
+                    )
+                    .await?;
+            }
+
+            let history = state.db.price_history(listing_id).await?;
+            Ok(serde_json::to_value(RevealResponse {
+                price,
+                winners,
+                points,
+                history: history
+                    .into_iter()
+                    .map(|x| PricePointResponse {
+                        price: x.price,
+                        observed_at: x.observed_at,
+                    })
+                    .collect(),
+            })?)
+        }
+    }
+}
+
+fn scoring_rules() -> anyhow::Result<Vec<Value>> {
+    Ok(RULES
+        .iter()
+        .map(|rule| {
+            Value::Object(
+                [
+                    ("id".to_owned(), rule.id().into()),
+                    ("name".to_owned(), rule.name().into()),
+                    ("description".to_owned(), rule.description().into()),
+                ]
+                .into_iter()
+                .collect(),
+            )
+        })
+        .collect())
 }
 
 #[derive(Deserialize)]
 struct ListingRequest {
-    #[serde(rename(deserialize = "seenIDs"))]
-    seen_ids: Vec<i64>,
     level: String,
+
+    #[serde(default = "default_rule")]
+    rule: String,
+}
+
+fn default_rule() -> String {
+    DEFAULT_RULE.to_owned()
+}
+
+#[derive(Deserialize)]
+struct RevealRequest {
+    token: String,
+    guesses: Vec<f64>,
 }
 
 #[derive(Default, Serialize)]
 struct ListingResponse {
     id: i64,
+    token: Option<String>,
     title: Option<String>,
-    price: Option<i64>,
 
     #[serde(rename(serialize = "imageURL"))]
     image_url: Option<String>,
 }
+
+#[derive(Serialize)]
+struct RevealResponse {
+    price: i64,
+    points: Vec<f64>,
+    winners: Vec<usize>,
+    history: Vec<PricePointResponse>,
+}
+
+#[derive(Serialize)]
+struct PricePointResponse {
+    price: i64,
+
+    #[serde(rename(serialize = "observedAt"))]
+    observed_at: i64,
+}
+
+#[derive(Serialize)]
+struct ResetResponse {
+    cleared: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn serve_is_the_default_command() {
+        assert_eq!(
+            default_to_serve(args(&["price-punchout", "db.sqlite"])),
+            args(&["price-punchout", "serve", "db.sqlite"])
+        );
+        assert_eq!(
+            default_to_serve(args(&["price-punchout", "-p", "9000", "db.sqlite"])),
+            args(&["price-punchout", "serve", "-p", "9000", "db.sqlite"])
+        );
+        for unchanged in [
+            &["price-punchout"][..],
+            &["price-punchout", "--help"],
+            &["price-punchout", "scrape", "--dry-run"],
+            &["price-punchout", "serve", "db.sqlite"],
+        ] {
+            assert_eq!(default_to_serve(args(unchanged)), args(unchanged));
+        }
+        assert!(Args::try_parse_from(default_to_serve(args(&["price-punchout", "db.sqlite"])))
+            .map(|x| matches!(x.command, Command::Serve(ServeArgs { ref db_path, .. }) if db_path == "db.sqlite"))
+            .unwrap_or_default());
+    }
+
+    #[tokio::test]
+    async fn images_are_revalidated_only_if_they_exist() {
+        let level = levels::Level {
+            id: "all".to_owned(),
+            website: "test".to_owned(),
+            name: "All".to_owned(),
+            icon: "all.svg".to_owned(),
+            filter: Default::default(),
+        };
+        let db = Database::open_in_memory().await.unwrap();
+        db.insert_or_update(db::Listing {
+            website: "test".to_owned(),
+            website_id: "1".to_owned(),
+            price: 100,
+            title: "Listing".to_owned(),
+            image_data: b"image".to_vec(),
+            categories: Vec::new(),
+            star_rating: None,
+            max_stars: None,
+            num_reviews: None,
+        })
+        .await
+        .unwrap();
+        db.sync_levels(vec![level.clone()]).await.unwrap();
+        let hash = db
+            .sample_listing(Vec::new(), level.id.clone())
+            .await
+            .unwrap()
+            .unwrap()
+            .image_hash;
+
+        let levels = Arc::new(LevelConfig {
+            websites: Vec::new(),
+            levels: vec![level],
+        });
+        let args = match Args::try_parse_from(["price-punchout", "serve", "db.sqlite"])
+            .unwrap()
+            .command
+        {
+            Command::Serve(args) => args,
+            _ => unreachable!(),
+        };
+        let state = ServerState {
+            db: db.clone(),
+            rooms: Rooms::new(levels.clone()),
+            accounts: Accounts::new(db, args.max_post_size, false),
+            levels,
+            args,
+            admin: None,
+            access_log: None,
+            metrics: Metrics::default(),
+        };
+
+        let missing = "0".repeat(32);
+        let etag = format!("\"{}\"", hash);
+        let missing_etag = format!("\"{}\"", missing);
+        for (hash, if_none_match, status) in [
+            (&hash, None, StatusCode::OK),
+            (&hash, Some("\"other\""), StatusCode::OK),
+            (&hash, Some(etag.as_str()), StatusCode::NOT_MODIFIED),
+            (&hash, Some("*"), StatusCode::NOT_MODIFIED),
+            (&missing, None, StatusCode::NOT_FOUND),
+            (&missing, Some(missing_etag.as_str()), StatusCode::NOT_FOUND),
+            (&missing, Some("*"), StatusCode::NOT_FOUND),
+        ] {
+            let mut req = Request::builder();
+            if let Some(x) = if_none_match {
+                req = req.header(IF_NONE_MATCH, x);
+            }
+            let req = req.body(Body::empty()).unwrap();
+            assert_eq!(image_response(&state, &req, hash).await.status(), status);
+        }
+    }
+}
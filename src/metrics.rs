@@ -0,0 +1,300 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::db::Database;
+use crate::levels::LevelConfig;
+
+// Upper bounds, in seconds, of the buckets for HTTP request latencies.
+const REQUEST_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// Upper bounds, in seconds, of the buckets for source update durations.
+const SOURCE_UPDATE_BUCKETS: [f64; 9] = [
+    10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0,
+];
+
+// Counters and histograms which are exported in the Prometheus text format.
+// Gauges which can be read from the database are computed when the metrics
+// are rendered, rather than being tracked here.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Mutex<MetricsInner>>,
+}
+
+#[derive(Default)]
+struct MetricsInner {
+    requests: BTreeMap<(&'static str, u16), u64>,
+    request_seconds: BTreeMap<&'static str, Histogram>,
+    source_updates: BTreeMap<(String, bool), u64>,
+    source_update_seconds: BTreeMap<String, Histogram>,
+    client_retries: BTreeMap<(String, &'static str), u64>,
+}
+
+impl Metrics {
+    // Record an HTTP request, where route is a fixed name for the handler
+    // rather than the full path, to keep the number of series bounded.
+    pub fn record_request(&self, route: &'static str, status: u16, latency: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.requests.entry((route, status)).or_default() += 1;
+        inner
+            .request_seconds
+            .entry(route)
+            .or_insert_with(|| Histogram::new(&REQUEST_BUCKETS))
+            .observe(latency.as_secs_f64());
+    }
+
+    pub fn record_source_update(&self, source_id: &str, success: bool, duration: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .source_updates
+            .entry((source_id.to_owned(), success))
+            .or_default() += 1;
+        inner
+            .source_update_seconds
+            .entry(source_id.to_owned())
+            .or_insert_with(|| Histogram::new(&SOURCE_UPDATE_BUCKETS))
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn record_retry(&self, host: &str, reason: &'static str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .client_retries
+            .entry((host.to_owned(), reason))
+            .or_default() += 1;
+    }
+
+    // Render every metric, including the current listing counts and
+    // database size.
+    pub async fn render(&self, db: &Database, levels: &LevelConfig) -> anyhow::Result<String> {
+        let counts = db.level_counts(Vec::new()).await?;
+        let storage = db.storage_stats().await?;
+
+        let mut out = String::new();
+        self.render_recorded(&mut out);
+
+        write_header(
+            &mut out,
+            "price_punchout_level_listings",
+            "gauge",
+            "Number of listings available in each level.",
+        );
+        for level in &levels.levels {
+            write_sample(
+                &mut out,
+                "price_punchout_level_listings",
+                &[("level", &level.id)],
+                counts.get(&level.id).copied().unwrap_or_default() as f64,
+            );
+        }
+        write_header(
+            &mut out,
+            "price_punchout_db_size_bytes",
+            "gauge",
+            "Size of the SQLite database.",
+        );
+        write_sample(
+            &mut out,
+            "price_punchout_db_size_bytes",
+            &[],
+            storage.size_bytes as f64,
+        );
+        write_header(
+            &mut out,
+            "price_punchout_db_blobs",
+            "gauge",
+            "Number of image blobs in the database.",
+        );
+        write_sample(
+            &mut out,
+            "price_punchout_db_blobs",
+            &[],
+            storage.blobs as f64,
+        );
+        Ok(out)
+    }
+
+    fn render_recorded(&self, out: &mut String) {
+        let inner = self.inner.lock().unwrap();
+
+        write_header(
+            out,
+            "price_punchout_http_requests_total",
+            "counter",
+            "Number of HTTP requests handled, by route and status.",
+        );
+        for ((route, status), count) in &inner.requests {
+            write_sample(
+                out,
+                "price_punchout_http_requests_total",
+                &[("route", route), ("status", &status.to_string())],
+                *count as f64,
+            );
+        }
+        write_header(
+            out,
+            "price_punchout_http_request_duration_seconds",
+            "histogram",
+            "Time taken to handle HTTP requests, by route.",
+        );
+        for (route, histogram) in &inner.request_seconds {
+            histogram.write(
+                out,
+                "price_punchout_http_request_duration_seconds",
+                &[("route", route)],
+            );
+        }
+
+        write_header(
+            out,
+            "price_punchout_source_updates_total",
+            "counter",
+            "Number of source updates, by source and result.",
+        );
+        for ((source, success), count) in &inner.source_updates {
+            let result = if *success { "success" } else { "failure" };
+            write_sample(
+                out,
+                "price_punchout_source_updates_total",
+                &[("source", source), ("result", result)],
+                *count as f64,
+            );
+        }
+        write_header(
+            out,
+            "price_punchout_source_update_duration_seconds",
+            "histogram",
+            "Time taken to update each source, including failed updates.",
+        );
+        for (source, histogram) in &inner.source_update_seconds {
+            histogram.write(
+                out,
+                "price_punchout_source_update_duration_seconds",
+                &[("source", source)],
+            );
+        }
+
+        write_header(
+            out,
+            "price_punchout_client_retries_total",
+            "counter",
+            "Number of scraper requests which were retried, by host and reason.",
+        );
+        for ((host, reason), count) in &inner.client_retries {
+            write_sample(
+                out,
+                "price_punchout_client_retries_total",
+                &[("host", host), ("reason", reason)],
+                *count as f64,
+            );
+        }
+    }
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+
+    // The number of observations in each bucket, not including those in
+    // lower buckets. Observations above every bound are only in count.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            buckets: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|x| value <= *x) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &[(&str, &str)]) {
+        let bucket_name = format!("{}_bucket", name);
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += count;
+            let le = bound.to_string();
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            write_sample(out, &bucket_name, &bucket_labels, cumulative as f64);
+        }
+        let mut bucket_labels = labels.to_vec();
+        bucket_labels.push(("le", "+Inf"));
+        write_sample(out, &bucket_name, &bucket_labels, self.count as f64);
+        write_sample(out, &format!("{}_sum", name), labels, self.sum);
+        write_sample(out, &format!("{}_count", name), labels, self.count as f64);
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        out.push('{');
+        for (i, (key, value)) in labels.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(out, "{}=\"{}\"", key, escape_label(value)).unwrap();
+        }
+        out.push('}');
+    }
+    writeln!(out, " {}", value).unwrap();
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_recorded_metrics() {
+        let metrics = Metrics::default();
+        metrics.record_request("/api/sample", 200, Duration::from_millis(20));
+        metrics.record_request("/api/sample", 200, Duration::from_secs(20));
+        metrics.record_source_update("walmart/\"3944\"", false, Duration::from_secs(5));
+        metrics.record_retry("www.target.com", "status");
+
+        let mut out = String::new();
+        metrics.render_recorded(&mut out);
+        let lines = out.lines().collect::<Vec<_>>();
+        for expected in [
+            "# TYPE price_punchout_http_requests_total counter",
+            "price_punchout_http_requests_total{route=\"/api/sample\",status=\"200\"} 2",
+            "price_punchout_http_request_duration_seconds_bucket{route=\"/api/sample\",le=\"0.01\"} 0",
+            "price_punchout_http_request_duration_seconds_bucket{route=\"/api/sample\",le=\"0.025\"} 1",
+            "price_punchout_http_request_duration_seconds_bucket{route=\"/api/sample\",le=\"10\"} 1",
+            "price_punchout_http_request_duration_seconds_bucket{route=\"/api/sample\",le=\"+Inf\"} 2",
+            "price_punchout_http_request_duration_seconds_sum{route=\"/api/sample\"} 20.02",
+            "price_punchout_http_request_duration_seconds_count{route=\"/api/sample\"} 2",
+            "price_punchout_source_updates_total{source=\"walmart/\\\"3944\\\"\",result=\"failure\"} 1",
+            "price_punchout_client_retries_total{host=\"www.target.com\",reason=\"status\"} 1",
+        ] {
+            assert!(lines.contains(&expected), "missing line: {}", expected);
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use rusqlite::{Connection, ErrorCode};
+
+// Schema changes, applied in order. The schema version of a database is the
+// number of migrations which have been applied to it.
+//
+// Existing migrations must never be edited or reordered; add a new migration
+// to the end of the list instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: the original schema. Databases which predate versioning already have
+    // these tables, so every statement must tolerate existing objects.
+    "
+        CREATE TABLE if not exists listings (
+            id           INTEGER PRIMARY KEY AUTOINCREMENT,
+            created      INTEGER NOT NULL,
+            last_seen    INTEGER NOT NULL,
+            website      CHAR(32) NOT NULL,
+            website_id   CHAR(32) NOT NULL,
+            price        INTEGER NOT NULL,
+            title        CHAR(128) NOT NULL,
+            image_blob   INTEGER NOT NULL,
+            star_rating  REAL,
+            max_stars    REAL,
+            num_reviews  INTEGER,
+            sweep_mark   INT,
+            UNIQUE (website, website_id)
+        );
+        CREATE TABLE if not exists blobs (
+            id           INTEGER PRIMARY KEY AUTOINCREMENT,
+            hash         CHAR(32),
+            data         BLOB,
+            UNIQUE (hash)
+        );
+        CREATE TABLE if not exists categories (
+            listing_id   INTEGER,
+            category     CHAR(64),
+            PRIMARY KEY (listing_id, category)
+        );
+        CREATE TABLE if not exists source_status (
+            source_id    CHAR(64),
+            last_updated INTEGER,
+            PRIMARY KEY (source_id)
+        );
+        CREATE TABLE if not exists log (
+            id         INTEGER PRIMARY KEY,
+            timestamp  INTEGER,
+            source     TEXT,
+            message    TEXT
+        );
+        CREATE INDEX if not exists listings_website_id ON listings(website, website_id);
+        CREATE INDEX if not exists listings_image_blob ON listings(image_blob);
+        CREATE INDEX if not exists listings_last_seen ON listings(last_seen);
+        CREATE INDEX if not exists blobs_hash ON blobs(hash);
+        CREATE INDEX if not exists log_timestamp ON log(timestamp);
+    ",
+    // 2: server-side rounds.
+    "
+        CREATE TABLE if not exists rounds (
+            id           INTEGER PRIMARY KEY AUTOINCREMENT,
+            token        CHAR(32) NOT NULL,
+            created      INTEGER NOT NULL,
+            listing_id   INTEGER NOT NULL,
+            level        CHAR(64) NOT NULL,
+            rule         CHAR(32) NOT NULL,
+            price        INTEGER NOT NULL,
+            revealed     INTEGER,
+            guesses      TEXT,
+            UNIQUE (token)
+        );
+        CREATE INDEX if not exists rounds_created ON rounds(created);
+    ",
+    // 3: price history.
+    "
+        CREATE TABLE if not exists price_history (
+            id           INTEGER PRIMARY KEY AUTOINCREMENT,
+            listing_id   INTEGER NOT NULL,
+            price        INTEGER NOT NULL,
+            observed_at  INTEGER NOT NULL
+        );
+        CREATE INDEX if not exists price_history_listing
+            ON price_history(listing_id, observed_at);
+    ",
+    // 4: a record of each source update.
+    "
+        CREATE TABLE if not exists source_runs (
+            id               INTEGER PRIMARY KEY AUTOINCREMENT,
+            source_id        CHAR(64) NOT NULL,
+            started          INTEGER NOT NULL,
+            duration_ms      INTEGER NOT NULL,
+            error            TEXT,
+            listings_added   INTEGER NOT NULL,
+            listings_updated INTEGER NOT NULL
+        );
+        CREATE INDEX if not exists source_runs_source ON source_runs(source_id, id);
+    ",
+    // 5: log levels.
+    "
+        ALTER TABLE log ADD COLUMN level CHAR(8) NOT NULL DEFAULT 'info';
+        CREATE INDEX if not exists log_level ON log(level, id);
+    ",
+    // 6: an index of the listings in each level, which is filled in when the
+    // levels are synced.
+    "
+        CREATE TABLE if not exists levels (
+            id           CHAR(64) NOT NULL,
+            filter       TEXT NOT NULL,
+            PRIMARY KEY (id)
+        );
+        CREATE TABLE if not exists level_listings (
+            level_id     CHAR(64) NOT NULL,
+            listing_id   INTEGER NOT NULL,
+            day          INTEGER NOT NULL,
+            PRIMARY KEY (level_id, listing_id)
+        ) WITHOUT ROWID;
+        CREATE INDEX if not exists level_listings_day
+            ON level_listings(level_id, day, listing_id);
+        CREATE INDEX if not exists level_listings_listing ON level_listings(listing_id);
+    ",
+    // 7: player sessions and the listings they have seen.
+    "
+        CREATE TABLE if not exists sessions (
+            id           INTEGER PRIMARY KEY AUTOINCREMENT,
+            token        CHAR(32) NOT NULL,
+            created      INTEGER NOT NULL,
+            last_active  INTEGER NOT NULL,
+            UNIQUE (token)
+        );
+        CREATE INDEX if not exists sessions_last_active ON sessions(last_active);
+        CREATE TABLE if not exists seen_listings (
+            session_id   INTEGER NOT NULL,
+            listing_id   INTEGER NOT NULL,
+            seen_at      INTEGER NOT NULL,
+            PRIMARY KEY (session_id, listing_id)
+        ) WITHOUT ROWID;
+        CREATE INDEX if not exists seen_listings_listing ON seen_listings(listing_id);
+    ",
+    // 8: player accounts, which sessions can be signed in to, and lifetime
+    // stats for each account.
+    "
+        CREATE TABLE if not exists accounts (
+            id             INTEGER PRIMARY KEY AUTOINCREMENT,
+            username       CHAR(32) NOT NULL COLLATE NOCASE,
+            password_hash  TEXT,
+            created        INTEGER NOT NULL,
+            current_streak INTEGER NOT NULL DEFAULT 0,
+            best_streak    INTEGER NOT NULL DEFAULT 0,
+            UNIQUE (username)
+        );
+        CREATE TABLE if not exists account_stats (
+            account_id   INTEGER NOT NULL,
+            level        CHAR(64) NOT NULL,
+            rounds       INTEGER NOT NULL,
+            wins         INTEGER NOT NULL,
+            total_error  REAL NOT NULL,
+            PRIMARY KEY (account_id, level)
+        ) WITHOUT ROWID;
+        CREATE TABLE if not exists login_codes (
+            account_id   INTEGER NOT NULL,
+            code         CHAR(8) NOT NULL,
+            expires      INTEGER NOT NULL,
+            attempts     INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (account_id)
+        );
+        ALTER TABLE sessions ADD COLUMN account_id INTEGER;
+    ",
+    // 9: the session which each single-player round was sampled for.
+    "
+        ALTER TABLE rounds ADD COLUMN session_id INTEGER;
+        CREATE INDEX if not exists rounds_session ON rounds(session_id);
+    ",
+];
+
+// Bring a database up to the latest schema version.
+//
+// Fails if the database was created by a newer version of this program,
+// since its schema may not be compatible with this one.
+pub fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE if not exists schema_version (version INTEGER NOT NULL)",
+        (),
+    )?;
+    let version = current_version(conn)?;
+    if version > MIGRATIONS.len() {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error {
+                code: ErrorCode::CannotOpen,
+                extended_code: 0,
+            },
+            Some(format!(
+                "database schema version {} is newer than the latest supported version {}",
+                version,
+                MIGRATIONS.len()
+            )),
+        ));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute("DELETE FROM schema_version", ())?;
+        tx.execute("INSERT INTO schema_version (version) VALUES (?1)", (i + 1,))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn current_version(conn: &Connection) -> rusqlite::Result<usize> {
+    match conn.query_row("SELECT version FROM schema_version", (), |row| {
+        row.get::<_, i64>(0)
+    }) {
+        Ok(x) => Ok(x as usize),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The tables created by versions of this program which predate
+    // schema_version.
+    const BASELINE_SCHEMA: &str = "
+        CREATE TABLE listings (
+            id           INTEGER PRIMARY KEY AUTOINCREMENT,
+            created      INTEGER NOT NULL,
+            last_seen    INTEGER NOT NULL,
+            website      CHAR(32) NOT NULL,
+            website_id   CHAR(32) NOT NULL,
+            price        INTEGER NOT NULL,
+            title        CHAR(128) NOT NULL,
+            image_blob   INTEGER NOT NULL,
+            star_rating  REAL,
+            max_stars    REAL,
+            num_reviews  INTEGER,
+            sweep_mark   INT,
+            UNIQUE (website, website_id)
+        );
+        CREATE TABLE blobs (
+            id           INTEGER PRIMARY KEY AUTOINCREMENT,
+            hash         CHAR(32),
+            data         BLOB,
+            UNIQUE (hash)
+        );
+        CREATE TABLE categories (
+            listing_id   INTEGER,
+            category     CHAR(64),
+            PRIMARY KEY (listing_id, category)
+        );
+        CREATE TABLE source_status (
+            source_id    CHAR(64),
+            last_updated INTEGER,
+            PRIMARY KEY (source_id)
+        );
+        CREATE TABLE log (
+            id         INTEGER PRIMARY KEY,
+            timestamp  INTEGER,
+            source     TEXT,
+            message    TEXT
+        );
+        CREATE INDEX listings_website_id ON listings(website, website_id);
+        CREATE INDEX listings_image_blob ON listings(image_blob);
+        CREATE INDEX listings_last_seen ON listings(last_seen);
+        CREATE INDEX blobs_hash ON blobs(hash);
+        CREATE INDEX log_timestamp ON log(timestamp);
+    ";
+
+    fn table_names(conn: &Connection) -> Vec<String> {
+        conn.prepare("SELECT name FROM sqlite_master WHERE type='table' ORDER BY name")
+            .unwrap()
+            .query_map((), |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn upgrade_baseline_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(BASELINE_SCHEMA).unwrap();
+        conn.execute_batch(
+            "
+                INSERT INTO listings (created, last_seen, website, website_id, price, title, image_blob)
+                VALUES (1, 2, 'amazon', 'B000', 1999, 'Widget', 1);
+                INSERT INTO log (timestamp, source, message) VALUES (3, 'main', 'hello');
+            ",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), MIGRATIONS.len());
+        for table in ["rounds", "price_history", "levels", "sessions", "accounts"] {
+            assert!(table_names(&conn).contains(&table.to_owned()), "{}", table);
+        }
+        let title: String = conn
+            .query_row(
+                "SELECT title FROM listings WHERE website_id='B000'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(title, "Widget");
+        let level: String = conn
+            .query_row("SELECT level FROM log", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(level, "info");
+    }
+
+    #[test]
+    fn migrate_is_idempotent() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let tables = table_names(&conn);
+        migrate(&mut conn).unwrap();
+        assert_eq!(table_names(&conn), tables);
+        assert_eq!(current_version(&conn).unwrap(), MIGRATIONS.len());
+        let rows: i64 = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 1);
+    }
+
+    #[test]
+    fn refuse_newer_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute(
+            "UPDATE schema_version SET version=?1",
+            (MIGRATIONS.len() + 1,),
+        )
+        .unwrap();
+        match migrate(&mut conn) {
+            Err(rusqlite::Error::SqliteFailure(e, _)) => {
+                assert_eq!(e.code, ErrorCode::CannotOpen)
+            }
+            _ => panic!("expected migrate to fail"),
+        }
+        assert_eq!(current_version(&conn).unwrap(), MIGRATIONS.len() + 1);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tokio::time::{sleep_until, Instant};
+
+// A token bucket for every host, so that requests to a host are spread out
+// no matter how many sources are talking to it.
+//
+// A rate of zero disables the token buckets, but hosts may still be paused.
+#[derive(Clone)]
+pub struct HostRateLimiter {
+    requests_per_second: f64,
+    burst: f64,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+struct Bucket {
+    // May be negative when callers are waiting for tokens to refill.
+    tokens: f64,
+    last_update: Instant,
+
+    // Set when a host asks us to back off.
+    paused_until: Instant,
+}
+
+impl HostRateLimiter {
+    pub fn new(requests_per_second: f64, burst: u32) -> HostRateLimiter {
+        HostRateLimiter {
+            requests_per_second,
+            burst: burst.max(1) as f64,
+            buckets: Default::default(),
+        }
+    }
+
+    // Wait until a request may be sent to the host.
+    pub async fn acquire(&self, host: &str) {
+        let deadline = {
+            let mut buckets = self.buckets.lock().await;
+            let now = Instant::now();
+            let bucket = self.bucket(&mut buckets, host, now);
+            if self.requests_per_second <= 0.0 {
+                bucket.paused_until
+            } else {
+                self.take_token(bucket, now)
+            }
+        };
+        sleep_until(deadline).await;
+    }
+
+    // Hold back every request to the host for the given duration.
+    pub async fn pause(&self, host: &str, duration: Duration) {
+        let mut buckets = self.buckets.lock().await;
+        let now = Instant::now();
+        let bucket = self.bucket(&mut buckets, host, now);
+        bucket.paused_until = bucket.paused_until.max(now + duration);
+    }
+
+    fn bucket<'a>(
+        &self,
+        buckets: &'a mut HashMap<String, Bucket>,
+        host: &str,
+        now: Instant,
+    ) -> &'a mut Bucket {
+        buckets.entry(host.to_owned()).or_insert(Bucket {
+            tokens: self.burst,
+            last_update: now,
+            paused_until: now,
+        })
+    }
+
+    // Take a token from the bucket and return when it may be used.
+    fn take_token(&self, bucket: &mut Bucket, now: Instant) -> Instant {
+        let elapsed = now.duration_since(bucket.last_update).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.requests_per_second).min(self.burst);
+        bucket.last_update = now;
+
+        // Reserve a token now, even if it has not refilled yet, so that
+        // waiting callers are served in order.
+        bucket.tokens -= 1.0;
+        let refill = if bucket.tokens < 0.0 {
+            Duration::from_secs_f64(-bucket.tokens / self.requests_per_second)
+        } else {
+            Duration::ZERO
+        };
+        (now + refill).max(bucket.paused_until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn acquire_spreads_requests() {
+        let limiter = HostRateLimiter::new(10.0, 2);
+        let start = Instant::now();
+        for _ in 0..2 {
+            limiter.acquire("example.com").await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        for _ in 0..3 {
+            limiter.acquire("example.com").await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        // Other hosts have their own buckets.
+        let before_other = Instant::now();
+        limiter.acquire("example.org").await;
+        assert_eq!(before_other.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pause_delays_host() {
+        let limiter = HostRateLimiter::new(0.0, 1);
+        let start = Instant::now();
+        limiter.pause("example.com", Duration::from_secs(5)).await;
+        limiter.acquire("example.org").await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limiter.acquire("example.com").await;
+        assert!(start.elapsed() >= Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_refill_up_to_burst() {
+        let limiter = HostRateLimiter::new(2.0, 3);
+        for _ in 0..3 {
+            limiter.acquire("example.com").await;
+        }
+
+        // After a long rest only the burst is available, and then requests
+        // go out at the configured rate.
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire("example.com").await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limiter.acquire("example.com").await;
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+
+        // Half a token refills in a quarter of a second.
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let before = Instant::now();
+        limiter.acquire("example.com").await;
+        assert_eq!(before.elapsed(), Duration::from_millis(250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_callers_are_spaced_out() {
+        let limiter = HostRateLimiter::new(4.0, 1);
+        let start = Instant::now();
+        let handles = (0..4)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move {
+                    limiter.acquire("example.com").await;
+                    start.elapsed()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut times = Vec::new();
+        for handle in handles {
+            times.push(handle.await.unwrap());
+        }
+        times.sort();
+        assert_eq!(
+            times,
+            [0, 250, 500, 750].map(Duration::from_millis).to_vec()
+        );
+    }
+}
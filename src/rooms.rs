@@ -0,0 +1,1073 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use futures_util::{SinkExt, StreamExt};
+use http::StatusCode;
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Request, Response};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::Mutex;
+use tokio::{spawn, time::sleep};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Message, Role, WebSocketConfig};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::db::{Database, RoundReveal, FORFEIT_ERROR};
+use crate::http_util::image_url;
+use crate::levels::{Level, LevelConfig};
+use crate::log::LogLevel;
+use crate::scoring::{check_guess, dollars_to_cents, find_rule, percent_error, winners, wins};
+use crate::{log_async, log_best_effort};
+
+// Rooms which have seen no activity for this long are closed.
+const ROOM_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 30);
+
+// The frequency with which to check for idle rooms.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+const DEFAULT_TIME_LIMIT: u64 = 60;
+const MIN_TIME_LIMIT: u64 = 10;
+const MAX_TIME_LIMIT: u64 = 600;
+
+const MAX_PLAYERS: usize = 99;
+const MAX_NAME_LENGTH: usize = 32;
+
+const CODE_LENGTH: usize = 4;
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ";
+
+// The set of live multiplayer rooms, keyed by join code.
+#[derive(Clone)]
+pub struct Rooms {
+    levels: Arc<LevelConfig>,
+    rooms: Arc<Mutex<HashMap<String, Arc<Mutex<Room>>>>>,
+}
+
+impl Rooms {
+    pub fn new(levels: Arc<LevelConfig>) -> Rooms {
+        Rooms {
+            levels,
+            rooms: Default::default(),
+        }
+    }
+
+    // Accept a WebSocket upgrade request and serve the resulting connection
+    // in the background, crediting rounds to the given account.
+    pub fn upgrade_response(
+        &self,
+        req: &mut Request<Body>,
+        db: &Database,
+        account_id: Option<i64>,
+        max_message_size: usize,
+    ) -> anyhow::Result<Response<Body>> {
+        let is_upgrade = req
+            .headers()
+            .get("upgrade")
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.eq_ignore_ascii_case("websocket"))
+            .unwrap_or_default();
+        if !is_upgrade {
+            return Err(anyhow::Error::msg("expected a WebSocket upgrade request"));
+        }
+        let key = req
+            .headers()
+            .get("sec-websocket-key")
+            .ok_or_else(|| anyhow::Error::msg("missing WebSocket key"))?;
+        let accept = derive_accept_key(key.as_bytes());
+
+        let on_upgrade = hyper::upgrade::on(req);
+        let rooms = self.clone();
+        let db = db.clone();
+        spawn(async move {
+            if let Ok(upgraded) = on_upgrade.await {
+                let config = WebSocketConfig {
+                    max_message_size: Some(max_message_size),
+                    ..Default::default()
+                };
+                let ws =
+                    WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(config)).await;
+                rooms.run_connection(ws, db, account_id).await;
+            }
+        });
+
+        Ok(Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-accept", accept)
+            .body(Body::empty())
+            .unwrap())
+    }
+
+    // Periodically close rooms which have been idle for too long.
+    pub async fn cleanup_loop(&self, db: Database) {
+        loop {
+            sleep(CLEANUP_INTERVAL).await;
+            for code in self.close_idle().await {
+                log_best_effort!(&db, LogLevel::Info, "closed idle room {}", code);
+            }
+        }
+    }
+
+    // Close and remove every idle room, returning their codes.
+    //
+    // The map of rooms is not locked while individual rooms are checked, so
+    // that a busy room does not stall every other room being created or
+    // joined in the meantime.
+    async fn close_idle(&self) -> Vec<String> {
+        let rooms = self
+            .rooms
+            .lock()
+            .await
+            .iter()
+            .map(|(code, room)| (code.clone(), room.clone()))
+            .collect::<Vec<_>>();
+        let mut idle = Vec::new();
+        for (code, room) in rooms {
+            let locked = room.lock().await;
+            if locked.last_active.elapsed() > ROOM_IDLE_TIMEOUT || !locked.has_connected_players() {
+                drop(locked);
+                idle.push((code, room));
+            }
+        }
+
+        let mut rooms = self.rooms.lock().await;
+        let mut closed = Vec::new();
+        for (code, room) in idle {
+            if rooms.get(&code).map(|x| Arc::ptr_eq(x, &room)) == Some(true) {
+                rooms.remove(&code);
+                room.lock().await.close("room closed due to inactivity");
+                closed.push(code);
+            }
+        }
+        closed
+    }
+
+    async fn run_connection(
+        &self,
+        ws: WebSocketStream<Upgraded>,
+        db: Database,
+        account_id: Option<i64>,
+    ) {
+        let (mut sink, mut stream) = ws.split();
+        let (tx, mut rx) = unbounded_channel::<ServerMessage>();
+        let writer = spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                let encoded = serde_json::to_string(&msg).expect("serialize room message");
+                if sink.send(Message::Text(encoded)).await.is_err() {
+                    break;
+                }
+            }
+            sink.close().await.ok();
+        });
+
+        let mut membership: Option<(Arc<Mutex<Room>>, usize)> = None;
+        while let Some(Ok(msg)) = stream.next().await {
+            let text = match msg {
+                Message::Text(text) => text,
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            let result = match serde_json::from_str::<ClientMessage>(&text) {
+                Ok(msg) => {
+                    self.handle_message(&db, &tx, account_id, &mut membership, msg)
+                        .await
+                }
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                tx.send(ServerMessage::Error {
+                    message: format!("{}", e),
+                })
+                .ok();
+            }
+        }
+
+        if let Some((room, player)) = membership {
+            let mut locked = room.lock().await;
+            locked.disconnect(player);
+            let all_guessed = locked.all_guessed();
+            drop(locked);
+            if all_guessed {
+                Room::reveal(&room, None, &db).await.ok();
+            }
+        }
+        drop(tx);
+        writer.await.ok();
+    }
+
+    async fn handle_message(
+        &self,
+        db: &Database,
+        tx: &UnboundedSender<ServerMessage>,
+        account_id: Option<i64>,
+        membership: &mut Option<(Arc<Mutex<Room>>, usize)>,
+        msg: ClientMessage,
+    ) -> anyhow::Result<()> {
+        if let Some((room, _)) = membership {
+            if room.lock().await.closed {
+                *membership = None;
+            }
+        }
+        match msg {
+            ClientMessage::Create {
+                name,
+                level,
+                rule,
+                time_limit,
+            } => {
+                if membership.is_some() {
+                    return Err(anyhow::Error::msg("already in a room"));
+                }
+                let level = self
+                    .levels
+                    .find_by_id(&level)
+                    .ok_or_else(|| anyhow::Error::msg("no level found with the supplied ID"))?
+                    .clone();
+                if find_rule(&rule).is_none() {
+                    return Err(anyhow::Error::msg(
+                        "no scoring rule found with the supplied ID",
+                    ));
+                }
+                let name = player_name(&name)?;
+                let time_limit = time_limit
+                    .unwrap_or(DEFAULT_TIME_LIMIT)
+                    .clamp(MIN_TIME_LIMIT, MAX_TIME_LIMIT);
+                let room = self.create(level, rule, time_limit).await;
+                let mut locked = room.lock().await;
+                let player = locked.add_player(name, tx.clone(), account_id)?;
+                log_async!(db, "created room {}", locked.code);
+                drop(locked);
+                *membership = Some((room, player));
+            }
+            ClientMessage::Join { code, name } => {
+                if membership.is_some() {
+                    return Err(anyhow::Error::msg("already in a room"));
+                }
+                let name = player_name(&name)?;
+                let room = self
+                    .rooms
+                    .lock()
+                    .await
+                    .get(&code.trim().to_uppercase())
+                    .cloned()
+                    .ok_or_else(|| anyhow::Error::msg("no room found with the supplied code"))?;
+                let player = room.lock().await.add_player(name, tx.clone(), account_id)?;
+                *membership = Some((room, player));
+            }
+            ClientMessage::Start | ClientMessage::Next => {
+                let (room, player) = membership
+                    .as_ref()
+                    .ok_or_else(|| anyhow::Error::msg("not in a room"))?;
+                Room::start_round(room, *player, db).await?;
+            }
+            ClientMessage::Guess { guess } => {
+                let (room, player) = membership
+                    .as_ref()
+                    .ok_or_else(|| anyhow::Error::msg("not in a room"))?;
+                check_guess(guess)?;
+                let mut locked = room.lock().await;
+                locked.last_active = Instant::now();
+                let round = locked
+                    .round
+                    .as_mut()
+                    .filter(|x| !x.revealed && !x.revealing)
+                    .ok_or_else(|| anyhow::Error::msg("no round is in progress"))?;
+                round.guesses[*player] = Some(guess);
+                locked.broadcast_players();
+                let all_guessed = locked.all_guessed();
+                drop(locked);
+                if all_guessed {
+                    Room::reveal(room, None, db).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn create(&self, level: Level, rule_id: String, time_limit: u64) -> Arc<Mutex<Room>> {
+        let mut rooms = self.rooms.lock().await;
+        let mut rng = rand::thread_rng();
+        let code = loop {
+            let code = (0..CODE_LENGTH)
+                .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+                .collect::<String>();
+            if !rooms.contains_key(&code) {
+                break code;
+            }
+        };
+        let room = Arc::new_cyclic(|this| {
+            Mutex::new(Room {
+                code: code.clone(),
+                level,
+                rule_id,
+                time_limit: Duration::from_secs(time_limit),
+                players: Vec::new(),
+                host: 0,
+                seen_ids: Vec::new(),
+                round: None,
+                round_number: 0,
+                starting: false,
+                closed: false,
+                last_active: Instant::now(),
+                this: this.clone(),
+            })
+        });
+        rooms.insert(code, room.clone());
+        room
+    }
+}
+
+struct Room {
+    code: String,
+    level: Level,
+    rule_id: String,
+    time_limit: Duration,
+    players: Vec<Player>,
+    host: usize,
+    seen_ids: Vec<i64>,
+    round: Option<ActiveRound>,
+    round_number: usize,
+    last_active: Instant,
+
+    // Set while the next round's listing is being chosen, during which the
+    // room is left unlocked.
+    starting: bool,
+
+    // Set once the room has been removed, after which its players are
+    // dropped from it the next time they send a message.
+    closed: bool,
+
+    // A handle to this room, used to schedule round timeouts.
+    this: Weak<Mutex<Room>>,
+}
+
+struct Player {
+    name: String,
+    score: f64,
+
+    // The account which the player's rounds are credited to, if they are
+    // signed in.
+    account_id: Option<i64>,
+
+    // The channel to the player's connection, or None if they left.
+    sender: Option<UnboundedSender<ServerMessage>>,
+}
+
+impl Player {
+    fn connected(&self) -> bool {
+        self.sender.is_some()
+    }
+}
+
+struct ActiveRound {
+    token: String,
+    number: usize,
+    guesses: Vec<Option<f64>>,
+    revealed: bool,
+
+    // The accounts of the players who were connected when the round started,
+    // who are credited with a loss if they leave without guessing.
+    accounts: Vec<Option<i64>>,
+
+    // Set while the round is being revealed in the database, during which
+    // the room is left unlocked and no more guesses are accepted.
+    revealing: bool,
+}
+
+impl Room {
+    fn add_player(
+        &mut self,
+        name: String,
+        sender: UnboundedSender<ServerMessage>,
+        account_id: Option<i64>,
+    ) -> anyhow::Result<usize> {
+        if self.players.len() >= MAX_PLAYERS {
+            return Err(anyhow::Error::msg("this room is full"));
+        }
+        let player = self.players.len();
+        self.players.push(Player {
+            name,
+            score: 0.0,
+            account_id,
+            sender: Some(sender),
+        });
+        if let Some(round) = self.round.as_mut() {
+            round.guesses.push(None);
+        }
+        if !self.players[self.host].connected() {
+            self.host = player;
+        }
+        self.last_active = Instant::now();
+        self.send(
+            player,
+            ServerMessage::Joined {
+                code: self.code.clone(),
+                player,
+            },
+        );
+        self.broadcast_players();
+        Ok(player)
+    }
+
+    fn disconnect(&mut self, player: usize) {
+        self.players[player].sender = None;
+        if self.host == player {
+            if let Some(next_host) = self.players.iter().position(|x| x.connected()) {
+                self.host = next_host;
+            }
+        }
+        self.broadcast_players();
+    }
+
+    fn has_connected_players(&self) -> bool {
+        self.players.iter().any(|x| x.connected())
+    }
+
+    fn all_guessed(&self) -> bool {
+        match &self.round {
+            Some(round) if !round.revealed && !round.revealing => {
+                self.has_connected_players()
+                    && self
+                        .players
+                        .iter()
+                        .zip(&round.guesses)
+                        .all(|(player, guess)| !player.connected() || guess.is_some())
+            }
+            _ => false,
+        }
+    }
+
+    // Start the next round on behalf of a player, who must be the host.
+    //
+    // The room is unlocked while a listing is sampled and the round is
+    // created, so that players can still join and the timeout of the
+    // previous round can still fire without waiting on the database.
+    async fn start_round(
+        room: &Arc<Mutex<Room>>,
+        player: usize,
+        db: &Database,
+    ) -> anyhow::Result<()> {
+        let (seen_ids, level_id, rule_id) = {
+            let mut room = room.lock().await;
+            if room.host != player {
+                return Err(anyhow::Error::msg("only the host can start a round"));
+            }
+            if room.closed {
+                return Err(anyhow::Error::msg("the room has been closed"));
+            }
+            if room.starting || room.round.as_ref().map(|x| !x.revealed).unwrap_or_default() {
+                return Err(anyhow::Error::msg("the current round is not finished"));
+            }
+            room.starting = true;
+            room.last_active = Instant::now();
+            (
+                room.seen_ids.clone(),
+                room.level.id.clone(),
+                room.rule_id.clone(),
+            )
+        };
+
+        let result = match db.sample_listing(seen_ids, level_id.clone()).await {
+            Ok(Some(sample)) => db
+                .create_round(None, sample.id, level_id, rule_id, sample.listing.price)
+                .await
+                .map(|token| Some((sample, token))),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+
+        let mut room = room.lock().await;
+        room.starting = false;
+        match result? {
+            Some((sample, token)) => {
+                room.seen_ids.push(sample.id);
+                room.round_number += 1;
+                room.round = Some(ActiveRound {
+                    token,
+                    number: room.round_number,
+                    guesses: vec![None; room.players.len()],
+                    revealed: false,
+                    revealing: false,
+                    accounts: room
+                        .players
+                        .iter()
+                        .map(|x| x.account_id.filter(|_| x.connected()))
+                        .collect(),
+                });
+                room.broadcast(ServerMessage::Round {
+                    number: room.round_number,
+                    title: sample.listing.title,
+                    image_url: image_url(&sample.image_hash),
+                    time_limit: room.time_limit.as_secs(),
+                });
+                room.broadcast_players();
+                room.schedule_timeout(db.clone());
+            }
+            None => room.broadcast(ServerMessage::Done),
+        }
+        Ok(())
+    }
+
+    // Reveal the current round once its time limit has passed, unless it was
+    // already revealed because every player guessed.
+    fn schedule_timeout(&self, db: Database) {
+        let room = self.this.clone();
+        let number = self.round_number;
+        let time_limit = self.time_limit;
+        spawn(async move {
+            sleep(time_limit).await;
+            if let Some(room) = room.upgrade() {
+                Room::reveal(&room, Some(number), &db).await.ok();
+            }
+        });
+    }
+
+    // Reveal the current round, or only the given round number if it is
+    // still the current one, and score the players' guesses.
+    //
+    // The room is unlocked while the round is revealed in the database, and
+    // the round is only marked as revealed once that succeeds, so that a
+    // failed reveal can be retried.
+    async fn reveal(
+        room: &Arc<Mutex<Room>>,
+        number: Option<usize>,
+        db: &Database,
+    ) -> anyhow::Result<()> {
+        let (token, guesses, submitted, encoded) = {
+            let mut room = room.lock().await;
+            let round = match room.round.as_mut() {
+                Some(round)
+                    if !round.revealed
+                        && !round.revealing
+                        && number.map(|x| x == round.number).unwrap_or(true) =>
+                {
+                    round
+                }
+                _ => return Ok(()),
+            };
+            let guesses = round.guesses.clone();
+            let submitted = guesses.iter().flatten().copied().collect::<Vec<_>>();
+            let encoded = serde_json::to_string(&submitted)?;
+            round.revealing = true;
+            (round.token.clone(), guesses, submitted, encoded)
+        };
+
+        let result = db.reveal_round(token, encoded).await;
+
+        let mut room = room.lock().await;
+        let round = match room.round.as_mut() {
+            Some(round) if round.revealing => round,
+            _ => return Ok(()),
+        };
+        round.revealing = false;
+        match result? {
+            RoundReveal::Revealed {
+                price, level, rule, ..
+            } => {
+                round.revealed = true;
+                let accounts = round.accounts.clone();
+                let rule = find_rule(&rule)
+                    .ok_or_else(|| anyhow::Error::msg("round has an unknown scoring rule"))?;
+                let cents = submitted
+                    .into_iter()
+                    .map(dollars_to_cents)
+                    .collect::<Vec<_>>();
+                let mut submitted_points = rule.points(price, &cents).into_iter();
+                let points = guesses
+                    .iter()
+                    .map(|x| match x {
+                        Some(_) => submitted_points.next().unwrap_or_default(),
+                        None => 0.0,
+                    })
+                    .collect::<Vec<_>>();
+                let mut submitted_wins = wins(rule, price, &cents).into_iter();
+                let won = guesses
+                    .iter()
+                    .map(|x| match x {
+                        Some(_) => submitted_wins.next().unwrap_or_default(),
+                        None => false,
+                    })
+                    .collect::<Vec<_>>();
+                for (player, x) in room.players.iter_mut().zip(&points) {
+                    player.score += x;
+                }
+                room.broadcast(ServerMessage::Reveal {
+                    price,
+                    guesses: guesses.clone(),
+                    winners: winners(&points),
+                    points,
+                });
+                room.broadcast_players();
+                drop(room);
+
+                // Players who were there when the round started lose it if
+                // they never guessed.
+                for (i, account_id) in accounts.into_iter().enumerate() {
+                    if let Some(account_id) = account_id {
+                        let error = guesses[i]
+                            .map(|x| percent_error(price, dollars_to_cents(x)))
+                            .unwrap_or(FORFEIT_ERROR);
+                        db.record_round_result(account_id, level.clone(), error, won[i])
+                            .await?;
+                    }
+                }
+                Ok(())
+            }
+            _ => {
+                // The round can never be revealed, so let the host move on.
+                round.revealed = true;
+                Err(anyhow::Error::msg("round was already revealed"))
+            }
+        }
+    }
+
+    fn close(&mut self, reason: &str) {
+        self.broadcast(ServerMessage::Error {
+            message: reason.to_owned(),
+        });
+        for player in self.players.iter_mut() {
+            player.sender = None;
+        }
+        self.round = None;
+        self.closed = true;
+    }
+
+    fn broadcast_players(&self) {
+        let guesses = self.round.as_ref().filter(|x| !x.revealed);
+        self.broadcast(ServerMessage::Players {
+            host: self.host,
+            players: self
+                .players
+                .iter()
+                .enumerate()
+                .map(|(i, player)| PlayerStatus {
+                    name: player.name.clone(),
+                    connected: player.connected(),
+                    guessed: guesses.map(|x| x.guesses[i].is_some()).unwrap_or_default(),
+                    score: player.score,
+                })
+                .collect(),
+        });
+    }
+
+    fn send(&self, player: usize, msg: ServerMessage) {
+        if let Some(sender) = &self.players[player].sender {
+            sender.send(msg).ok();
+        }
+    }
+
+    fn broadcast(&self, msg: ServerMessage) {
+        for i in 0..self.players.len() {
+            self.send(i, msg.clone());
+        }
+    }
+}
+
+fn player_name(name: &str) -> anyhow::Result<String> {
+    let name = name
+        .trim()
+        .chars()
+        .take(MAX_NAME_LENGTH)
+        .collect::<String>();
+    if name.is_empty() {
+        Err(anyhow::Error::msg("a player name is required"))
+    } else {
+        Ok(name)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ClientMessage {
+    Create {
+        name: String,
+        level: String,
+        rule: String,
+
+        #[serde(rename(deserialize = "timeLimit"))]
+        time_limit: Option<u64>,
+    },
+    Join {
+        code: String,
+        name: String,
+    },
+    Start,
+    Guess {
+        guess: f64,
+    },
+    Next,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ServerMessage {
+    Joined {
+        code: String,
+        player: usize,
+    },
+    Players {
+        host: usize,
+        players: Vec<PlayerStatus>,
+    },
+    Round {
+        number: usize,
+        title: String,
+
+        #[serde(rename(serialize = "imageURL"))]
+        image_url: String,
+
+        #[serde(rename(serialize = "timeLimit"))]
+        time_limit: u64,
+    },
+    Reveal {
+        price: i64,
+        guesses: Vec<Option<f64>>,
+        points: Vec<f64>,
+        winners: Vec<usize>,
+    },
+    Done,
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Clone, Serialize)]
+struct PlayerStatus {
+    name: String,
+    connected: bool,
+    guessed: bool,
+    score: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Listing;
+    use crate::levels::LevelFilter;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    async fn test_rooms() -> (Rooms, Database) {
+        let level = Level {
+            id: "all".to_owned(),
+            website: "test".to_owned(),
+            name: "All".to_owned(),
+            icon: "all.svg".to_owned(),
+            filter: LevelFilter::default(),
+        };
+        let db = Database::open_in_memory().await.unwrap();
+        for i in 0..3 {
+            db.insert_or_update(Listing {
+                website: "test".to_owned(),
+                website_id: format!("{}", i),
+                price: 1000 * (i + 1),
+                title: format!("Listing {}", i),
+                image_data: vec![i as u8],
+                categories: Vec::new(),
+                star_rating: None,
+                max_stars: None,
+                num_reviews: None,
+            })
+            .await
+            .unwrap();
+        }
+        db.sync_levels(vec![level.clone()]).await.unwrap();
+        let rooms = Rooms::new(Arc::new(LevelConfig {
+            websites: Vec::new(),
+            levels: vec![level],
+        }));
+        (rooms, db)
+    }
+
+    struct TestPlayer {
+        tx: UnboundedSender<ServerMessage>,
+        rx: UnboundedReceiver<ServerMessage>,
+        account_id: Option<i64>,
+        membership: Option<(Arc<Mutex<Room>>, usize)>,
+    }
+
+    impl TestPlayer {
+        fn new() -> TestPlayer {
+            let (tx, rx) = unbounded_channel();
+            TestPlayer {
+                tx,
+                rx,
+                account_id: None,
+                membership: None,
+            }
+        }
+
+        async fn signed_in(db: &Database, username: &str) -> TestPlayer {
+            TestPlayer {
+                account_id: db.create_account(username.to_owned(), None).await.unwrap(),
+                ..TestPlayer::new()
+            }
+        }
+
+        async fn send(
+            &mut self,
+            rooms: &Rooms,
+            db: &Database,
+            msg: ClientMessage,
+        ) -> anyhow::Result<()> {
+            rooms
+                .handle_message(db, &self.tx, self.account_id, &mut self.membership, msg)
+                .await
+        }
+
+        fn received(&mut self) -> Vec<ServerMessage> {
+            let mut result = Vec::new();
+            while let Ok(msg) = self.rx.try_recv() {
+                result.push(msg);
+            }
+            result
+        }
+
+        fn room_code(&self) -> String {
+            let (room, _) = self.membership.as_ref().unwrap();
+            room.try_lock().unwrap().code.clone()
+        }
+    }
+
+    fn create_message(time_limit: u64) -> ClientMessage {
+        ClientMessage::Create {
+            name: "Host".to_owned(),
+            level: "all".to_owned(),
+            rule: "closest".to_owned(),
+            time_limit: Some(time_limit),
+        }
+    }
+
+    fn join_message(code: String) -> ClientMessage {
+        ClientMessage::Join {
+            code,
+            name: "Guest".to_owned(),
+        }
+    }
+
+    fn find_reveal(messages: Vec<ServerMessage>) -> Option<(i64, Vec<Option<f64>>, Vec<usize>)> {
+        messages.into_iter().find_map(|msg| match msg {
+            ServerMessage::Reveal {
+                price,
+                guesses,
+                winners,
+                ..
+            } => Some((price, guesses, winners)),
+            _ => None,
+        })
+    }
+
+    #[tokio::test]
+    async fn join_codes() {
+        let (rooms, db) = test_rooms().await;
+        let mut host = TestPlayer::new();
+        host.send(&rooms, &db, create_message(60)).await.unwrap();
+        let code = host.room_code();
+        assert_eq!(code.len(), CODE_LENGTH);
+        assert!(code.bytes().all(|x| CODE_ALPHABET.contains(&x)));
+
+        let mut guest = TestPlayer::new();
+        let other = if code == "AAAA" { "BBBB" } else { "AAAA" };
+        assert!(guest
+            .send(&rooms, &db, join_message(other.to_owned()))
+            .await
+            .is_err());
+        guest
+            .send(
+                &rooms,
+                &db,
+                join_message(format!(" {} ", code.to_lowercase())),
+            )
+            .await
+            .unwrap();
+        assert!(guest.received().into_iter().any(|msg| matches!(
+            msg,
+            ServerMessage::Joined { code: ref x, player: 1 } if *x == code
+        )));
+        assert!(guest
+            .send(&rooms, &db, join_message(code.clone()))
+            .await
+            .is_err());
+
+        // Rooms with nobody left in them are closed and can't be joined.
+        assert!(rooms.close_idle().await.is_empty());
+        for player in [&host, &guest] {
+            let (room, player) = player.membership.as_ref().unwrap();
+            room.lock().await.disconnect(*player);
+        }
+        assert_eq!(rooms.close_idle().await, vec![code.clone()]);
+        let mut late = TestPlayer::new();
+        assert!(late.send(&rooms, &db, join_message(code)).await.is_err());
+
+        // Players are dropped from closed rooms and can start over.
+        let (old_room, _) = host.membership.clone().unwrap();
+        host.send(&rooms, &db, create_message(60)).await.unwrap();
+        let (new_room, _) = host.membership.as_ref().unwrap();
+        assert!(!Arc::ptr_eq(&old_room, new_room));
+    }
+
+    #[tokio::test]
+    async fn guess_collection() {
+        let (rooms, db) = test_rooms().await;
+        let mut host = TestPlayer::new();
+        host.send(&rooms, &db, create_message(60)).await.unwrap();
+        let mut guest = TestPlayer::new();
+        guest
+            .send(&rooms, &db, join_message(host.room_code()))
+            .await
+            .unwrap();
+
+        assert!(guest.send(&rooms, &db, ClientMessage::Start).await.is_err());
+        assert!(host
+            .send(&rooms, &db, ClientMessage::Guess { guess: 1.0 })
+            .await
+            .is_err());
+        host.send(&rooms, &db, ClientMessage::Start).await.unwrap();
+        assert!(host.send(&rooms, &db, ClientMessage::Next).await.is_err());
+
+        host.send(&rooms, &db, ClientMessage::Guess { guess: 25.0 })
+            .await
+            .unwrap();
+        assert!(find_reveal(guest.received()).is_none());
+        assert!(guest
+            .send(&rooms, &db, ClientMessage::Guess { guess: -1.0 })
+            .await
+            .is_err());
+
+        guest
+            .send(&rooms, &db, ClientMessage::Guess { guess: 5.0 })
+            .await
+            .unwrap();
+        let (price, guesses, winners) = find_reveal(guest.received()).unwrap();
+        assert_eq!(guesses, vec![Some(25.0), Some(5.0)]);
+        let expected_winner =
+            if (price as f64 / 100.0 - 25.0).abs() < (price as f64 / 100.0 - 5.0).abs() {
+                0
+            } else {
+                1
+            };
+        assert_eq!(winners, vec![expected_winner]);
+        assert!(guest
+            .send(&rooms, &db, ClientMessage::Guess { guess: 5.0 })
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_reveal_stays_open() {
+        let (rooms, db) = test_rooms().await;
+        let mut host = TestPlayer::new();
+        host.send(&rooms, &db, create_message(60)).await.unwrap();
+        host.send(&rooms, &db, ClientMessage::Start).await.unwrap();
+        db.close().await.unwrap();
+        assert!(host
+            .send(&rooms, &db, ClientMessage::Guess { guess: 10.0 })
+            .await
+            .is_err());
+        assert!(find_reveal(host.received()).is_none());
+
+        let (room, _) = host.membership.as_ref().unwrap();
+        let room = room.lock().await;
+        let round = room.round.as_ref().unwrap();
+        assert!(!round.revealed && !round.revealing);
+        assert_eq!(round.guesses, vec![Some(10.0)]);
+    }
+
+    #[tokio::test]
+    async fn rounds_are_credited_to_accounts() {
+        let (rooms, db) = test_rooms().await;
+        let mut host = TestPlayer::signed_in(&db, "host").await;
+        host.send(&rooms, &db, create_message(60)).await.unwrap();
+        let mut guest = TestPlayer::signed_in(&db, "guest").await;
+        guest
+            .send(&rooms, &db, join_message(host.room_code()))
+            .await
+            .unwrap();
+        let mut anonymous = TestPlayer::new();
+        anonymous
+            .send(&rooms, &db, join_message(host.room_code()))
+            .await
+            .unwrap();
+        let stats = |player: &TestPlayer| {
+            let db = db.clone();
+            let account_id = player.account_id.unwrap();
+            async move {
+                let stats = db.account_stats(account_id).await.unwrap().unwrap();
+                stats
+                    .levels
+                    .iter()
+                    .map(|x| (x.level.clone(), x.rounds, x.wins))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        // A player who leaves without guessing loses the round.
+        host.send(&rooms, &db, ClientMessage::Start).await.unwrap();
+        anonymous
+            .send(&rooms, &db, ClientMessage::Guess { guess: 10.0 })
+            .await
+            .unwrap();
+        let (room, player) = guest.membership.clone().unwrap();
+        room.lock().await.disconnect(player);
+        host.send(&rooms, &db, ClientMessage::Guess { guess: 1e6 })
+            .await
+            .unwrap();
+        assert!(find_reveal(host.received()).is_some());
+        assert_eq!(stats(&host).await, vec![("all".to_owned(), 1, 0)]);
+        assert_eq!(stats(&guest).await, vec![("all".to_owned(), 1, 0)]);
+
+        // Against nobody else, the guess must be close to win.
+        room.lock().await.disconnect(2);
+        host.send(&rooms, &db, ClientMessage::Next).await.unwrap();
+        let guess = host
+            .received()
+            .into_iter()
+            .find_map(|msg| match msg {
+                // Listing i costs $10 * (i + 1).
+                ServerMessage::Round { title, .. } => {
+                    let i = title.trim_start_matches("Listing ").parse::<f64>().ok()?;
+                    Some(10.0 * (i + 1.0))
+                }
+                _ => None,
+            })
+            .unwrap();
+        host.send(&rooms, &db, ClientMessage::Guess { guess })
+            .await
+            .unwrap();
+        assert_eq!(stats(&host).await, vec![("all".to_owned(), 2, 1)]);
+        assert_eq!(stats(&guest).await, vec![("all".to_owned(), 1, 0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeout_reveal() {
+        let (rooms, db) = test_rooms().await;
+        let mut host = TestPlayer::new();
+        host.send(&rooms, &db, create_message(MIN_TIME_LIMIT))
+            .await
+            .unwrap();
+        let mut guest = TestPlayer::new();
+        guest
+            .send(&rooms, &db, join_message(host.room_code()))
+            .await
+            .unwrap();
+        host.send(&rooms, &db, ClientMessage::Start).await.unwrap();
+        host.send(&rooms, &db, ClientMessage::Guess { guess: 10.0 })
+            .await
+            .unwrap();
+        host.received();
+
+        sleep(Duration::from_secs(MIN_TIME_LIMIT - 1)).await;
+        assert!(find_reveal(host.received()).is_none());
+
+        sleep(Duration::from_secs(2)).await;
+        let reveal = loop {
+            match host.rx.recv().await.unwrap() {
+                ServerMessage::Reveal { guesses, .. } => break guesses,
+                _ => continue,
+            }
+        };
+        assert_eq!(reveal, vec![Some(10.0), None]);
+    }
+}
@@ -0,0 +1,302 @@
+// A method of awarding points to players based on their guesses.
+//
+// Prices and guesses are both measured in cents. Points are returned in the
+// same order as the guesses.
+pub trait ScoringRule: Send + Sync {
+    fn id(&self) -> &'static str;
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    fn points(&self, price: i64, guesses: &[i64]) -> Vec<f64>;
+
+    // Check if a guess wins a round with no other players, where there is
+    // nobody to beat. By default, the guess must be within SOLO_WIN_ERROR
+    // percent of the price.
+    fn solo_win(&self, price: i64, guess: i64) -> bool {
+        percent_error(price, guess) <= SOLO_WIN_ERROR
+    }
+}
+
+pub const DEFAULT_RULE: &str = "closest";
+
+// The largest guess which is accepted, in dollars. Guesses are converted to
+// cents, and this keeps the arithmetic on them from overflowing.
+pub const MAX_GUESS: f64 = 10_000_000.0;
+
+// The largest percent error of a guess which wins a round played alone.
+pub const SOLO_WIN_ERROR: f64 = 10.0;
+
+pub const RULES: [&dyn ScoringRule; 4] = [
+    &AbsoluteClosest,
+    &ClosestWithoutGoingOver,
+    &PercentageError,
+    &LogRatio,
+];
+
+pub fn find_rule(id: &str) -> Option<&'static dyn ScoringRule> {
+    RULES.iter().copied().find(|x| x.id() == id)
+}
+
+// Check that a guess in dollars is a positive number no larger than
+// MAX_GUESS.
+pub fn check_guess(guess: f64) -> anyhow::Result<()> {
+    if !guess.is_finite() || guess <= 0.0 {
+        Err(anyhow::Error::msg("guess must be a positive number"))
+    } else if guess > MAX_GUESS {
+        Err(anyhow::Error::msg(format!(
+            "guess must be at most ${}",
+            MAX_GUESS
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+// Convert a guess in dollars to a number of cents.
+pub fn dollars_to_cents(guess: f64) -> i64 {
+    (guess * 100.0).round() as i64
+}
+
+// Get the error of a guess as a percentage of the price.
+pub fn percent_error(price: i64, guess: i64) -> f64 {
+    100.0 * (guess - price).abs() as f64 / price.max(1) as f64
+}
+
+// Check which guesses in a round won. Against other players, the guesses
+// which earned the most points win, while a guess made alone must be close
+// enough to the price by the rule's own standard.
+pub fn wins(rule: &dyn ScoringRule, price: i64, guesses: &[i64]) -> Vec<bool> {
+    if let [guess] = guesses {
+        return vec![rule.solo_win(price, *guess)];
+    }
+    let winners = winners(&rule.points(price, guesses));
+    (0..guesses.len()).map(|i| winners.contains(&i)).collect()
+}
+
+// Get the indices of the players who earned the most points in a round, or
+// an empty list if nobody earned any points.
+pub fn winners(points: &[f64]) -> Vec<usize> {
+    let best = points.iter().copied().fold(0.0, f64::max);
+    if best <= 0.0 {
+        return Vec::new();
+    }
+    points
+        .iter()
+        .enumerate()
+        .filter_map(|(i, x)| if *x == best { Some(i) } else { None })
+        .collect()
+}
+
+// The closest guess wins, and ties split a single point.
+pub struct AbsoluteClosest;
+
+impl ScoringRule for AbsoluteClosest {
+    fn id(&self) -> &'static str {
+        "closest"
+    }
+
+    fn name(&self) -> &'static str {
+        "Closest Guess"
+    }
+
+    fn description(&self) -> &'static str {
+        "The closest guess wins the round"
+    }
+
+    fn points(&self, price: i64, guesses: &[i64]) -> Vec<f64> {
+        let errors = guesses
+            .iter()
+            .map(|x| Some((x - price).abs()))
+            .collect::<Vec<_>>();
+        split_point(&errors)
+    }
+}
+
+// The closest guess which does not exceed the price wins, as in The Price Is
+// Right. If every guess is too high, nobody wins.
+pub struct ClosestWithoutGoingOver;
+
+impl ScoringRule for ClosestWithoutGoingOver {
+    fn id(&self) -> &'static str {
+        "price-is-right"
+    }
+
+    fn name(&self) -> &'static str {
+        "Without Going Over"
+    }
+
+    fn description(&self) -> &'static str {
+        "The closest guess wins, but guessing too high loses"
+    }
+
+    fn points(&self, price: i64, guesses: &[i64]) -> Vec<f64> {
+        let errors = guesses
+            .iter()
+            .map(|x| if *x <= price { Some(price - x) } else { None })
+            .collect::<Vec<_>>();
+        split_point(&errors)
+    }
+
+    fn solo_win(&self, price: i64, guess: i64) -> bool {
+        guess <= price && percent_error(price, guess) <= SOLO_WIN_ERROR
+    }
+}
+
+// Every guess earns up to one point, losing points in proportion to its
+// relative error. Guesses which are off by 100% or more earn nothing.
+pub struct PercentageError;
+
+impl ScoringRule for PercentageError {
+    fn id(&self) -> &'static str {
+        "percent-error"
+    }
+
+    fn name(&self) -> &'static str {
+        "Percentage Error"
+    }
+
+    fn description(&self) -> &'static str {
+        "Everyone scores based on how far off they were, in percent"
+    }
+
+    fn points(&self, price: i64, guesses: &[i64]) -> Vec<f64> {
+        let price = price.max(1) as f64;
+        guesses
+            .iter()
+            .map(|x| (1.0 - (*x as f64 - price).abs() / price).max(0.0))
+            .collect()
+    }
+}
+
+// Every guess earns up to one point, losing points according to the log of
+// the ratio between the guess and the price. This treats guessing half the
+// price the same as guessing double the price; both earn nothing.
+pub struct LogRatio;
+
+impl ScoringRule for LogRatio {
+    fn id(&self) -> &'static str {
+        "log-ratio"
+    }
+
+    fn name(&self) -> &'static str {
+        "Log Ratio"
+    }
+
+    fn description(&self) -> &'static str {
+        "Everyone scores, and guessing half is as bad as guessing double"
+    }
+
+    fn points(&self, price: i64, guesses: &[i64]) -> Vec<f64> {
+        let price = price.max(1) as f64;
+        guesses
+            .iter()
+            .map(|x| {
+                let ratio = (*x).max(1) as f64 / price;
+                (1.0 - ratio.ln().abs() / 2f64.ln()).max(0.0)
+            })
+            .collect()
+    }
+}
+
+// Split a single point between the guesses with the lowest error, where a
+// missing error indicates a disqualified guess.
+fn split_point(errors: &[Option<i64>]) -> Vec<f64> {
+    let mut result = vec![0.0; errors.len()];
+    if let Some(best) = errors.iter().flatten().min() {
+        let indices = errors
+            .iter()
+            .enumerate()
+            .filter_map(|(i, x)| if *x == Some(*best) { Some(i) } else { None })
+            .collect::<Vec<_>>();
+        for i in &indices {
+            result[*i] = 1.0 / indices.len() as f64;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rule_points() {
+        let price = 1000;
+        let guesses = [900, 1100, 1050, 2000];
+        for (rule, expected) in [
+            (find_rule("closest").unwrap(), [0.0, 0.0, 1.0, 0.0]),
+            (find_rule("price-is-right").unwrap(), [1.0, 0.0, 0.0, 0.0]),
+            (find_rule("percent-error").unwrap(), [0.9, 0.9, 0.95, 0.0]),
+            (find_rule("log-ratio").unwrap(), [0.848, 0.862, 0.930, 0.0]),
+        ] {
+            let points = rule.points(price, &guesses);
+            for (actual, expected) in points.iter().zip(expected) {
+                assert!(
+                    (actual - expected).abs() < 1e-3,
+                    "{}: got {:?}, expected {:?}",
+                    rule.id(),
+                    points,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_guess_over_wins_nothing() {
+        let points = ClosestWithoutGoingOver.points(1000, &[1001, 5000]);
+        assert_eq!(points, vec![0.0, 0.0]);
+        assert!(winners(&points).is_empty());
+    }
+
+    #[test]
+    fn solo_wins() {
+        let guesses = [900, 1050, 1100, 1200];
+        for (rule, expected) in [
+            ("closest", [true, true, true, false]),
+            ("price-is-right", [true, false, false, false]),
+            ("percent-error", [true, true, true, false]),
+            ("log-ratio", [true, true, true, false]),
+        ] {
+            let rule = find_rule(rule).unwrap();
+            let actual = guesses
+                .iter()
+                .map(|x| wins(rule, 1000, &[*x])[0])
+                .collect::<Vec<_>>();
+            assert_eq!(actual, expected, "{}", rule.id());
+        }
+
+        // With other players, the best guess wins however far off it is.
+        let rule = find_rule("closest").unwrap();
+        assert_eq!(wins(rule, 1000, &[5000, 9000]), vec![true, false]);
+        assert_eq!(wins(rule, 1000, &[1000, 1000]), vec![true, true]);
+    }
+
+    #[test]
+    fn split_point_ties() {
+        assert_eq!(
+            split_point(&[Some(5), None, Some(5), Some(7)]),
+            vec![0.5, 0.0, 0.5, 0.0]
+        );
+        assert_eq!(split_point(&[None, None]), vec![0.0, 0.0]);
+        assert!(split_point(&[]).is_empty());
+    }
+
+    #[test]
+    fn winners_ties() {
+        assert_eq!(winners(&[0.5, 0.0, 0.5]), vec![0, 2]);
+        assert_eq!(winners(&[0.2, 0.9, 0.4]), vec![1]);
+        assert!(winners(&[0.0, 0.0]).is_empty());
+        assert!(winners(&[]).is_empty());
+    }
+
+    #[test]
+    fn guess_bounds() {
+        assert!(check_guess(0.01).is_ok());
+        assert!(check_guess(MAX_GUESS).is_ok());
+        for guess in [0.0, -5.0, MAX_GUESS + 1.0, f64::NAN, f64::INFINITY] {
+            assert!(check_guess(guess).is_err(), "accepted {}", guess);
+        }
+        assert_eq!(dollars_to_cents(MAX_GUESS), 1_000_000_000);
+        assert_eq!(percent_error(1000, dollars_to_cents(15.0)), 50.0);
+    }
+}
@@ -1,22 +1,83 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
 use std::{future::Future, ops::DerefMut, sync::Arc, time::Duration};
 
-use reqwest::IntoUrl;
+use rand::Rng;
+use reqwest::header::{CONTENT_TYPE, RETRY_AFTER};
+use reqwest::{IntoUrl, ResponseBuilderExt, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
 use tokio::{sync::RwLock, time::sleep};
 
+use crate::metrics::Metrics;
+use crate::rate_limit::HostRateLimiter;
+
+// Determines where responses come from.
+//
+// Recording and replaying store request/response pairs in a cassette
+// directory, so that scrapers can be developed and tested offline.
+#[derive(Clone)]
+pub enum Transport {
+    Live,
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+// Limits on how hard the client may hit each host.
+#[derive(Clone)]
+pub struct ClientPolicy {
+    pub num_retries: i32,
+
+    // The steady rate of requests to a single host, and the number of
+    // requests which may be sent at once after a quiet period.
+    pub requests_per_second: f64,
+    pub burst: u32,
+
+    // Failed requests are retried after a randomized delay which starts at
+    // retry_backoff and doubles with every attempt, up to max_retry_backoff.
+    // A host's Retry-After header can only lengthen this delay.
+    pub retry_backoff: Duration,
+    pub max_retry_backoff: Duration,
+}
+
+impl Default for ClientPolicy {
+    fn default() -> ClientPolicy {
+        ClientPolicy {
+            num_retries: 10,
+            requests_per_second: 1.0,
+            burst: 4,
+            retry_backoff: Duration::from_secs(10),
+            max_retry_backoff: Duration::from_secs(600),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Client {
     client: Arc<RwLock<reqwest::Client>>,
-    num_retries: i32,
+    policy: ClientPolicy,
+    limiter: HostRateLimiter,
+    transport: Transport,
+    metrics: Metrics,
 }
 
 impl Client {
-    pub fn new(num_retries: i32) -> Client {
+    pub fn new(policy: ClientPolicy, transport: Transport) -> Client {
         Client {
             client: Arc::new(RwLock::new(reqwest::Client::new())),
-            num_retries: num_retries,
+            limiter: HostRateLimiter::new(policy.requests_per_second, policy.burst),
+            policy,
+            transport,
+            metrics: Default::default(),
         }
     }
 
+    // Count retries in the given metrics rather than a private set.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Client {
+        self.metrics = metrics;
+        self
+    }
+
     pub async fn run_get<
         T,
         U: IntoUrl,
@@ -26,12 +87,12 @@ impl Client {
         &self,
         url: U,
         resp_fn: Resp,
-    ) -> anyhow::Result<T> {
-        let u = url.into_url()?;
+    ) -> Result<T, ClientError> {
+        let u = url.into_url().map_err(ClientError::Request)?;
         self.run(move |client| client.get(u.clone()), resp_fn).await
     }
 
-    pub async fn get_bytes<U: IntoUrl>(&self, url: U) -> anyhow::Result<Vec<u8>> {
+    pub async fn get_bytes<U: IntoUrl>(&self, url: U) -> Result<Vec<u8>, ClientError> {
         self.run_get(url, |resp| async { Ok(resp.bytes().await?.into()) })
             .await
     }
@@ -45,36 +106,733 @@ impl Client {
         &self,
         req_fn: Req,
         resp_fn: Resp,
-    ) -> anyhow::Result<T> {
-        let mut last_err: anyhow::Error = anyhow::Error::msg("UNREACHABLE");
-        for i in 0..self.num_retries {
-            let client = self.client.read().await;
-            let builder = req_fn(&client).timeout(Duration::from_secs(30));
-            let result = builder.send().await;
-            match result {
+    ) -> Result<T, ClientError> {
+        if let Transport::Replay(dir) = &self.transport {
+            // Replayed responses never change, so there is nothing to retry.
+            let request = req_fn(&*self.client.read().await)
+                .build()
+                .map_err(ClientError::Request)?;
+            let url = request.url().clone();
+            let resp = Cassette::for_request(dir, &request)
+                .load()
+                .await
+                .map_err(|error| ClientError::Transport {
+                    url: url.clone(),
+                    error,
+                })?;
+            let resp = check_response(resp, &url).await?;
+            return resp_fn(resp)
+                .await
+                .map_err(|error| ClientError::Decode { url, error });
+        }
+
+        let num_retries = self.policy.num_retries.max(1);
+        let mut last_err = None;
+        for i in 0..num_retries {
+            // Clone the client rather than holding the lock for the whole
+            // request, so a hung request can't block a reset of the client.
+            let client = self.client.read().await.clone();
+            let request = req_fn(&client)
+                .timeout(Duration::from_secs(30))
+                .build()
+                .map_err(ClientError::Request)?;
+            let url = request.url().clone();
+            let host = url.host_str().unwrap_or_default().to_owned();
+            self.limiter.acquire(&host).await;
+            let cassette = if let Transport::Record(dir) = &self.transport {
+                Some(Cassette::for_request(dir, &request))
+            } else {
+                None
+            };
+            let err = match client.execute(request).await {
                 Err(e) => {
-                    last_err = e.into();
-                    drop(client);
                     let mut writer = self.client.write().await;
                     *writer.deref_mut() = reqwest::Client::new();
-                    drop(writer); // Explicitly unlock before sleeping
-                    if i + 1 < self.num_retries {
-                        sleep(Duration::from_secs(10)).await;
+                    ClientError::Transport {
+                        url,
+                        error: e.into(),
                     }
                 }
                 Ok(resp) => {
-                    let output = resp_fn(resp).await;
-                    match output {
-                        Err(e) => {
-                            last_err = e;
-                        }
-                        Ok(x) => {
-                            return Ok(x);
-                        }
+                    let resp =
+                        match cassette {
+                            Some(cassette) => cassette.record(resp).await.map_err(|error| {
+                                ClientError::Transport {
+                                    url: url.clone(),
+                                    error,
+                                }
+                            })?,
+                            None => resp,
+                        };
+                    match check_response(resp, &url).await {
+                        Ok(resp) => match resp_fn(resp).await {
+                            Ok(x) => return Ok(x),
+                            Err(error) => ClientError::Decode { url, error },
+                        },
+                        Err(e) => e,
                     }
                 }
             };
+            match &err {
+                ClientError::Blocked { .. } => {
+                    // Give the host a rest before anybody else asks it for
+                    // anything, since retrying would only make things worse.
+                    self.limiter
+                        .pause(&host, self.policy.max_retry_backoff)
+                        .await;
+                    return Err(err);
+                }
+                ClientError::Status {
+                    status,
+                    retry_after,
+                    ..
+                } => {
+                    if *status == StatusCode::TOO_MANY_REQUESTS
+                        || *status == StatusCode::SERVICE_UNAVAILABLE
+                    {
+                        // Hold back every request to the host, not just this one.
+                        let delay = retry_after
+                            .unwrap_or_default()
+                            .max(self.backoff(i))
+                            .min(self.policy.max_retry_backoff);
+                        self.limiter.pause(&host, delay).await;
+                    } else if status.is_server_error() || *status == StatusCode::REQUEST_TIMEOUT {
+                        if i + 1 < num_retries {
+                            sleep(self.backoff(i)).await;
+                        }
+                    } else {
+                        return Err(err);
+                    }
+                }
+                // No response was received, or it could not be decoded,
+                // perhaps because it was cut short.
+                _ => {
+                    if i + 1 < num_retries {
+                        sleep(self.backoff(i)).await;
+                    }
+                }
+            }
+            if i + 1 < num_retries {
+                self.metrics.record_retry(&host, err.kind());
+            }
+            last_err = Some(err);
+        }
+        Err(last_err.expect("at least one attempt should be made"))
+    }
+
+    // Get a randomized delay before the given retry attempt.
+    fn backoff(&self, attempt: i32) -> Duration {
+        let max = self.policy.max_retry_backoff;
+        let delay = self
+            .policy
+            .retry_backoff
+            .saturating_mul(1 << attempt.clamp(0, 16))
+            .min(max);
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+// The ways in which a request can fail.
+#[derive(Debug)]
+pub enum ClientError {
+    // The request could not be built, such as when the URL is invalid.
+    Request(reqwest::Error),
+
+    // No response was received.
+    Transport {
+        url: Url,
+        error: anyhow::Error,
+    },
+
+    // The server responded with an error status.
+    Status {
+        url: Url,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        snippet: String,
+    },
+
+    // The response could not be turned into a result.
+    Decode {
+        url: Url,
+        error: anyhow::Error,
+    },
+
+    // The server responded with a captcha or a block page.
+    Blocked {
+        url: Url,
+        reason: String,
+    },
+}
+
+impl ClientError {
+    // A short name for the kind of failure, for use in metrics.
+    fn kind(&self) -> &'static str {
+        match self {
+            ClientError::Request(_) => "request",
+            ClientError::Transport { .. } => "transport",
+            ClientError::Status { .. } => "status",
+            ClientError::Decode { .. } => "decode",
+            ClientError::Blocked { .. } => "blocked",
+        }
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Request(_) => write!(f, "invalid request"),
+            ClientError::Transport { url, .. } => write!(f, "request to {} failed", url),
+            ClientError::Status {
+                url,
+                status,
+                snippet,
+                ..
+            } => write!(f, "{} responded with status {}: {}", url, status, snippet),
+            ClientError::Decode { url, .. } => {
+                write!(f, "failed to decode response from {}", url)
+            }
+            ClientError::Blocked { url, reason } => write!(f, "blocked by {}: {}", url, reason),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Request(e) => Some(e),
+            ClientError::Transport { error, .. } | ClientError::Decode { error, .. } => {
+                Some(error.as_ref())
+            }
+            _ => None,
+        }
+    }
+}
+
+// Text which indicates that a page is a captcha or a bot check rather than
+// the content we asked for. Ordinary pages can mention these too, so they
+// are only looked for in responses which are already suspicious.
+const BLOCK_MARKERS: [&str; 5] = [
+    "/errors/validatecaptcha",
+    "px-captcha",
+    "robot or human",
+    "are you a human",
+    "captcha-delivery",
+];
+
+// Titles of block pages. Like the markers, these are only looked for in
+// suspicious responses, since a product can have a similar name.
+const BLOCK_TITLES: [&str; 4] = [
+    "robot or human",
+    "robot check",
+    "are you a human",
+    "access denied",
+];
+
+// Only this much of an error page is kept for logging.
+const SNIPPET_LENGTH: usize = 200;
+
+// Turn error statuses and block pages into errors, so that the caller only
+// sees the responses it asked for.
+async fn check_response(
+    resp: reqwest::Response,
+    requested_url: &Url,
+) -> Result<reqwest::Response, ClientError> {
+    let url = resp.url().clone();
+    let status = resp.status();
+    if url.path().starts_with("/blocked") {
+        return Err(ClientError::Blocked {
+            url,
+            reason: "redirected to a block page".to_owned(),
+        });
+    }
+    let is_html = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.starts_with("text/html"))
+        .unwrap_or_default();
+    if status.is_success() && !is_html {
+        return Ok(resp);
+    }
+
+    let retry_after = retry_after(&resp);
+    let headers = resp.headers().clone();
+    let body = resp.bytes().await.map_err(|e| ClientError::Transport {
+        url: url.clone(),
+        error: e.into(),
+    })?;
+    let text = String::from_utf8_lossy(&body).to_lowercase();
+    let suspicious = status == StatusCode::FORBIDDEN
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || url != *requested_url;
+    if suspicious {
+        if let Some(marker) = BLOCK_MARKERS.iter().find(|x| text.contains(*x)) {
+            return Err(ClientError::Blocked {
+                url,
+                reason: format!("captcha page ({})", marker),
+            });
+        }
+        if let Some(title) = page_title(&text) {
+            if BLOCK_TITLES.iter().any(|x| title.contains(x)) {
+                return Err(ClientError::Blocked {
+                    url,
+                    reason: format!("block page ({})", title),
+                });
+            }
+        }
+    }
+    if status == StatusCode::FORBIDDEN {
+        return Err(ClientError::Blocked {
+            url,
+            reason: "access forbidden".to_owned(),
+        });
+    }
+    if !status.is_success() {
+        let snippet = String::from_utf8_lossy(&body)
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .chars()
+            .take(SNIPPET_LENGTH)
+            .collect();
+        return Err(ClientError::Status {
+            url,
+            status,
+            retry_after,
+            snippet,
+        });
+    }
+    Ok(build_response(
+        &url,
+        status.as_u16(),
+        headers
+            .iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_owned()))),
+        body.to_vec(),
+    )
+    .expect("rebuilding a valid response should not fail"))
+}
+
+// Get the trimmed contents of a page's <title> element.
+fn page_title(html: &str) -> Option<&str> {
+    let start = html.find("<title")?;
+    let start = start + html[start..].find('>')? + 1;
+    let end = start + html[start..].find("</title")?;
+    Some(html[start..end].trim())
+}
+
+fn build_response<I: IntoIterator<Item = (String, String)>>(
+    url: &Url,
+    status: u16,
+    headers: I,
+    body: Vec<u8>,
+) -> anyhow::Result<reqwest::Response> {
+    let mut builder = http::Response::builder().status(status).url(url.clone());
+    for (k, v) in headers {
+        builder = builder.header(k, v);
+    }
+    Ok(builder.body(body)?.into())
+}
+
+// Parse a Retry-After header, which may be a number of seconds or a date.
+fn retry_after(resp: &reqwest::Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        Some(Duration::from_secs(seconds))
+    } else {
+        let date = httpdate::parse_http_date(value).ok()?;
+        Some(
+            date.duration_since(std::time::SystemTime::now())
+                .unwrap_or_default(),
+        )
+    }
+}
+
+// A recorded response, stored as a metadata file next to a raw body file.
+// Both files are named after a hash of the request.
+struct Cassette {
+    metadata_path: PathBuf,
+    body_path: PathBuf,
+    method: String,
+    url: Url,
+}
+
+#[derive(Deserialize, Serialize)]
+struct CassetteMetadata {
+    method: String,
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+impl Cassette {
+    fn for_request(dir: &Path, request: &reqwest::Request) -> Cassette {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(request.method().as_str());
+        hasher.update(" ");
+        hasher.update(request.url().as_str());
+        if let Some(body) = request.body().and_then(|x| x.as_bytes()) {
+            hasher.update("\n");
+            hasher.update(body);
+        }
+        let mut name = String::with_capacity(32);
+        for ch in &hasher.finalize()[0..16] {
+            write!(&mut name, "{:02x}", ch).unwrap();
+        }
+        Cassette {
+            metadata_path: dir.join(format!("{}.json", name)),
+            body_path: dir.join(format!("{}.body", name)),
+            method: request.method().to_string(),
+            url: request.url().clone(),
+        }
+    }
+
+    // Save a live response, and return an equivalent response to use in its
+    // place.
+    //
+    // The URL that is saved is the one the response came from, which differs
+    // from the requested URL if the request was redirected.
+    async fn record(self, resp: reqwest::Response) -> anyhow::Result<reqwest::Response> {
+        let metadata = CassetteMetadata {
+            method: self.method.clone(),
+            url: resp.url().to_string(),
+            status: resp.status().as_u16(),
+            headers: resp
+                .headers()
+                .iter()
+                .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_owned())))
+                .collect(),
+        };
+        let body = resp.bytes().await?;
+        if let Some(parent) = self.metadata_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&self.body_path, &body).await?;
+        tokio::fs::write(&self.metadata_path, serde_json::to_vec_pretty(&metadata)?).await?;
+        self.response(metadata, body.to_vec())
+    }
+
+    async fn load(self) -> anyhow::Result<reqwest::Response> {
+        let metadata = match tokio::fs::read(&self.metadata_path).await {
+            Ok(x) => serde_json::from_slice::<CassetteMetadata>(&x)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(anyhow::Error::msg(format!(
+                    "no recorded response for {} {}",
+                    self.method, self.url
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let body = tokio::fs::read(&self.body_path).await?;
+        self.response(metadata, body)
+    }
+
+    fn response(
+        &self,
+        metadata: CassetteMetadata,
+        body: Vec<u8>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let url = Url::parse(&metadata.url)?;
+        build_response(&url, metadata.status, metadata.headers, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use tokio::task::JoinHandle;
+
+    use super::*;
+
+    fn test_policy() -> ClientPolicy {
+        ClientPolicy {
+            num_retries: 3,
+            retry_backoff: Duration::from_millis(10),
+            max_retry_backoff: Duration::from_millis(100),
+            ..Default::default()
+        }
+    }
+
+    // Serve responses from a handler which is passed the request path and the
+    // number of earlier requests, returning the base URL and a request count.
+    fn serve(
+        handler: fn(&str, usize) -> Response<Body>,
+    ) -> (String, Arc<AtomicUsize>, JoinHandle<hyper::Result<()>>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let count_clone = count.clone();
+        let make_service = make_service_fn(move |_conn| {
+            let count = count_clone.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let n = count.fetch_add(1, Ordering::SeqCst);
+                    let resp = handler(req.uri().path(), n);
+                    async move { Ok::<_, Infallible>(resp) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        (url, count, tokio::spawn(server))
+    }
+
+    fn html(status: u16, body: &'static str) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .header("content-type", "text/html; charset=utf-8")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[test]
+    fn backoff_jitter_bounds() {
+        let client = Client::new(
+            ClientPolicy {
+                retry_backoff: Duration::from_secs(10),
+                max_retry_backoff: Duration::from_secs(60),
+                ..Default::default()
+            },
+            Transport::Live,
+        );
+        for (attempt, full_delay) in [(0, 10), (1, 20), (2, 40), (3, 60), (40, 60)] {
+            let full_delay = Duration::from_secs(full_delay);
+            for _ in 0..100 {
+                let delay = client.backoff(attempt);
+                assert!(
+                    delay >= full_delay / 2,
+                    "{:?} for attempt {}",
+                    delay,
+                    attempt
+                );
+                assert!(delay <= full_delay, "{:?} for attempt {}", delay, attempt);
+            }
         }
-        Err(last_err)
+    }
+
+    #[test]
+    fn retry_after_formats() {
+        let url = Url::parse("https://example.com/").unwrap();
+        let parse = |value: &str| {
+            let resp = build_response(
+                &url,
+                429,
+                vec![("retry-after".to_owned(), value.to_owned())],
+                Vec::new(),
+            )
+            .unwrap();
+            retry_after(&resp)
+        };
+        assert_eq!(parse("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse(" 7 "), Some(Duration::from_secs(7)));
+
+        let later = std::time::SystemTime::now() + Duration::from_secs(300);
+        let delay = parse(&httpdate::fmt_http_date(later)).unwrap();
+        assert!(delay > Duration::from_secs(290) && delay <= Duration::from_secs(300));
+
+        // Dates in the past mean the request can be retried right away.
+        assert_eq!(parse("Wed, 21 Oct 2015 07:28:00 GMT"), Some(Duration::ZERO));
+        assert_eq!(parse("soon"), None);
+
+        let resp = build_response(&url, 429, Vec::new(), Vec::new()).unwrap();
+        assert_eq!(retry_after(&resp), None);
+    }
+
+    #[tokio::test]
+    async fn retry_after_throttling() {
+        let (url, count, server) = serve(|_, n| {
+            if n == 0 {
+                Response::builder()
+                    .status(429)
+                    .header("retry-after", "1")
+                    .body(Body::empty())
+                    .unwrap()
+            } else {
+                Response::new(Body::from("ok"))
+            }
+        });
+        let policy = ClientPolicy {
+            max_retry_backoff: Duration::from_secs(5),
+            ..test_policy()
+        };
+        let client = Client::new(policy, Transport::Live);
+        let start = std::time::Instant::now();
+        let body = client.get_bytes(&url).await.unwrap();
+        assert_eq!(body, b"ok");
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (url, count, server) = serve(|_, n| {
+            if n < 2 {
+                html(500, "<html><body>\n  Internal   error\n</body></html>")
+            } else {
+                html(200, "<html><body>fine</body></html>")
+            }
+        });
+        let client = Client::new(test_policy(), Transport::Live);
+        let body = client
+            .run_get(&url, |resp| async { Ok(resp.text().await?) })
+            .await
+            .unwrap();
+        assert_eq!(body, "<html><body>fine</body></html>");
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        let client = Client::new(
+            ClientPolicy {
+                num_retries: 1,
+                ..test_policy()
+            },
+            Transport::Live,
+        );
+        count.store(0, Ordering::SeqCst);
+        match client.get_bytes(&url).await {
+            Err(ClientError::Status {
+                status, snippet, ..
+            }) => {
+                assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+                assert_eq!(snippet, "<html><body> Internal error </body></html>");
+            }
+            x => panic!("unexpected result: {:?}", x),
+        }
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (url, count, server) = serve(|_, _| html(404, "not found"));
+        let client = Client::new(test_policy(), Transport::Live);
+        let result = client.get_bytes(&url).await;
+        assert!(matches!(result, Err(ClientError::Status { status, .. }) if status == 404));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn block_pages_are_detected() {
+        let (url, count, server) = serve(|path, _| match path {
+            "/captcha" => html(429, "<html><title>Robot Check</title></html>"),
+            "/redirect" => Response::builder()
+                .status(302)
+                .header("location", "/challenge")
+                .body(Body::empty())
+                .unwrap(),
+            "/challenge" => html(200, "<html><div id=\"px-captcha\"></div></html>"),
+            _ => html(403, "<html>Access Denied</html>"),
+        });
+        let client = Client::new(test_policy(), Transport::Live);
+        for path in ["/captcha", "/redirect", "/forbidden"] {
+            let result = client.get_bytes(format!("{}{}", url, path)).await;
+            assert!(
+                matches!(result, Err(ClientError::Blocked { .. })),
+                "unexpected result for {}: {:?}",
+                path,
+                result
+            );
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn markers_in_normal_pages_are_allowed() {
+        let (url, _, server) = serve(|_, _| {
+            html(
+                200,
+                "<html><title>Robot or Human? Party Game</title><body>Tired of cleaning? Are \
+                 you a human or a robot? This robot vacuum has a px-captcha sticker.</body></html>",
+            )
+        });
+        let client = Client::new(test_policy(), Transport::Live);
+        let body = client
+            .run_get(&url, |resp| async { Ok(resp.text().await?) })
+            .await
+            .unwrap();
+        assert!(body.contains("Party Game"));
+        server.abort();
+    }
+
+    #[test]
+    fn page_titles() {
+        assert_eq!(
+            page_title("<html><head><title lang=\"en\">\n  robot check\n</title>"),
+            Some("robot check")
+        );
+        assert_eq!(page_title("<html><body>no title</body></html>"), None);
+    }
+
+    #[tokio::test]
+    async fn decode_errors_keep_cause() {
+        let (url, count, server) = serve(|_, _| Response::new(Body::from("not json")));
+        let client = Client::new(test_policy(), Transport::Live);
+        let result = client
+            .run_get(&url, |resp| async {
+                Ok(serde_json::from_slice::<Vec<i64>>(&resp.bytes().await?)?)
+            })
+            .await;
+        let err = anyhow::Error::from(result.unwrap_err());
+        let message = format!("{:#}", err);
+        assert!(message.starts_with("failed to decode response from"));
+        assert!(message.contains("expected ident"));
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn record_then_replay() {
+        let dir = std::env::temp_dir().join(format!("cassettes-{}", rand::random::<u64>()));
+
+        let (base_url, _, server) = serve(|path, _| match path {
+            "/moved" => Response::builder()
+                .status(302)
+                .header("location", "/page?x=1")
+                .body(Body::empty())
+                .unwrap(),
+            _ => Response::builder()
+                .header("x-test", "yes")
+                .body(Body::from(format!("hello from {}", path)))
+                .unwrap(),
+        });
+        let url = format!("{}/page?x=1", base_url);
+        let moved_url = format!("{}/moved", base_url);
+        let recorder = Client::new(test_policy(), Transport::Record(dir.clone()));
+        let live = recorder.get_bytes(&url).await.unwrap();
+        assert_eq!(live, b"hello from /page");
+        recorder.get_bytes(&moved_url).await.unwrap();
+        server.abort();
+
+        let player = Client::new(test_policy(), Transport::Replay(dir.clone()));
+        let (header, final_url, body) = player
+            .run_get(&url, |resp| async {
+                Ok((
+                    resp.headers()["x-test"].to_str()?.to_owned(),
+                    resp.url().to_string(),
+                    resp.text().await?,
+                ))
+            })
+            .await
+            .unwrap();
+        assert_eq!(header, "yes");
+        assert_eq!(final_url, url);
+        assert_eq!(body, "hello from /page");
+
+        // Redirects are replayed as responses from the final URL.
+        let final_url = player
+            .run_get(&moved_url, |resp| async move { Ok(resp.url().to_string()) })
+            .await
+            .unwrap();
+        assert_eq!(final_url, url);
+
+        let missing = player.get_bytes(format!("{}&y=2", url)).await;
+        assert!(matches!(missing, Err(ClientError::Transport { .. })));
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
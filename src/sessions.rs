@@ -0,0 +1,108 @@
+use hyper::header::{HeaderValue, COOKIE, SET_COOKIE};
+use hyper::http::HeaderMap;
+use hyper::{Body, Request, Response};
+
+use crate::db::{Database, SESSION_LIFETIME};
+
+const COOKIE_NAME: &str = "session";
+
+// A player's session, identified by a cookie. Sessions remember which
+// listings have been shown so that players don't see repeats, and may be
+// signed in to an account.
+pub struct Session {
+    pub id: i64,
+    pub account_id: Option<i64>,
+    token: String,
+}
+
+impl Session {
+    // Find the session named by a request's cookie, if it exists.
+    pub async fn find(db: &Database, req: &Request<Body>) -> anyhow::Result<Option<Session>> {
+        match session_token(req.headers()) {
+            Some(token) => Ok(db
+                .find_session(token.clone())
+                .await?
+                .map(|(id, account_id)| Session {
+                    id,
+                    account_id,
+                    token,
+                })),
+            None => Ok(None),
+        }
+    }
+
+    // Find the session named by a request's cookie, or start a new one if the
+    // cookie is missing or refers to a session which has expired.
+    pub async fn find_or_create(db: &Database, req: &Request<Body>) -> anyhow::Result<Session> {
+        if let Some(session) = Session::find(db, req).await? {
+            return Ok(session);
+        }
+        let (id, token) = db.create_session().await?;
+        Ok(Session {
+            id,
+            account_id: None,
+            token,
+        })
+    }
+
+    // Sign the session in to an account, or out if account_id is None. This
+    // changes the token, so the cookie must be set again afterwards.
+    pub async fn set_account(
+        &mut self,
+        db: &Database,
+        account_id: Option<i64>,
+    ) -> anyhow::Result<()> {
+        self.token = db.set_session_account(self.id, account_id).await?;
+        self.account_id = account_id;
+        Ok(())
+    }
+
+    // Set the session cookie on a response, which also extends its expiry.
+    pub fn with_cookie(&self, mut resp: Response<Body>) -> Response<Body> {
+        let cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            COOKIE_NAME, self.token, SESSION_LIFETIME
+        );
+        resp.headers_mut()
+            .append(SET_COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        resp
+    }
+}
+
+// Get the session token from a request's cookies, ignoring values which
+// could not have been created by the server.
+fn session_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(';'))
+        .filter_map(|x| x.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE_NAME)
+        .map(|(_, value)| value)
+        .filter(|x| x.len() == 32 && x.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(|x| x.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_token_from_cookies() {
+        let token = "0123456789abcdef0123456789abcdef";
+        let mut headers = HeaderMap::new();
+        assert_eq!(session_token(&headers), None);
+
+        headers.insert(
+            COOKIE,
+            format!("theme=dark; session={}; other=1", token)
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(session_token(&headers), Some(token.to_owned()));
+
+        headers.insert(COOKIE, "session=../../etc".parse().unwrap());
+        assert_eq!(session_token(&headers), None);
+    }
+}
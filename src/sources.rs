@@ -1,13 +1,27 @@
-use crate::db::Listing;
-use crate::{amazon, log_async, target};
+use crate::db::{Listing, ListingChange, SourceRun};
+use crate::http_util::detect_image_mime;
+use crate::images::{ImageNormalizer, ImageRejection};
+use crate::jsonld::{self, Store};
+use crate::levels::LevelConfig;
+use crate::log::LogLevel;
+use crate::metrics::Metrics;
+use crate::{amazon, log_async, log_at_async, log_best_effort, log_warn_async, target, walmart};
 use crate::{db::Database, scraper::Client};
-use std::{future::Future, pin::Pin, time::Duration};
-use tokio::sync::mpsc::Receiver;
-use tokio::time::sleep;
+use futures_util::FutureExt;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{future::Future, pin::Pin, sync::Arc};
+use tokio::select;
+use tokio::sync::{mpsc::Receiver, watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout};
 
 // The frequency with which to check if a source needs to be updated.
 // This is not actually the interval of updates, which is determined by the
-// caller of update_sources_loop().
+// caller of SourceUpdater::update_loop().
 const LOOP_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 // A soft limit to prevent levels from growing unboundedly.
@@ -21,119 +35,762 @@ const AMAZON_RESULT_LIMIT: i64 = 50;
 // fetching too many pages of results.
 const TARGET_RESULT_LIMIT: i64 = 50;
 
+// This limit is applied to Walmart searches to prevent the scraper from
+// fetching too many pages of results.
+const WALMART_RESULT_LIMIT: i64 = 50;
+
+// This limit is applied to JSON-LD stores to prevent the scraper from
+// fetching too many product pages.
+const JSONLD_RESULT_LIMIT: i64 = 50;
+
+// An item produced by a scraper: either a listing, or a product which could
+// not be turned into a listing.
+pub enum ScrapedItem {
+    Listing(Listing),
+    Skipped(SkipReason),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SkipReason {
+    MissingDetails,
+    MissingPrice,
+    UnparseablePrice,
+    MissingImage,
+    UnusableImage(ImageRejection),
+    FailedRequest,
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::MissingDetails => write!(f, "missing details"),
+            SkipReason::MissingPrice => write!(f, "missing price"),
+            SkipReason::UnparseablePrice => write!(f, "unparseable price"),
+            SkipReason::MissingImage => write!(f, "missing image"),
+            SkipReason::UnusableImage(reason) => write!(f, "unusable image ({})", reason),
+            SkipReason::FailedRequest => write!(f, "failed request"),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SkipCounts(BTreeMap<SkipReason, usize>);
+
+impl SkipCounts {
+    fn add(&mut self, reason: SkipReason) {
+        *self.0.entry(reason).or_default() += 1;
+    }
+
+    pub fn total(&self) -> usize {
+        self.0.values().sum()
+    }
+}
+
+impl Display for SkipCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts = self
+            .0
+            .iter()
+            .map(|(reason, count)| format!("{} {}", count, reason))
+            .collect::<Vec<_>>();
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+// The number of listings stored during an update.
+#[derive(Clone, Copy, Default)]
+pub struct UpdateCounts {
+    pub added: i64,
+    pub updated: i64,
+}
+
+pub struct DryRunSummary {
+    pub listings: usize,
+    pub skipped: SkipCounts,
+}
+
 // A source of retail listing data.
 //
 // Each source implementation should have its own string identifier, which may
 // be scoped differently than an entire website.
 pub trait Source: Send + Sync {
     fn identifier(&self) -> String;
+
+    // The website which the source scrapes, used to limit how many sources
+    // talk to the same website at once.
+    fn website(&self) -> String;
+
+    // Fetch listings and store them in the database, keeping track of the
+    // stored listings in counts. The counts are updated as listings are
+    // stored, so they remain accurate if the update fails or is cancelled.
+    //
+    // If max_items is specified, it overrides the source's own limit on the
+    // number of listings to fetch.
     fn update_listings<'a>(
         &'a self,
         client: &'a Client,
         db: &'a Database,
+        max_items: Option<i64>,
+        counts: &'a mut UpdateCounts,
     ) -> Pin<Box<dyn 'a + Send + Sync + Future<Output = anyhow::Result<()>>>>;
+
+    // Fetch listings like update_listings(), but print them to stdout as JSON
+    // Lines instead of storing them.
+    fn dry_run<'a>(
+        &'a self,
+        client: &'a Client,
+        max_items: Option<i64>,
+    ) -> Pin<Box<dyn 'a + Send + Sync + Future<Output = anyhow::Result<DryRunSummary>>>>;
 }
 
 pub struct StreamingSearchSource<
-    F: 'static + Send + Sync + Fn(Client, String) -> Receiver<anyhow::Result<Listing>>,
+    F: 'static + Send + Sync + Fn(Client, String) -> Receiver<anyhow::Result<ScrapedItem>>,
 > {
     prefix: String,
+    website: String,
     category: String,
     max_items: i64,
+    images: ImageNormalizer,
     f: F,
 }
 
-impl<F: 'static + Send + Sync + Fn(Client, String) -> Receiver<anyhow::Result<Listing>>> Source
+impl<F: 'static + Send + Sync + Fn(Client, String) -> Receiver<anyhow::Result<ScrapedItem>>>
+    StreamingSearchSource<F>
+{
+    fn listings(&self, client: &Client, max_items: Option<i64>) -> UsableListings {
+        UsableListings {
+            items: (self.f)(client.clone(), self.category.clone()),
+            images: self.images.clone(),
+            remaining: max_items.unwrap_or(self.max_items),
+            skipped: Default::default(),
+        }
+    }
+}
+
+impl<F: 'static + Send + Sync + Fn(Client, String) -> Receiver<anyhow::Result<ScrapedItem>>> Source
     for StreamingSearchSource<F>
 {
     fn identifier(&self) -> String {
         format!("{}/{}", self.prefix, self.category.clone())
     }
 
+    fn website(&self) -> String {
+        self.website.clone()
+    }
+
     fn update_listings<'a>(
         &'a self,
         client: &'a Client,
         db: &'a Database,
+        max_items: Option<i64>,
+        counts: &'a mut UpdateCounts,
     ) -> Pin<Box<dyn 'a + Send + Sync + Future<Output = anyhow::Result<()>>>> {
         Box::pin(async move {
-            let mut listings = (self.f)(client.clone(), self.category.clone());
-            let mut count = 0;
-            while let Some(result) = listings.recv().await {
-                let listing = result?;
-                db.insert_or_update(listing).await?;
-                count += 1;
-                if count >= self.max_items {
-                    break;
+            let mut listings = self.listings(client, max_items);
+            while let Some(result) = listings.next().await {
+                let (listing, _) = result?;
+                match db.insert_or_update(listing).await? {
+                    ListingChange::Inserted => counts.added += 1,
+                    ListingChange::Updated => counts.updated += 1,
                 }
             }
+            if listings.skipped.total() > 0 {
+                log_warn_async!(
+                    db,
+                    "skipped {} items from source {}: {}",
+                    listings.skipped.total(),
+                    self.identifier(),
+                    listings.skipped
+                );
+            }
             Ok(())
         })
     }
+
+    fn dry_run<'a>(
+        &'a self,
+        client: &'a Client,
+        max_items: Option<i64>,
+    ) -> Pin<Box<dyn 'a + Send + Sync + Future<Output = anyhow::Result<DryRunSummary>>>> {
+        Box::pin(async move {
+            let mut listings = self.listings(client, max_items);
+            let mut count = 0;
+            while let Some(result) = listings.next().await {
+                let (listing, image) = result?;
+                println!(
+                    "{}",
+                    serde_json::to_string(&DryRunListing::new(listing, image))?
+                );
+                count += 1;
+            }
+            Ok(DryRunSummary {
+                listings: count,
+                skipped: listings.skipped,
+            })
+        })
+    }
+}
+
+// The listings from a scraper which have usable images, up to a limit.
+struct UsableListings {
+    items: Receiver<anyhow::Result<ScrapedItem>>,
+    images: ImageNormalizer,
+    remaining: i64,
+    skipped: SkipCounts,
 }
 
-fn amazon_source(category: &str) -> Box<dyn Source> {
+impl UsableListings {
+    // Get the next listing with a normalized image, along with a description
+    // of the image as it was downloaded.
+    async fn next(&mut self) -> Option<anyhow::Result<(Listing, ImageInfo)>> {
+        while self.remaining > 0 {
+            match self.items.recv().await? {
+                Err(e) => return Some(Err(e)),
+                Ok(ScrapedItem::Skipped(reason)) => self.skipped.add(reason),
+                Ok(ScrapedItem::Listing(mut listing)) => {
+                    let image = ImageInfo::new(&listing.image_data);
+                    match self.images.normalize(listing.image_data).await {
+                        Ok(data) => {
+                            listing.image_data = data;
+                            self.remaining -= 1;
+                            return Some(Ok((listing, image)));
+                        }
+                        Err(reason) => self.skipped.add(SkipReason::UnusableImage(reason)),
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct ImageInfo {
+    size: usize,
+    mime: Option<&'static str>,
+}
+
+impl ImageInfo {
+    fn new(data: &[u8]) -> ImageInfo {
+        ImageInfo {
+            size: data.len(),
+            mime: detect_image_mime(data),
+        }
+    }
+}
+
+// A listing as printed by a dry run, with a description in place of the
+// image data.
+#[derive(Serialize)]
+struct DryRunListing {
+    website: String,
+    website_id: String,
+    price: i64,
+    title: String,
+    image: ImageInfo,
+    categories: Vec<String>,
+    star_rating: Option<f64>,
+    max_stars: Option<f64>,
+    num_reviews: Option<i64>,
+}
+
+impl DryRunListing {
+    fn new(listing: Listing, image: ImageInfo) -> DryRunListing {
+        DryRunListing {
+            website: listing.website,
+            website_id: listing.website_id,
+            price: listing.price,
+            title: listing.title,
+            image,
+            categories: listing.categories,
+            star_rating: listing.star_rating,
+            max_stars: listing.max_stars,
+            num_reviews: listing.num_reviews,
+        }
+    }
+}
+
+fn amazon_source(category: &str, images: &ImageNormalizer) -> Box<dyn Source> {
     Box::new(StreamingSearchSource {
         prefix: "azn".to_owned(),
+        website: "amazon.com".to_owned(),
         category: category.to_owned(),
         max_items: AMAZON_RESULT_LIMIT,
+        images: images.clone(),
         f: amazon::stream_category,
     })
 }
 
-fn target_source(category: &str) -> Box<dyn Source> {
+fn target_source(category: &str, images: &ImageNormalizer) -> Box<dyn Source> {
     Box::new(StreamingSearchSource {
         prefix: "tgt".to_owned(),
+        website: "target.com".to_owned(),
         category: category.to_owned(),
         max_items: TARGET_RESULT_LIMIT,
+        images: images.clone(),
         f: target::stream_category,
     })
 }
 
-pub fn default_sources() -> Vec<Box<dyn Source>> {
+fn walmart_source(category: &str, images: &ImageNormalizer) -> Box<dyn Source> {
+    Box::new(StreamingSearchSource {
+        prefix: "wmt".to_owned(),
+        website: "walmart.com".to_owned(),
+        category: category.to_owned(),
+        max_items: WALMART_RESULT_LIMIT,
+        images: images.clone(),
+        f: walmart::stream_category,
+    })
+}
+
+pub fn jsonld_source(store: Store, images: &ImageNormalizer) -> Box<dyn Source> {
+    Box::new(StreamingSearchSource {
+        prefix: "jsonld".to_owned(),
+        website: store.website.clone(),
+        category: store.id.clone(),
+        max_items: JSONLD_RESULT_LIMIT,
+        images: images.clone(),
+        f: move |client, _| jsonld::stream_store(client, store.clone()),
+    })
+}
+
+pub fn default_sources(images: ImageNormalizer) -> Vec<Box<dyn Source>> {
     let mut result = Vec::new();
     for (_, category) in amazon::CATEGORIES {
-        result.push(amazon_source(category));
+        result.push(amazon_source(category, &images));
     }
     for (_, category) in target::CATEGORIES {
-        result.push(target_source(category));
+        result.push(target_source(category, &images));
+    }
+    for (_, category) in walmart::CATEGORIES {
+        result.push(walmart_source(category, &images));
     }
     result
 }
 
-pub async fn update_sources_loop(
+// Limits on how many sources are updated at once, and for how long.
+#[derive(Clone)]
+pub struct UpdateLimits {
+    pub concurrency: usize,
+    pub website_concurrency: usize,
+    pub source_timeout: Duration,
+
+    // Overrides the number of listings each source fetches.
+    pub max_items: Option<i64>,
+}
+
+// Updates sources on demand while enforcing UpdateLimits across every
+// update, and never updates the same source twice at once.
+#[derive(Clone)]
+pub struct SourceUpdater {
     client: Client,
     db: Database,
-    update_interval: Duration,
-    sources: Vec<Box<dyn Source>>,
-) -> anyhow::Result<()> {
-    loop {
-        let mut updated_any: bool = false;
+    limits: UpdateLimits,
+    sources: Arc<Vec<Arc<dyn Source>>>,
+    all_permits: Arc<Semaphore>,
+    website_permits: Arc<HashMap<String, Arc<Semaphore>>>,
+    running: Arc<std::sync::Mutex<HashSet<String>>>,
+    metrics: Metrics,
+
+    // Set to true once the updater is shutting down.
+    stop: Arc<watch::Sender<bool>>,
+}
+
+impl SourceUpdater {
+    pub fn new(
+        client: Client,
+        db: Database,
+        limits: UpdateLimits,
+        sources: Vec<Box<dyn Source>>,
+    ) -> SourceUpdater {
+        let sources: Vec<Arc<dyn Source>> = sources.into_iter().map(Arc::from).collect();
+        let mut website_permits = HashMap::new();
         for source in &sources {
+            website_permits
+                .entry(source.website())
+                .or_insert_with(|| Arc::new(Semaphore::new(limits.website_concurrency.max(1))));
+        }
+        SourceUpdater {
+            client,
+            db,
+            all_permits: Arc::new(Semaphore::new(limits.concurrency.max(1))),
+            limits,
+            sources: Arc::new(sources),
+            website_permits: Arc::new(website_permits),
+            running: Default::default(),
+            metrics: Default::default(),
+            stop: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    // Record the outcome of each update in the given metrics.
+    pub fn with_metrics(mut self, metrics: Metrics) -> SourceUpdater {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn sources(&self) -> &[Arc<dyn Source>] {
+        &self.sources
+    }
+
+    pub fn find(&self, id: &str) -> Option<Arc<dyn Source>> {
+        self.sources.iter().find(|x| x.identifier() == id).cloned()
+    }
+
+    // Check if a source is being updated or waiting to be updated.
+    pub fn is_running(&self, id: &str) -> bool {
+        self.running.lock().unwrap().contains(id)
+    }
+
+    // Stop starting updates and interrupt the ones in progress, then wait for
+    // them to record their results. A listing which is being written when
+    // the update is interrupted is still written in full.
+    pub async fn shutdown(&self) {
+        self.stop.send_replace(true);
+
+        // Updates hold a permit until they are done with the database.
+        if let Ok(permits) = self
+            .all_permits
+            .acquire_many(self.limits.concurrency.max(1) as u32)
+            .await
+        {
+            permits.forget();
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        *self.stop.borrow()
+    }
+
+    // Wait until shutdown() is called.
+    async fn stopped(&self) {
+        let mut stop = self.stop.subscribe();
+        while !*stop.borrow() {
+            if stop.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Update each of the sources once, returning the number of sources which
+    // failed or timed out. Sources which are already being updated are
+    // skipped.
+    pub async fn update(&self, sources: Vec<Arc<dyn Source>>) -> usize {
+        let mut tasks = JoinSet::new();
+        for source in sources {
             let id = source.identifier();
-            if db
-                .should_update_source(id.clone(), update_interval.as_secs_f64().ceil() as i64)
+            if !self.running.lock().unwrap().insert(id.clone()) {
+                continue;
+            }
+            let updater = self.clone();
+            tasks.spawn(async move {
+                // Catch panics so that the source is never stuck as running.
+                let result = AssertUnwindSafe(updater.update_source(source))
+                    .catch_unwind()
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow::Error::msg("update panicked")));
+                updater.running.lock().unwrap().remove(&id);
+                match result {
+                    Ok(success) => success,
+                    Err(e) => {
+                        updater.record_failure(&id, e).await;
+                        false
+                    }
+                }
+            });
+        }
+        let mut failures = 0;
+        while let Some(result) = tasks.join_next().await {
+            if !result.unwrap_or_default() {
+                failures += 1;
+            }
+        }
+        failures
+    }
+
+    // Update every source which has not been updated within the interval,
+    // until the updater is shut down.
+    //
+    // Errors, such as the database being briefly unavailable, are logged
+    // and the sources are checked again later.
+    pub async fn update_loop(&self, levels: Arc<LevelConfig>, update_interval: Duration) {
+        while !self.is_stopped() {
+            if let Err(e) = self.update_due(&levels, update_interval).await {
+                log_best_effort!(
+                    &self.db,
+                    LogLevel::Error,
+                    "error in source update loop: {:#}",
+                    e
+                );
+            }
+            select! {
+                _ = sleep(LOOP_CHECK_INTERVAL) => (),
+                _ = self.stopped() => (),
+            }
+        }
+    }
+
+    async fn update_due(
+        &self,
+        levels: &LevelConfig,
+        update_interval: Duration,
+    ) -> anyhow::Result<()> {
+        let mut due = Vec::new();
+        for source in self.sources.iter() {
+            if self
+                .db
+                .should_update_source(
+                    source.identifier(),
+                    update_interval.as_secs_f64().ceil() as i64,
+                )
                 .await?
             {
-                log_async!(&db, "updating source {}", id);
-                if let Err(e) = source.update_listings(&client, &db).await {
-                    log_async!(&db, "error updating source {}: {}", id, e);
-                } else {
-                    log_async!(&db, "successfully updated source {}", id);
-                }
-                updated_any = true;
-                db.updated_source(id).await?;
+                due.push(source.clone());
+            }
+        }
+        if !due.is_empty() {
+            self.update(due).await;
+            if !self.is_stopped() {
+                delete_old_listings(&self.db, levels).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Record an update which failed before its outcome could be recorded by
+    // update_source(), such as when the database was briefly unavailable.
+    async fn record_failure(&self, id: &str, err: anyhow::Error) {
+        let error = format!("{:#}", err);
+        self.metrics.record_source_update(id, false, Duration::ZERO);
+        log_best_effort!(
+            &self.db,
+            LogLevel::Error,
+            "error updating source {}: {}",
+            id,
+            error
+        );
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let result = self
+            .db
+            .record_source_run(SourceRun {
+                source_id: id.to_owned(),
+                started,
+                duration_ms: 0,
+                error: Some(error),
+                listings_added: 0,
+                listings_updated: 0,
+            })
+            .await;
+        if let Err(e) = result {
+            eprintln!("failed to record update of source {}: {}", id, e);
+        }
+    }
+
+    async fn update_source(&self, source: Arc<dyn Source>) -> anyhow::Result<bool> {
+        // Wait for the website before taking a global slot, so that sources
+        // waiting on a busy website don't hold up other websites.
+        let permits = async {
+            let website_permit = self.website_permits[&source.website()]
+                .clone()
+                .acquire_owned()
+                .await?;
+            let permit = self.all_permits.clone().acquire_owned().await?;
+            anyhow::Ok((website_permit, permit))
+        };
+        let _permits = select! {
+            biased;
+            _ = self.stopped() => return Ok(false),
+            permits = permits => permits?,
+        };
+
+        let id = source.identifier();
+        let db = &self.db;
+        log_async!(db, "updating source {}", id);
+        let started = SystemTime::now();
+        let start_instant = Instant::now();
+        let mut counts = UpdateCounts::default();
+        let update = timeout(
+            self.limits.source_timeout,
+            source.update_listings(&self.client, db, self.limits.max_items, &mut counts),
+        );
+        let result = select! {
+            biased;
+            _ = self.stopped() => None,
+            result = update => Some(result),
+        };
+        let error = match &result {
+            None => Some("interrupted by shutdown".to_owned()),
+            Some(Err(_)) => Some(format!(
+                "timed out after {} seconds",
+                self.limits.source_timeout.as_secs()
+            )),
+            Some(Ok(Err(e))) => Some(format!("{:#}", e)),
+            Some(Ok(Ok(_))) => None,
+        };
+        let duration = start_instant.elapsed();
+        self.metrics
+            .record_source_update(&id, error.is_none(), duration);
+        match (&result, &error) {
+            (None, _) => log_warn_async!(db, "interrupted update of source {}", id),
+            (_, Some(e)) => {
+                log_at_async!(db, LogLevel::Error, "error updating source {}: {}", id, e)
+            }
+            (_, None) => log_async!(db, "successfully updated source {}", id),
+        }
+        db.record_source_run(SourceRun {
+            source_id: id.clone(),
+            started: started.duration_since(UNIX_EPOCH)?.as_secs() as i64,
+            duration_ms: duration.as_millis() as i64,
+            error: error.clone(),
+            listings_added: counts.added,
+            listings_updated: counts.updated,
+        })
+        .await?;
+        // An interrupted source should be updated again as soon as possible.
+        if result.is_some() {
+            db.updated_source(id).await?;
+        }
+        Ok(error.is_none())
+    }
+}
+
+// Print the listings from each source without storing them, along with a
+// summary of the items which were skipped, returning the number of sources
+// which failed.
+pub async fn dry_run_sources(
+    client: &Client,
+    sources: Vec<Arc<dyn Source>>,
+    max_items: Option<i64>,
+) -> usize {
+    let mut failures = 0;
+    for source in sources {
+        let id = source.identifier();
+        match source.dry_run(client, max_items).await {
+            Ok(summary) if summary.skipped.total() > 0 => eprintln!(
+                "{}: {} listings, {} skipped ({})",
+                id,
+                summary.listings,
+                summary.skipped.total(),
+                summary.skipped
+            ),
+            Ok(summary) => eprintln!("{}: {} listings, 0 skipped", id, summary.listings),
+            Err(e) => {
+                eprintln!("{}: error: {:#}", id, e);
+                failures += 1;
             }
         }
-        if updated_any {
-            let delete_counts = db.delete_old_listings(MAX_LISTINGS_PER_LEVEL).await?;
-            log_async!(
-                &db,
-                "ran delete cycle: {} listings, {} blobs, and {} categories deleted.",
-                delete_counts.listings,
-                delete_counts.blobs,
-                delete_counts.categories
-            );
-        }
-        sleep(LOOP_CHECK_INTERVAL).await;
+    }
+    failures
+}
+
+pub async fn delete_old_listings(db: &Database, levels: &LevelConfig) -> anyhow::Result<()> {
+    let delete_counts = db
+        .delete_old_listings(MAX_LISTINGS_PER_LEVEL, levels.levels.clone())
+        .await?;
+    log_async!(
+        db,
+        "ran delete cycle: {} listings, {} blobs, {} categories, and {} rounds deleted.",
+        delete_counts.listings,
+        delete_counts.blobs,
+        delete_counts.categories,
+        delete_counts.rounds
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scraper::{ClientPolicy, Transport};
+
+    struct TestSource {
+        id: &'static str,
+        panics: bool,
+    }
+
+    impl Source for TestSource {
+        fn identifier(&self) -> String {
+            self.id.to_owned()
+        }
+
+        fn website(&self) -> String {
+            "example.com".to_owned()
+        }
+
+        fn update_listings<'a>(
+            &'a self,
+            _client: &'a Client,
+            _db: &'a Database,
+            _max_items: Option<i64>,
+            _counts: &'a mut UpdateCounts,
+        ) -> Pin<Box<dyn 'a + Send + Sync + Future<Output = anyhow::Result<()>>>> {
+            Box::pin(async move {
+                if self.panics {
+                    panic!("source {} panicked", self.id);
+                }
+                Ok(())
+            })
+        }
+
+        fn dry_run<'a>(
+            &'a self,
+            _client: &'a Client,
+            _max_items: Option<i64>,
+        ) -> Pin<Box<dyn 'a + Send + Sync + Future<Output = anyhow::Result<DryRunSummary>>>>
+        {
+            Box::pin(async {
+                Ok(DryRunSummary {
+                    listings: 0,
+                    skipped: Default::default(),
+                })
+            })
+        }
+    }
+
+    fn test_updater(db: Database) -> SourceUpdater {
+        let sources: Vec<Box<dyn Source>> = vec![
+            Box::new(TestSource {
+                id: "ok",
+                panics: false,
+            }),
+            Box::new(TestSource {
+                id: "panics",
+                panics: true,
+            }),
+        ];
+        SourceUpdater::new(
+            Client::new(ClientPolicy::default(), Transport::Live),
+            db,
+            UpdateLimits {
+                concurrency: 2,
+                website_concurrency: 2,
+                source_timeout: Duration::from_secs(10),
+                max_items: None,
+            },
+            sources,
+        )
+    }
+
+    #[tokio::test]
+    async fn failed_updates_are_recorded() {
+        let db = Database::open_in_memory().await.unwrap();
+        let updater = test_updater(db.clone());
+        assert_eq!(updater.update(updater.sources().to_vec()).await, 1);
+        assert!(!updater.is_running("panics"));
+        let health = db.source_health().await.unwrap();
+        assert!(health["ok"].last_error.is_none());
+        assert_eq!(
+            health["panics"].last_error.as_ref().unwrap().1,
+            "update panicked"
+        );
+
+        // Errors from the database are recorded as failures rather than
+        // panicking, even if they can't be written anywhere.
+        db.close().await.unwrap();
+        assert_eq!(updater.update(updater.sources().to_vec()).await, 2);
+        assert!(!updater.is_running("ok"));
     }
 }
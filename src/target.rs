@@ -1,15 +1,17 @@
 use std::collections::HashMap;
-use std::time::Duration;
 
 use regex::Regex;
 use serde::Deserialize;
-use tokio::time::sleep;
 use tokio::{
     spawn,
     sync::mpsc::{channel, Receiver},
 };
 
-use crate::{db::Listing, scraper::Client};
+use crate::{
+    db::Listing,
+    scraper::Client,
+    sources::{ScrapedItem, SkipReason},
+};
 
 pub const CATEGORIES: [(&str, &str); 21] = [
     ("Gift Ideas", "96d2i"),
@@ -36,7 +38,10 @@ pub const CATEGORIES: [(&str, &str); 21] = [
     // ("Shop All Categories", "5xsxf"),
 ];
 
-pub fn stream_category(client: Client, category_id: String) -> Receiver<anyhow::Result<Listing>> {
+pub fn stream_category(
+    client: Client,
+    category_id: String,
+) -> Receiver<anyhow::Result<ScrapedItem>> {
     let (tx, rx) = channel(1);
     spawn(async move {
         match extract_search_keys(&client).await {
@@ -60,21 +65,19 @@ pub fn stream_category(client: Client, category_id: String) -> Receiver<anyhow::
                             Ok(serde_json::from_slice::<'_, SearchResult>(&data)?)
                         })
                         .await;
-                    sleep(Duration::from_secs(10)).await;
                     match page_results {
                         Ok(results) => {
-                            if results.data.search.products.len() == 0 {
+                            if results.data.search.products.is_empty() {
                                 return;
                             }
                             offset += results.data.search.products.len();
                             for item in results.data.search.products {
                                 match product_listing(&client, category_id.clone(), item).await {
-                                    Ok(Some(x)) => {
+                                    Ok(x) => {
                                         if tx.send(Ok(x)).await.is_err() {
                                             return;
                                         }
                                     }
-                                    Ok(None) => (),
                                     Err(e) => {
                                         tx.send(Err(e)).await.ok();
                                         return;
@@ -83,7 +86,7 @@ pub fn stream_category(client: Client, category_id: String) -> Receiver<anyhow::
                             }
                         }
                         Err(e) => {
-                            tx.send(Err(e)).await.ok();
+                            tx.send(Err(e.into())).await.ok();
                             return;
                         }
                     }
@@ -98,32 +101,32 @@ async fn product_listing(
     client: &Client,
     category: String,
     product: SearchResultProduct,
-) -> anyhow::Result<Option<Listing>> {
-    if let Ok(parsed_price) = (&product
+) -> anyhow::Result<ScrapedItem> {
+    let parsed_price = match product
         .price
         .formatted_current_price
-        .replace(&[',', '$'], ""))
+        .replace([',', '$'], "")
         .parse::<f64>()
     {
-        let image_data = client
-            .get_bytes(product.item.enrichment.images.primary_image_url)
-            .await?;
-        Ok(Some(Listing {
-            website: "target.com".to_owned(),
-            website_id: product.tcin,
-            price: (parsed_price * 100.0).round() as i64,
-            title: html_escape::decode_html_entities(&product.item.product_description.title)
-                .as_ref()
-                .to_owned(),
-            image_data: image_data,
-            categories: vec![category],
-            star_rating: None,
-            max_stars: None,
-            num_reviews: None,
-        }))
-    } else {
-        Ok(None)
-    }
+        Ok(x) => x,
+        Err(_) => return Ok(ScrapedItem::Skipped(SkipReason::UnparseablePrice)),
+    };
+    let image_data = client
+        .get_bytes(product.item.enrichment.images.primary_image_url)
+        .await?;
+    Ok(ScrapedItem::Listing(Listing {
+        website: "target.com".to_owned(),
+        website_id: product.tcin,
+        price: (parsed_price * 100.0).round() as i64,
+        title: html_escape::decode_html_entities(&product.item.product_description.title)
+            .as_ref()
+            .to_owned(),
+        image_data,
+        categories: vec![category],
+        star_rating: None,
+        max_stars: None,
+        num_reviews: None,
+    }))
 }
 
 #[derive(Deserialize)]
@@ -184,7 +187,7 @@ struct SearchKeys {
 }
 
 async fn extract_search_keys(client: &Client) -> anyhow::Result<SearchKeys> {
-    client
+    Ok(client
         .run_get("https://www.target.com", |resp| async {
             let response = resp.text().await?;
             let prefix = "Object.defineProperties(window, {";
@@ -218,7 +221,7 @@ async fn extract_search_keys(client: &Client) -> anyhow::Result<SearchKeys> {
                     .entry(key.get(1).unwrap().as_str().to_owned())
                     .or_insert(0) += 1;
             }
-            if counts.len() == 0 {
+            if counts.is_empty() {
                 return Err(anyhow::Error::msg(
                     "no api keys found in Target homepage config",
                 ));
@@ -244,5 +247,63 @@ async fn extract_search_keys(client: &Client) -> anyhow::Result<SearchKeys> {
                 visitor_id: vid.as_str().to_owned(),
             })
         })
-        .await
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::scraper::{ClientPolicy, Transport};
+
+    fn replay_client() -> Client {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/cassettes/target");
+        Client::new(ClientPolicy::default(), Transport::Replay(dir))
+    }
+
+    #[tokio::test]
+    async fn extract_search_keys_from_homepage() {
+        let keys = extract_search_keys(&replay_client()).await.unwrap();
+        assert_eq!(keys.api_key, "9f36aeafbe60771e321a7cc95a78140772ab3e96");
+        assert_eq!(keys.visitor_id, "0185A1B2C3D4E5F60718293A4B5C6D7E");
+    }
+
+    #[tokio::test]
+    async fn stream_category_pages() {
+        let mut rx = stream_category(replay_client(), "5xt85".to_owned());
+        let mut listings = Vec::new();
+        let mut skipped = Vec::new();
+        while let Some(item) = rx.recv().await {
+            match item.unwrap() {
+                ScrapedItem::Listing(x) => listings.push(x),
+                ScrapedItem::Skipped(reason) => skipped.push(reason),
+            }
+        }
+        let summary = listings
+            .iter()
+            .map(|x| {
+                (
+                    x.website_id.as_str(),
+                    x.price,
+                    x.title.as_str(),
+                    x.image_data.as_slice(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "80001",
+                    1299,
+                    "Camping Lantern & Hook",
+                    b"lantern image".as_slice()
+                ),
+                ("80003", 10000, "2-Person Tent", b"tent image"),
+            ]
+        );
+        assert_eq!(skipped, vec![SkipReason::UnparseablePrice]);
+        assert_eq!(listings[0].categories, vec!["5xt85"]);
+    }
 }
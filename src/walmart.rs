@@ -0,0 +1,359 @@
+use regex::Regex;
+use serde::Deserialize;
+use tokio::{
+    spawn,
+    sync::mpsc::{channel, Receiver},
+};
+
+use crate::{
+    db::Listing,
+    scraper::{Client, ClientError},
+    sources::{ScrapedItem, SkipReason},
+};
+
+pub const CATEGORIES: [(&str, &str); 14] = [
+    ("Electronics", "3944"),
+    ("Home", "4044"),
+    ("Toys", "4171"),
+    ("Sports & Outdoors", "4125"),
+    ("Clothing", "5438"),
+    ("Baby", "5427"),
+    ("Pets", "5440"),
+    ("Patio & Garden", "5428"),
+    ("Video Games", "2636"),
+    ("Home Improvement", "1072864"),
+    ("Auto & Tires", "91083"),
+    ("Office Supplies", "1229749"),
+    ("Arts, Crafts & Sewing", "1334134"),
+    ("Household Essentials", "1115193"),
+];
+
+// Walmart will not serve browse pages beyond this page number.
+const MAX_PAGES: usize = 25;
+
+pub fn stream_category(
+    client: Client,
+    category_id: String,
+) -> Receiver<anyhow::Result<ScrapedItem>> {
+    let (tx, rx) = channel(1);
+    spawn(async move {
+        let mut page_num = 1;
+        loop {
+            let page = client
+                .run_get(browse_url(&category_id, page_num), |resp| async {
+                    parse_browse_page(&resp.text().await?)
+                })
+                .await;
+            match page {
+                Ok(page) => {
+                    if page.items.is_empty() && page.skipped.is_empty() {
+                        return;
+                    }
+                    for reason in page.skipped {
+                        if tx.send(Ok(ScrapedItem::Skipped(reason))).await.is_err() {
+                            return;
+                        }
+                    }
+                    for item in page.items {
+                        // One missing image shouldn't lose the rest of the
+                        // category, but there's no point carrying on once
+                        // we're blocked.
+                        let item = match product_listing(&client, category_id.clone(), item).await {
+                            Ok(x) => ScrapedItem::Listing(x),
+                            Err(e @ ClientError::Blocked { .. }) => {
+                                tx.send(Err(e.into())).await.ok();
+                                return;
+                            }
+                            Err(_) => ScrapedItem::Skipped(SkipReason::FailedRequest),
+                        };
+                        if tx.send(Ok(item)).await.is_err() {
+                            return;
+                        }
+                    }
+                    if page_num >= page.max_page.min(MAX_PAGES) {
+                        return;
+                    }
+                    page_num += 1;
+                }
+                Err(e) => {
+                    tx.send(Err(e.into())).await.ok();
+                    return;
+                }
+            }
+        }
+    });
+    rx
+}
+
+fn browse_url(category_id: &str, page_num: usize) -> String {
+    format!(
+        "https://www.walmart.com/browse/{}?page={}",
+        category_id, page_num
+    )
+}
+
+async fn product_listing(
+    client: &Client,
+    category: String,
+    item: BrowseItem,
+) -> Result<Listing, ClientError> {
+    let image_data = client.get_bytes(item.image_url).await?;
+    Ok(Listing {
+        website: "walmart.com".to_owned(),
+        website_id: item.id,
+        price: item.price,
+        title: item.title,
+        image_data,
+        categories: vec![category],
+        star_rating: item.star_rating,
+        max_stars: item.star_rating.map(|_| 5.0),
+        num_reviews: item.num_reviews,
+    })
+}
+
+// A page of browse results, with only the items that can become listings.
+#[derive(Debug)]
+struct BrowsePage {
+    items: Vec<BrowseItem>,
+    skipped: Vec<SkipReason>,
+    max_page: usize,
+}
+
+#[derive(Debug, PartialEq)]
+struct BrowseItem {
+    id: String,
+    title: String,
+    price: i64,
+    image_url: String,
+    star_rating: Option<f64>,
+    num_reviews: Option<i64>,
+}
+
+// Extract the search results from the JSON which Walmart embeds in browse
+// pages for client-side rendering.
+fn parse_browse_page(html: &str) -> anyhow::Result<BrowsePage> {
+    let pattern = Regex::new(r#"(?s)<script id="__NEXT_DATA__"[^>]*>(.*?)</script>"#).unwrap();
+    let data = pattern
+        .captures(html)
+        .ok_or(anyhow::Error::msg("walmart page data not found"))?
+        .get(1)
+        .unwrap();
+    let next_data: NextData = serde_json::from_str(data.as_str())?;
+    let result = next_data.props.page_props.initial_data.search_result;
+    let mut items = Vec::new();
+    let mut skipped = Vec::new();
+    for item in result.item_stacks.into_iter().flat_map(|x| x.items) {
+        // Ads and other placeholders are not products, so they are not
+        // counted as skipped.
+        if item.typename.as_deref() != Some("Product") {
+            continue;
+        }
+        match BrowseItem::from_result(item) {
+            Ok(x) => items.push(x),
+            Err(reason) => skipped.push(reason),
+        }
+    }
+    Ok(BrowsePage {
+        items,
+        skipped,
+        max_page: result.pagination.map(|x| x.max_page).unwrap_or(1),
+    })
+}
+
+impl BrowseItem {
+    // Convert a product search result into an item, or explain why it cannot
+    // become a listing.
+    fn from_result(item: SearchResultItem) -> Result<BrowseItem, SkipReason> {
+        let price = item
+            .price_info
+            .and_then(|x| x.current_price)
+            .and_then(|x| x.price)
+            .ok_or(SkipReason::MissingPrice)?;
+        let image_url = item
+            .image_info
+            .and_then(|x| x.thumbnail_url)
+            .ok_or(SkipReason::MissingImage)?;
+        // Thumbnail URLs request a scaled-down copy; drop the query to get
+        // the full image.
+        let image_url = match image_url.split_once('?') {
+            Some((base, _)) => base.to_owned(),
+            None => image_url,
+        };
+        let (id, name) = match (item.us_item_id, item.name) {
+            (Some(id), Some(name)) => (id, name),
+            _ => return Err(SkipReason::MissingDetails),
+        };
+        let num_reviews = item.number_of_reviews.filter(|x| *x > 0);
+        Ok(BrowseItem {
+            id,
+            title: html_escape::decode_html_entities(&name).as_ref().to_owned(),
+            price: (price * 100.0).round() as i64,
+            image_url,
+            star_rating: num_reviews.and(item.average_rating),
+            num_reviews,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct NextData {
+    props: NextDataProps,
+}
+
+#[derive(Deserialize)]
+struct NextDataProps {
+    #[serde(rename(deserialize = "pageProps"))]
+    page_props: NextDataPageProps,
+}
+
+#[derive(Deserialize)]
+struct NextDataPageProps {
+    #[serde(rename(deserialize = "initialData"))]
+    initial_data: NextDataInitialData,
+}
+
+#[derive(Deserialize)]
+struct NextDataInitialData {
+    #[serde(rename(deserialize = "searchResult"))]
+    search_result: SearchResult,
+}
+
+#[derive(Deserialize)]
+struct SearchResult {
+    #[serde(rename(deserialize = "itemStacks"))]
+    item_stacks: Vec<SearchResultStack>,
+
+    #[serde(rename(deserialize = "paginationV2"))]
+    pagination: Option<SearchResultPagination>,
+}
+
+#[derive(Deserialize)]
+struct SearchResultStack {
+    items: Vec<SearchResultItem>,
+}
+
+#[derive(Deserialize)]
+struct SearchResultPagination {
+    #[serde(rename(deserialize = "maxPage"))]
+    max_page: usize,
+}
+
+// Stacks mix products with ads and other placeholders, so every field is
+// optional.
+#[derive(Deserialize)]
+struct SearchResultItem {
+    #[serde(rename(deserialize = "__typename"))]
+    typename: Option<String>,
+
+    #[serde(rename(deserialize = "usItemId"))]
+    us_item_id: Option<String>,
+
+    name: Option<String>,
+
+    #[serde(rename(deserialize = "priceInfo"))]
+    price_info: Option<SearchResultPriceInfo>,
+
+    #[serde(rename(deserialize = "imageInfo"))]
+    image_info: Option<SearchResultImageInfo>,
+
+    #[serde(rename(deserialize = "averageRating"))]
+    average_rating: Option<f64>,
+
+    #[serde(rename(deserialize = "numberOfReviews"))]
+    number_of_reviews: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct SearchResultPriceInfo {
+    #[serde(rename(deserialize = "currentPrice"))]
+    current_price: Option<SearchResultPrice>,
+}
+
+#[derive(Deserialize)]
+struct SearchResultPrice {
+    price: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct SearchResultImageInfo {
+    #[serde(rename(deserialize = "thumbnailUrl"))]
+    thumbnail_url: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::scraper::{ClientPolicy, Transport};
+
+    const BROWSE_PAGE: &str = include_str!("../testdata/walmart/browse.html");
+
+    #[test]
+    fn parse_browse_page_items() {
+        let page = parse_browse_page(BROWSE_PAGE).unwrap();
+        assert_eq!(page.max_page, 25);
+        assert_eq!(
+            page.items,
+            vec![
+                BrowseItem {
+                    id: "719294392".to_owned(),
+                    title: "onn. 32\" Class HD (720P) LED Roku Smart TV".to_owned(),
+                    price: 9800,
+                    image_url: "https://i5.walmartimages.com/seo/onn-32-Class-HD-TV_1.jpeg"
+                        .to_owned(),
+                    star_rating: Some(4.3),
+                    num_reviews: Some(15023),
+                },
+                BrowseItem {
+                    id: "604342441".to_owned(),
+                    title: "Apple AirPods with Charging Case (2nd Generation)".to_owned(),
+                    price: 8999,
+                    image_url: "https://i5.walmartimages.com/seo/Apple-AirPods_2.jpeg".to_owned(),
+                    star_rating: None,
+                    num_reviews: None,
+                },
+            ]
+        );
+        assert_eq!(
+            page.skipped,
+            vec![SkipReason::MissingPrice, SkipReason::MissingImage]
+        );
+    }
+
+    #[test]
+    fn parse_browse_page_without_pagination() {
+        let html = r#"<script id="__NEXT_DATA__" type="application/json">
+            {"props":{"pageProps":{"initialData":{"searchResult":{"itemStacks":[]}}}}}
+        </script>"#;
+        let page = parse_browse_page(html).unwrap();
+        assert!(page.items.is_empty());
+        assert_eq!(page.max_page, 1);
+    }
+
+    #[test]
+    fn parse_browse_page_missing_data() {
+        assert!(parse_browse_page("<html><body>Robot or human?</body></html>").is_err());
+    }
+
+    #[tokio::test]
+    async fn stream_category_skips_failed_images() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/cassettes/walmart");
+        let client = Client::new(ClientPolicy::default(), Transport::Replay(dir));
+        let mut rx = stream_category(client, "kitchen".to_owned());
+        match rx.recv().await {
+            Some(Ok(ScrapedItem::Listing(listing))) => {
+                assert_eq!(listing.website_id, "1001");
+                assert_eq!(listing.price, 24900);
+                assert_eq!(listing.image_data, b"mixer data");
+                assert_eq!(listing.categories, vec!["kitchen".to_owned()]);
+            }
+            _ => panic!("expected a listing"),
+        }
+        assert!(matches!(
+            rx.recv().await,
+            Some(Ok(ScrapedItem::Skipped(SkipReason::FailedRequest)))
+        ));
+        assert!(rx.recv().await.is_none());
+    }
+}